@@ -0,0 +1,108 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use rand::Rng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+// An `X-AB-Buckets` entry: `<name>=<weight>:<body>`
+struct Bucket {
+    name: String,
+    weight: f64,
+    body: String,
+}
+
+// Parse `X-AB-Buckets`, a comma-separated list of `<name>=<weight>:<body>`
+// entries, e.g. `control=0.5:bodyA,variant=0.5:bodyB`. Weights are relative,
+// so they need not add up to 1.
+fn parse_buckets(headers: &HeaderMap) -> Result<Vec<Bucket>, String> {
+    let value = headers
+        .get("X-AB-Buckets")
+        .ok_or("X-AB-Buckets is required")?
+        .to_str()
+        .map_err(|e| e.to_string())?;
+
+    let buckets = value
+        .split(',')
+        .map(|entry| {
+            let (name, rest) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not <name>=<weight>:<body>", entry.trim()))?;
+            let (weight, body) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("{} is not <name>=<weight>:<body>", entry.trim()))?;
+            let name = name.trim();
+            if name.is_empty() || HeaderValue::from_str(name).is_err() {
+                return Err(format!("bucket name {:?} is not valid", name));
+            }
+            let weight = weight
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|w| w.is_finite() && *w >= 0.0)
+                .ok_or_else(|| format!("weight of {} must be a non-negative number", name))?;
+            Ok(Bucket { name: name.to_string(), weight, body: body.to_string() })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if buckets.iter().map(|b| b.weight).sum::<f64>() <= 0.0 {
+        return Err("at least one bucket needs a positive weight".to_string());
+    }
+    Ok(buckets)
+}
+
+// Point in [0, 1) for the assignment: derived from the SHA-256 of `X-AB-Key`
+// when present, so a key always lands in the same bucket, otherwise random
+fn assignment_point(headers: &HeaderMap) -> f64 {
+    match headers.get("X-AB-Key") {
+        Some(key) => {
+            let digest = Sha256::digest(key.as_bytes());
+            let prefix = u64::from_be_bytes(digest[..8].try_into().unwrap());
+            // The top 53 bits fit an f64 exactly
+            (prefix >> 11) as f64 / (1u64 << 53) as f64
+        }
+        None => rand::thread_rng().gen::<f64>(),
+    }
+}
+
+// Assign the request to one of the `X-AB-Buckets` by weight and return that
+// bucket's body, named in `X-AB-Bucket`. With `X-AB-Key` the assignment is
+// deterministic. Nothing is sent to the upstream.
+pub async fn ab_handler(headers: HeaderMap) -> Response {
+    let buckets = match parse_buckets(&headers) {
+        Ok(buckets) => buckets,
+        Err(details) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-AB-Buckets",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+
+    let total: f64 = buckets.iter().map(|b| b.weight).sum();
+    let mut remaining = assignment_point(&headers) * total;
+    let bucket = buckets
+        .iter()
+        .filter(|b| b.weight > 0.0)
+        .find(|b| {
+            remaining -= b.weight;
+            remaining < 0.0
+        })
+        // Rounding can leave the point just past the last bucket
+        .unwrap_or_else(|| buckets.iter().rfind(|b| b.weight > 0.0).unwrap());
+
+    let content_type = if serde_json::from_str::<serde_json::Value>(&bucket.body).is_ok() {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let mut response = bucket.body.clone().into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert("X-AB-Bucket", HeaderValue::from_str(&bucket.name).unwrap());
+    response
+}
@@ -0,0 +1,94 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    response::Json,
+};
+use http_body_util::Full;
+use serde_json::{json, Value};
+
+use crate::connector::HttpClient;
+use crate::{send_upstream, UpstreamResponse};
+
+// Internal redirects followed for one request before giving up, so an
+// upstream that redirects to itself cannot hold the request forever
+pub const MAX_ACCEL_REDIRECTS: usize = 5;
+
+const ACCEL_REDIRECT: &str = "X-Accel-Redirect";
+
+// Parse ACCEL_BASE_URL, the base the `X-Accel-Redirect` paths are fetched from
+pub fn parse_base_url(value: &str) -> Result<String, String> {
+    let uri = value
+        .parse::<Uri>()
+        .map_err(|e| format!("{} is not a valid URL: {}", value, e))?;
+    if uri.scheme().is_none() || uri.host().is_none() {
+        return Err(format!("{} must be an absolute URL", value));
+    }
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+// nginx-style internal redirect: while the upstream answers with
+// `X-Accel-Redirect: <path>`, fetch that path from ACCEL_BASE_URL with a GET
+// and use its response instead. The returned response never carries the
+// header. Also returns the URLs fetched, in order.
+pub async fn follow(
+    client: &HttpClient,
+    base_url: &str,
+    mut upstream: UpstreamResponse,
+) -> Result<(UpstreamResponse, Vec<String>), (StatusCode, Json<Value>)> {
+    let mut followed = Vec::new();
+    while let Some(location) = upstream.headers.remove(ACCEL_REDIRECT) {
+        let path = location.to_str().ok().filter(|path| path.starts_with('/')).ok_or_else(|| (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "error": "Invalid X-Accel-Redirect",
+                "error_type": "accel_redirect_invalid",
+                "details": "X-Accel-Redirect must be a path starting with /"
+            }))
+        ))?;
+        if followed.len() == MAX_ACCEL_REDIRECTS {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                Json(json!({
+                    "error": "Too many internal redirects",
+                    "error_type": "accel_redirect_loop",
+                    "details": format!("Gave up after following {} X-Accel-Redirect headers", MAX_ACCEL_REDIRECTS),
+                    "redirects": followed
+                }))
+            ));
+        }
+
+        let url = format!("{}{}", base_url, path);
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(&url)
+            .body(Full::default())
+            .map_err(|e| (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({
+                    "error": "Invalid X-Accel-Redirect",
+                    "error_type": "accel_redirect_invalid",
+                    "details": e.to_string()
+                }))
+            ))?;
+        upstream = send_upstream(client, req, &url).await?;
+        followed.push(url);
+    }
+
+    Ok((upstream, followed))
+}
+
+// Report of the redirects followed for the response wrapper, None if there
+// were none
+pub fn report(followed: &[String]) -> Option<Value> {
+    followed.last().map(|location| json!({
+        "location": location,
+        "redirects": followed
+    }))
+}
+
+// `X-Accel-Location`: where the response was fetched from, when an internal
+// redirect was followed
+pub fn add_header(headers: &mut HeaderMap, followed: &[String]) {
+    if let Some(value) = followed.last().and_then(|location| HeaderValue::from_str(location).ok()) {
+        headers.insert("X-Accel-Location", value);
+    }
+}
@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use serde_json::json;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::fault::{Fault, FaultContext};
+use crate::{metrics, SharedState};
+
+// Requests currently being handled, probes, metrics and admin calls aside
+#[derive(Default)]
+pub struct InFlight {
+    count: AtomicU64,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn current(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Count a request until the guard is dropped
+    fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { in_flight: self.clone() }
+    }
+}
+
+struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Count the request as in flight until its response has been produced
+pub async fn track_in_flight(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let _guard = state.in_flight.enter();
+    next.run(request).await
+}
+
+// With ADAPTIVE_BASE_MS or ADAPTIVE_PER_INFLIGHT_MS set, delay each request
+// by `base + per_inflight * in_flight`, counting the request itself, like a
+// backend that slows down as load builds up
+pub struct AdaptiveDelay {
+    in_flight: Arc<InFlight>,
+}
+
+impl AdaptiveDelay {
+    pub fn new(in_flight: Arc<InFlight>) -> Self {
+        AdaptiveDelay { in_flight }
+    }
+}
+
+#[async_trait]
+impl Fault for AdaptiveDelay {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let (base_ms, per_inflight_ms) = match (ctx.config.adaptive_base_ms, ctx.config.adaptive_per_inflight_ms) {
+            (None, None) => return ControlFlow::Continue(()),
+            _ if !ctx.config.chaos_enabled => return ControlFlow::Continue(()),
+            (base_ms, per_inflight_ms) => (base_ms.unwrap_or(0), per_inflight_ms.unwrap_or(0)),
+        };
+
+        let in_flight = self.in_flight.current();
+        let delay_ms = base_ms.saturating_add(per_inflight_ms.saturating_mul(in_flight));
+        if delay_ms > 0 {
+            let delay_start = Instant::now();
+            let slept = ctx.sleep(Duration::from_millis(delay_ms)).await;
+            ctx.timing.record_delay(delay_start.elapsed());
+            slept?;
+            ctx.fault_log.record("adaptive_delay", delay_ms);
+        }
+
+        ctx.report.insert("adaptive_delay".to_string(), json!({
+            "in_flight": in_flight,
+            "base_ms": base_ms,
+            "per_inflight_ms": per_inflight_ms,
+            "delay_ms": delay_ms
+        }));
+        ControlFlow::Continue(())
+    }
+}
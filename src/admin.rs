@@ -0,0 +1,247 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::SharedState;
+
+// Admin endpoints are only available when ADMIN_TOKEN is configured, and
+// require it as a bearer token.
+fn authorize(state: &SharedState, headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+    let config = state.config.read().unwrap();
+    let Some(expected) = config.admin_token.as_deref() else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": "Admin API disabled",
+                "details": "Set ADMIN_TOKEN to enable admin endpoints"
+            }))
+        ));
+    };
+
+    let provided = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "));
+
+    // Constant-time, so response timing doesn't leak how much of a guess matched
+    let matches = provided.is_some_and(|p| bool::from(p.as_bytes().ct_eq(expected.as_bytes())));
+    if !matches {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "error": "Invalid or missing admin token"
+            }))
+        ));
+    }
+
+    Ok(())
+}
+
+// The effective config, with the armed failure waiting for the next request
+fn effective_config(state: &SharedState, config: &Config) -> Value {
+    let mut effective = config.to_json();
+    effective["armed_failure"] = state.armed_failure.to_json();
+    effective
+}
+
+// Return the effective config without changing it
+pub async fn get_config(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    let config = state.config.read().unwrap();
+    (StatusCode::OK, Json(effective_config(&state, &config)))
+}
+
+// Update runtime-adjustable config fields and return the effective config
+pub async fn patch_config(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    let Some(fields) = payload.as_object() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid config patch",
+                "details": "Request body must be a JSON object"
+            }))
+        );
+    };
+
+    let mut config = state.config.write().unwrap();
+    match config.apply_patch(fields) {
+        Ok(()) => (StatusCode::OK, Json(effective_config(&state, &config))),
+        Err(details) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid config patch",
+                "details": details
+            }))
+        ),
+    }
+}
+
+// Read-only dump of the proxy's stateful subsystems, for debugging tests
+pub async fn get_state(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    let mut sni_overrides: Vec<String> = state
+        .override_clients
+        .lock()
+        .unwrap()
+        .keys()
+        .filter_map(|(sni, _)| sni.clone())
+        .collect();
+    sni_overrides.sort();
+    sni_overrides.dedup();
+
+    (StatusCode::OK, Json(json!({
+        "config": state.config.read().unwrap().to_json(),
+        "rates": state.rates.to_json(),
+        "sni_clients": sni_overrides,
+        "body_rotation": state.rotation.to_json(),
+        "sequence": state.sequence.to_json(),
+        "warmup_requests": state.warmup_requests.load(Ordering::Relaxed),
+        "warm_keys": state.warm_keys.to_json(),
+        "debounce": state.debouncer.to_json(),
+        "failure_burst": state.failure_burst.to_json(),
+        "in_flight": state.in_flight.current(),
+        "longpoll_waiting": state.longpoll.waiting(),
+        "maintenance": state.maintenance.to_json(),
+        "armed_failure": state.armed_failure.to_json(),
+        "cold_start_pending": state.cold_start_pending.load(Ordering::Relaxed),
+        "gc_paused": state.gc_pause.is_paused(),
+        "stale_cache": state.stale_cache.to_json(),
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
+
+// Reset runtime state to how it was at startup: body rotations restart from
+// their first body, /sequence from its first response, the global warmup
+// curve starts over, warm keys are forgotten, the failure burst chain is
+// healthy again, maintenance mode is turned off, an armed failure is
+// disarmed, the next request pays the cold-start delay again, cached stale
+// responses are dropped and the cached upstream token is fetched again
+pub async fn reset(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    state.rotation.reset();
+    state.sequence.reset();
+    state.warmup_requests.store(0, Ordering::Relaxed);
+    state.warm_keys.reset();
+    state.failure_burst.reset();
+    state.maintenance.reset();
+    state.armed_failure.reset();
+    state.cold_start_pending.store(true, Ordering::Relaxed);
+    state.stale_cache.reset();
+    state.upstream_auth.reset().await;
+
+    (StatusCode::OK, Json(json!({
+        "status": "reset",
+        "reset": ["body_rotation", "sequence", "warmup", "warm_keys", "failure_burst", "maintenance", "armed_failure", "cold_start", "stale_cache", "upstream_token"],
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
+
+// Turn maintenance mode on or off. While on, every fault endpoint returns the
+// configured response without contacting the upstream.
+pub async fn set_maintenance(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    let result = match payload.as_object() {
+        Some(fields) => state.maintenance.update(fields),
+        None => Err("Request body must be a JSON object".to_string()),
+    };
+    match result {
+        Ok(()) => (StatusCode::OK, Json(state.maintenance.to_json())),
+        Err(details) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid maintenance settings",
+                "details": details
+            }))
+        ),
+    }
+}
+
+// Arm a failure that the next request to a fault endpoint returns instead of
+// running its faults, after which it disarms itself
+pub async fn arm_failure(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection;
+    }
+
+    let result = match payload.as_object() {
+        Some(fields) => state.armed_failure.arm(fields),
+        None => Err("Request body must be a JSON object".to_string()),
+    };
+    match result {
+        Ok(()) => (StatusCode::OK, Json(state.armed_failure.to_json())),
+        Err(details) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid armed failure",
+                "details": details
+            }))
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn only_the_exact_admin_token_is_accepted() {
+        let state = crate::test_state(|config| config.admin_token = Some("s3cret".to_string()));
+
+        assert!(authorize(&state, &bearer("s3cret")).is_ok());
+        for wrong in ["s3cre", "s3cret!", "S3CRET", ""] {
+            let (status, _) = authorize(&state, &bearer(wrong)).unwrap_err();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = authorize(&state, &HeaderMap::new()).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
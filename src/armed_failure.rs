@@ -0,0 +1,88 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Map, Value};
+use std::sync::Mutex;
+
+use crate::fault_log::FaultLog;
+use crate::metrics::{Outcome, RequestOutcome};
+use crate::SharedState;
+
+// Failure returned by the next fault endpoint request
+struct ArmedResponse {
+    status: StatusCode,
+    body: Value,
+}
+
+// One-shot failure armed through the admin API. Disarmed at startup and
+// once it has fired.
+#[derive(Default)]
+pub struct ArmedFailure(Mutex<Option<ArmedResponse>>);
+
+impl ArmedFailure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Apply a `POST /admin/arm-failure` body: `{"status", "body"}`. `status`
+    // defaults to 503 and `body` to a generic error. Arming again replaces
+    // the failure that is waiting.
+    pub fn arm(&self, fields: &Map<String, Value>) -> Result<(), String> {
+        let status = match fields.get("status") {
+            None => StatusCode::SERVICE_UNAVAILABLE,
+            Some(status) => status
+                .as_u64()
+                .filter(|code| (100..=599).contains(code))
+                .and_then(|code| StatusCode::from_u16(code as u16).ok())
+                .ok_or("status must be a status code between 100 and 599")?,
+        };
+        let body = fields.get("body").cloned().unwrap_or_else(|| json!({
+            "error": "Armed failure"
+        }));
+
+        *self.0.lock().unwrap() = Some(ArmedResponse { status, body });
+        Ok(())
+    }
+
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    pub fn to_json(&self) -> Value {
+        match &*self.0.lock().unwrap() {
+            Some(response) => json!({
+                "armed": true,
+                "status": response.status.as_u16(),
+                "body": response.body
+            }),
+            None => json!({ "armed": false }),
+        }
+    }
+}
+
+// Answer the first fault endpoint request after the failure was armed with
+// it, without running any faults or contacting the upstream, and disarm it.
+// Taking it under the lock means exactly one request gets it, even when
+// several arrive at once.
+pub async fn fire(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let armed = state.armed_failure.0.lock().unwrap().take();
+    let Some(ArmedResponse { status, body }) = armed else {
+        return next.run(request).await;
+    };
+
+    if let Some(outcome) = request.extensions().get::<RequestOutcome>() {
+        outcome.mark(Outcome::SimulatedFailure);
+    }
+    if let Some(fault_log) = request.extensions().get::<FaultLog>() {
+        fault_log.record("armed_failure", status.as_u16());
+    }
+    (status, Json(body)).into_response()
+}
@@ -0,0 +1,84 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// Body check requested with `X-Expect-Body-Sha256`
+pub struct BodyHashCheck {
+    expected: String,
+    // `X-Strict-Body-Hash: true`: answer a mismatch with 502 instead of
+    // only flagging it
+    strict: bool,
+}
+
+// A body whose hash differs from the expected one
+pub struct Mismatch {
+    expected: String,
+    actual: String,
+    strict: bool,
+}
+
+impl BodyHashCheck {
+    // Parse the requested check, rejecting anything but 64 hex digits
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, (StatusCode, Json<Value>)> {
+        let Some(h) = headers.get("X-Expect-Body-Sha256") else {
+            return Ok(None);
+        };
+        let expected = h
+            .to_str()
+            .ok()
+            .filter(|s| s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Expect-Body-Sha256",
+                    "details": "X-Expect-Body-Sha256 must be a SHA-256 digest as 64 hex digits"
+                }))
+            ))?;
+        let strict = headers
+            .get("X-Strict-Body-Hash")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<bool>().ok())
+            .unwrap_or(false);
+
+        Ok(Some(BodyHashCheck { expected: expected.to_ascii_lowercase(), strict }))
+    }
+
+    // Hash the upstream body as received and compare it with the expected
+    // digest
+    pub fn verify(&self, body: &[u8]) -> Option<Mismatch> {
+        let actual = format!("{:x}", Sha256::digest(body));
+        (actual != self.expected).then(|| Mismatch {
+            expected: self.expected.clone(),
+            actual,
+            strict: self.strict,
+        })
+    }
+}
+
+impl Mismatch {
+    // The 502 returned in strict mode
+    pub fn strict_response(&self) -> Option<Response> {
+        if !self.strict {
+            return None;
+        }
+        let mut response = (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "error": "Upstream body hash mismatch",
+                "error_type": "body_hash_mismatch",
+                "expected_sha256": self.expected,
+                "actual_sha256": self.actual
+            }))
+        ).into_response();
+        self.flag(&mut response);
+        Some(response)
+    }
+
+    // Mark the response with `X-Body-Hash-Mismatch: true`
+    pub fn flag<B>(&self, response: &mut axum::http::Response<B>) {
+        response.headers_mut().insert("X-Body-Hash-Mismatch", HeaderValue::from_static("true"));
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Json,
+};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::request_id::RequestId;
+
+// Directory for `X-Capture: true`, or None when capture was not requested.
+// Requests asking for capture without CAPTURE_DIR are rejected.
+pub fn capture_dir(config: &Config, headers: &HeaderMap) -> Result<Option<PathBuf>, (StatusCode, Json<Value>)> {
+    let requested = headers
+        .get("X-Capture")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    if !requested {
+        return Ok(None);
+    }
+    match &config.capture_dir {
+        Some(dir) => Ok(Some(PathBuf::from(dir))),
+        None => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Capture not configured",
+                "details": "X-Capture requires CAPTURE_DIR to be set"
+            }))
+        )),
+    }
+}
+
+// File extension for a media type. mime_guess lists a type's extensions
+// alphabetically (`text/plain` starts with `asm`), so the usual ones are
+// picked by hand first.
+fn extension(essence: &str) -> &'static str {
+    match essence {
+        "application/json" => "json",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/csv" => "csv",
+        "application/xml" | "text/xml" => "xml",
+        _ if essence.ends_with("+json") => "json",
+        _ if essence.ends_with("+xml") => "xml",
+        _ => mime_guess::get_mime_extensions_str(essence)
+            .and_then(|extensions| extensions.first())
+            .copied()
+            .unwrap_or("bin"),
+    }
+}
+
+// Save an upstream body under `dir` as `<timestamp>_<request id>.<ext>`, with
+// the extension guessed from its Content-Type. The file is written by a
+// spawned task so the response is not held up; write errors are only logged.
+// Returns the file name, reported to the client in `X-Capture-File`.
+pub fn save(dir: PathBuf, request_id: &RequestId, content_type: Option<&HeaderValue>, body: Bytes) -> String {
+    // Request ids can come from the client, so keep only characters that are
+    // safe in a file name
+    let id: String = request_id
+        .as_str()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let extension = content_type
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map_or("bin", |essence| extension(essence.trim()));
+    let name = format!("{}_{}.{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), id, extension);
+
+    let path = dir.join(&name);
+    tokio::spawn(async move {
+        let written = match tokio::fs::create_dir_all(&dir).await {
+            Ok(()) => tokio::fs::write(&path, &body).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::warn!("Failed to capture response body to {}: {}", path.display(), e);
+        }
+    });
+    name
+}
+
+// Report the capture file name to the client
+pub fn add_header(headers: &mut HeaderMap, name: &str) {
+    if let Ok(value) = HeaderValue::from_str(name) {
+        headers.insert("X-Capture-File", value);
+    }
+}
@@ -0,0 +1,34 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use std::io::Write;
+
+use crate::config::Config;
+
+// Whether the body sent upstream is gzipped: `X-Compress-Request: gzip` or
+// `identity`, defaulting to COMPRESS_UPSTREAM_REQUESTS
+pub fn gzip_requested(config: &Config, headers: &HeaderMap) -> Result<bool, (StatusCode, Json<Value>)> {
+    match headers.get("X-Compress-Request").map(|h| h.to_str()) {
+        None => Ok(config.compress_upstream_requests),
+        Some(Ok(value)) if value.eq_ignore_ascii_case("gzip") => Ok(true),
+        Some(Ok(value)) if value.eq_ignore_ascii_case("identity") => Ok(false),
+        Some(_) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Compress-Request",
+                "details": "X-Compress-Request must be gzip or identity"
+            }))
+        )),
+    }
+}
+
+pub fn gzip(body: &[u8]) -> Bytes {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::default());
+    // Writing to a Vec cannot fail
+    encoder.write_all(body).unwrap();
+    Bytes::from(encoder.finish().unwrap())
+}
@@ -0,0 +1,721 @@
+use dotenv::dotenv;
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::accel;
+use crate::connector::{self, TlsVersion};
+use crate::fanout;
+use crate::gc_pause::{self, GcPauseMode};
+use crate::logging::LogRotation;
+use crate::middleware;
+use crate::proxy::{self, RouteRule};
+use crate::retry;
+use crate::rotation;
+use crate::sequence::{self, SequenceResponse};
+use crate::schedule::DelayRule;
+use crate::synthetic::SyntheticRequest;
+use crate::upstream_auth::TokenSource;
+use crate::vhost::{self, VirtualHost};
+use crate::wrapper::WrapperTemplate;
+
+// Profile that leaves the configured fault defaults unchanged
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Configuration struct to hold environment variables
+#[derive(Clone)]
+pub struct Config {
+    pub target_url: String,
+    pub success_probability: f64,
+    pub connect_timeout: Option<Duration>,
+    pub disable_pooling: bool,
+    pub upstream_sni: Option<String>,
+    pub admin_token: Option<String>,
+    pub emit_timing: bool,
+    pub emit_rps_header: bool,
+    pub route_rules: Vec<RouteRule>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_handshake_delay: Option<Duration>,
+    pub failure_body_templates: HashMap<String, Value>,
+    pub shutdown_drain_delay: Duration,
+    pub log_file: Option<String>,
+    pub log_rotation: LogRotation,
+    pub default_success_status: Option<StatusCode>,
+    pub chaos_enabled: bool,
+    pub delay_schedule: Vec<DelayRule>,
+    pub max_cpu_burn: Duration,
+    pub failure_rate_by_method: HashMap<String, f64>,
+    pub client_request_timeout: Option<Duration>,
+    pub rotating_bodies: HashMap<String, Vec<Value>>,
+    pub sequence_responses: Vec<SequenceResponse>,
+    pub upstream_retries: u32,
+    pub compress_upstream_requests: bool,
+    pub fixtures_dir: Option<String>,
+    pub forward_headers: Vec<HeaderName>,
+    pub emit_handler_header: bool,
+    pub warm_key_ttl: Duration,
+    pub enable_trailers: bool,
+    pub cold_start_delay: Option<Duration>,
+    pub strip_request_headers: Vec<HeaderName>,
+    pub strip_response_headers: Vec<HeaderName>,
+    pub serve_stale_on_error: bool,
+    pub slow_request_threshold: Option<Duration>,
+    pub upstream_max_request_bytes: Option<usize>,
+    pub allow_smuggling: bool,
+    pub vhost_map: Vec<VirtualHost>,
+    pub default_response_content_type: Option<HeaderValue>,
+    pub fanout_urls: Vec<String>,
+    pub synthetic_interval: Option<Duration>,
+    pub synthetic_request: SyntheticRequest,
+    pub capture_dir: Option<String>,
+    pub max_header_count: usize,
+    pub max_header_bytes: usize,
+    pub fault_active_window: Option<Duration>,
+    pub accel_base_url: Option<String>,
+    pub control_file: Option<String>,
+    pub success_wrapper_template: Option<WrapperTemplate>,
+    pub adaptive_base_ms: Option<u64>,
+    pub adaptive_per_inflight_ms: Option<u64>,
+    pub dns_overrides: HashMap<String, IpAddr>,
+    pub max_inflated_header_bytes: usize,
+    pub upstream_auth: Option<TokenSource>,
+    pub upstream_min_tls: Option<TlsVersion>,
+    pub upstream_max_tls: Option<TlsVersion>,
+    pub failure_success_body: Option<String>,
+    pub gc_pause_interval: Option<Duration>,
+    pub gc_pause_duration: Duration,
+    pub gc_pause_mode: GcPauseMode,
+    pub max_delay_budget: Option<Duration>,
+    pub log_body_max_len: Option<usize>,
+    // Port and fault profile of each listener, ordered by port
+    pub listeners: Vec<(u16, String)>,
+    // Config patches applied on top of this config for each named profile
+    pub fault_profiles: HashMap<String, Map<String, Value>>,
+    // Profile this config was built for
+    pub profile: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let target_url = env::var("TARGET_URL")
+            .expect("TARGET_URL must be set");
+
+        let success_probability = env::var("SUCCESS_PROBABILITY")
+            .unwrap_or_else(|_| "0.8".to_string())
+            .parse::<f64>()
+            .expect("SUCCESS_PROBABILITY must be a float between 0.0 and 1.0");
+
+        let connect_timeout = env::var("CONNECT_TIMEOUT_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("CONNECT_TIMEOUT_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let disable_pooling = env::var("DISABLE_POOLING")
+            .map(|s| s.parse::<bool>().expect("DISABLE_POOLING must be true or false"))
+            .unwrap_or(false);
+
+        let upstream_sni = env::var("UPSTREAM_SNI").ok().filter(|s| !s.is_empty());
+
+        let admin_token = env::var("ADMIN_TOKEN").ok().filter(|s| !s.is_empty());
+
+        let emit_timing = env::var("EMIT_TIMING")
+            .map(|s| s.parse::<bool>().expect("EMIT_TIMING must be true or false"))
+            .unwrap_or(false);
+
+        let emit_rps_header = env::var("EMIT_RPS_HEADER")
+            .map(|s| s.parse::<bool>().expect("EMIT_RPS_HEADER must be true or false"))
+            .unwrap_or(false);
+
+        let route_rules = env::var("ROUTE_REGEX")
+            .map(|s| RouteRule::parse_list(&s)
+                .unwrap_or_else(|e| panic!("ROUTE_REGEX is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let tls_cert_file = env::var("TLS_CERT_FILE").ok().filter(|s| !s.is_empty());
+        let tls_key_file = env::var("TLS_KEY_FILE").ok().filter(|s| !s.is_empty());
+
+        let tls_handshake_delay = env::var("TLS_HANDSHAKE_DELAY_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("TLS_HANDSHAKE_DELAY_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let failure_body_templates = env::var("FAILURE_BODY_TEMPLATES")
+            .map(|s| parse_failure_body_templates(&s)
+                .unwrap_or_else(|e| panic!("FAILURE_BODY_TEMPLATES is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let shutdown_drain_delay = env::var("SHUTDOWN_DRAIN_DELAY_MS")
+            .map(|s| s.parse::<u64>().expect("SHUTDOWN_DRAIN_DELAY_MS must be a positive integer"))
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+
+        let log_file = env::var("LOG_FILE").ok().filter(|s| !s.is_empty());
+
+        let log_rotation = env::var("LOG_ROTATION")
+            .map(|s| LogRotation::parse(&s)
+                .unwrap_or_else(|e| panic!("LOG_ROTATION is invalid: {}", e)))
+            .unwrap_or(LogRotation::Daily);
+
+        let default_success_status = env::var("DEFAULT_SUCCESS_STATUS")
+            .ok()
+            .map(|s| parse_status_code(&s)
+                .unwrap_or_else(|e| panic!("DEFAULT_SUCCESS_STATUS is invalid: {}", e)));
+
+        let chaos_enabled = env::var("CHAOS_ENABLED")
+            .map(|s| s.parse::<bool>().expect("CHAOS_ENABLED must be true or false"))
+            .unwrap_or(true);
+
+        let delay_schedule = env::var("DELAY_SCHEDULE")
+            .map(|s| DelayRule::parse_list(&s)
+                .unwrap_or_else(|e| panic!("DELAY_SCHEDULE is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let max_cpu_burn = env::var("MAX_CPU_BURN_MS")
+            .map(|s| s.parse::<u64>().expect("MAX_CPU_BURN_MS must be a positive integer"))
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_secs(1));
+
+        let failure_rate_by_method = env::var("FAILURE_RATE_BY_METHOD")
+            .map(|s| parse_failure_rate_by_method(&s)
+                .unwrap_or_else(|e| panic!("FAILURE_RATE_BY_METHOD is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let client_request_timeout = env::var("CLIENT_REQUEST_TIMEOUT_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("CLIENT_REQUEST_TIMEOUT_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let rotating_bodies = env::var("ROTATING_BODIES")
+            .map(|s| rotation::parse_rotating_bodies(&s)
+                .unwrap_or_else(|e| panic!("ROTATING_BODIES is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let sequence_responses = env::var("SEQUENCE_RESPONSES")
+            .map(|s| sequence::parse_sequence_responses(&s)
+                .unwrap_or_else(|e| panic!("SEQUENCE_RESPONSES is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let upstream_retries = env::var("UPSTREAM_RETRIES")
+            .map(|s| s.parse::<u32>()
+                .ok()
+                .filter(|&n| n <= retry::MAX_RETRIES)
+                .unwrap_or_else(|| panic!("UPSTREAM_RETRIES must be an integer between 0 and {}", retry::MAX_RETRIES)))
+            .unwrap_or(0);
+
+        let compress_upstream_requests = env::var("COMPRESS_UPSTREAM_REQUESTS")
+            .map(|s| s.parse::<bool>().expect("COMPRESS_UPSTREAM_REQUESTS must be true or false"))
+            .unwrap_or(false);
+
+        let fixtures_dir = env::var("FIXTURES_DIR").ok().filter(|s| !s.is_empty());
+
+        let forward_headers = env::var("FORWARD_HEADERS")
+            .map(|s| proxy::parse_forward_headers(&s)
+                .unwrap_or_else(|e| panic!("FORWARD_HEADERS is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let emit_handler_header = env::var("EMIT_HANDLER_HEADER")
+            .map(|s| s.parse::<bool>().expect("EMIT_HANDLER_HEADER must be true or false"))
+            .unwrap_or(false);
+
+        let warm_key_ttl = env::var("WARM_KEY_TTL_SECS")
+            .map(|s| s.parse::<u64>().expect("WARM_KEY_TTL_SECS must be a positive integer"))
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(300));
+
+        let enable_trailers = env::var("ENABLE_TRAILERS")
+            .map(|s| s.parse::<bool>().expect("ENABLE_TRAILERS must be true or false"))
+            .unwrap_or(false);
+
+        let cold_start_delay = env::var("COLD_START_DELAY_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("COLD_START_DELAY_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let strip_request_headers = env::var("STRIP_REQUEST_HEADERS")
+            .map(|s| proxy::parse_header_names(&s)
+                .unwrap_or_else(|e| panic!("STRIP_REQUEST_HEADERS is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let strip_response_headers = env::var("STRIP_RESPONSE_HEADERS")
+            .map(|s| proxy::parse_header_names(&s)
+                .unwrap_or_else(|e| panic!("STRIP_RESPONSE_HEADERS is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let serve_stale_on_error = env::var("SERVE_STALE_ON_ERROR")
+            .map(|s| s.parse::<bool>().expect("SERVE_STALE_ON_ERROR must be true or false"))
+            .unwrap_or(false);
+
+        let slow_request_threshold = env::var("SLOW_REQUEST_THRESHOLD_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("SLOW_REQUEST_THRESHOLD_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let upstream_max_request_bytes = env::var("UPSTREAM_MAX_REQUEST_BYTES")
+            .ok()
+            .map(|s| s.parse::<usize>().expect("UPSTREAM_MAX_REQUEST_BYTES must be a positive integer"));
+
+        let allow_smuggling = env::var("ALLOW_SMUGGLING")
+            .map(|s| s.parse::<bool>().expect("ALLOW_SMUGGLING must be true or false"))
+            .unwrap_or(false);
+
+        let vhost_map = env::var("VHOST_MAP")
+            .map(|s| vhost::parse_vhost_map(&s)
+                .unwrap_or_else(|e| panic!("VHOST_MAP is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let default_response_content_type = env::var("DEFAULT_RESPONSE_CONTENT_TYPE")
+            .ok()
+            .map(|s| s
+                .parse::<mime::Mime>()
+                .ok()
+                .and_then(|mime| HeaderValue::from_str(mime.as_ref()).ok())
+                .expect("DEFAULT_RESPONSE_CONTENT_TYPE must be a media type, e.g. text/plain"));
+
+        let fanout_urls = env::var("FANOUT_URLS")
+            .map(|s| fanout::parse_fanout_urls(&s)
+                .unwrap_or_else(|e| panic!("FANOUT_URLS is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let synthetic_interval = env::var("SYNTHETIC_INTERVAL_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().ok().filter(|&ms| ms > 0)
+                .expect("SYNTHETIC_INTERVAL_MS must be a positive integer"))
+            .map(Duration::from_millis);
+
+        let synthetic_request = env::var("SYNTHETIC_REQUEST")
+            .map(|s| SyntheticRequest::parse(&s)
+                .unwrap_or_else(|e| panic!("SYNTHETIC_REQUEST is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let capture_dir = env::var("CAPTURE_DIR").ok().filter(|s| !s.is_empty());
+
+        let max_header_count = env::var("MAX_HEADER_COUNT")
+            .map(|s| s.parse::<usize>()
+                .ok()
+                .filter(|n| (1..=middleware::HEADER_COUNT_CEILING).contains(n))
+                .unwrap_or_else(|| panic!("MAX_HEADER_COUNT must be an integer between 1 and {}", middleware::HEADER_COUNT_CEILING)))
+            .unwrap_or(middleware::HEADER_COUNT_CEILING);
+
+        let max_header_bytes = env::var("MAX_HEADER_BYTES")
+            .map(|s| s.parse::<usize>().ok().filter(|&n| n > 0)
+                .expect("MAX_HEADER_BYTES must be a positive integer"))
+            .unwrap_or(middleware::DEFAULT_MAX_HEADER_BYTES);
+
+        let fault_active_window = env::var("FAULT_ACTIVE_WINDOW_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>().ok().filter(|&secs| secs > 0)
+                .expect("FAULT_ACTIVE_WINDOW_SECS must be a positive integer"))
+            .map(Duration::from_secs);
+
+        let accel_base_url = env::var("ACCEL_BASE_URL")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| accel::parse_base_url(&s)
+                .unwrap_or_else(|e| panic!("ACCEL_BASE_URL is invalid: {}", e)));
+
+        let control_file = env::var("CONTROL_FILE").ok().filter(|s| !s.is_empty());
+
+        let success_wrapper_template = env::var("SUCCESS_WRAPPER_TEMPLATE")
+            .ok()
+            .map(|s| WrapperTemplate::parse(&s)
+                .unwrap_or_else(|e| panic!("SUCCESS_WRAPPER_TEMPLATE is invalid: {}", e)));
+
+        let adaptive_base_ms = env::var("ADAPTIVE_BASE_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("ADAPTIVE_BASE_MS must be a non-negative integer"));
+
+        let adaptive_per_inflight_ms = env::var("ADAPTIVE_PER_INFLIGHT_MS")
+            .ok()
+            .map(|s| s.parse::<u64>().expect("ADAPTIVE_PER_INFLIGHT_MS must be a non-negative integer"));
+
+        let dns_overrides = env::var("DNS_OVERRIDES")
+            .map(|s| connector::parse_dns_overrides(&s)
+                .unwrap_or_else(|e| panic!("DNS_OVERRIDES is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let max_inflated_header_bytes = env::var("MAX_INFLATED_HEADER_BYTES")
+            .map(|s| s.parse::<usize>().ok().filter(|&n| n > 0)
+                .expect("MAX_INFLATED_HEADER_BYTES must be a positive integer"))
+            .unwrap_or(middleware::DEFAULT_MAX_INFLATED_HEADER_BYTES);
+
+        let upstream_auth = TokenSource::from_env(|name| env::var(name).ok().filter(|s| !s.is_empty()))
+            .unwrap_or_else(|e| panic!("Upstream authentication is misconfigured: {}", e));
+
+        let upstream_min_tls = env::var("UPSTREAM_MIN_TLS")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| TlsVersion::parse(&s)
+                .unwrap_or_else(|e| panic!("UPSTREAM_MIN_TLS is invalid: {}", e)));
+        let upstream_max_tls = env::var("UPSTREAM_MAX_TLS")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| TlsVersion::parse(&s)
+                .unwrap_or_else(|e| panic!("UPSTREAM_MAX_TLS is invalid: {}", e)));
+        if let (Some(min), Some(max)) = (upstream_min_tls, upstream_max_tls) {
+            if min > max {
+                panic!("UPSTREAM_MIN_TLS must not be above UPSTREAM_MAX_TLS");
+            }
+        }
+
+        let failure_success_body = env::var("FAILURE_SUCCESS_BODY").ok();
+
+        let gc_pause_interval = env::var("GC_PAUSE_INTERVAL_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>().ok().filter(|&secs| secs > 0)
+                .expect("GC_PAUSE_INTERVAL_SECS must be a positive integer"))
+            .map(Duration::from_secs);
+        let gc_pause_duration = env::var("GC_PAUSE_DURATION_MS")
+            .map(|s| s.parse::<u64>().ok().filter(|&ms| ms > 0)
+                .expect("GC_PAUSE_DURATION_MS must be a positive integer"))
+            .map(Duration::from_millis)
+            .unwrap_or(gc_pause::DEFAULT_PAUSE_DURATION);
+        if gc_pause_interval.is_some_and(|interval| gc_pause_duration >= interval) {
+            panic!("GC_PAUSE_DURATION_MS must be shorter than GC_PAUSE_INTERVAL_SECS");
+        }
+        let gc_pause_mode = env::var("GC_PAUSE_MODE")
+            .map(|s| GcPauseMode::parse(&s)
+                .unwrap_or_else(|e| panic!("GC_PAUSE_MODE is invalid: {}", e)))
+            .unwrap_or(GcPauseMode::Unavailable);
+
+        let max_delay_budget = env::var("MAX_DELAY_BUDGET_MS")
+            .ok()
+            .map(|s| s.parse::<u64>()
+                .expect("MAX_DELAY_BUDGET_MS must be a non-negative integer"))
+            .map(Duration::from_millis);
+
+        let log_body_max_len = env::var("LOG_BODY_MAX_LEN")
+            .ok()
+            .map(|s| s.parse::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .expect("LOG_BODY_MAX_LEN must be a positive integer"));
+
+        let listeners = env::var("LISTENERS")
+            .map(|s| parse_listeners(&s)
+                .unwrap_or_else(|e| panic!("LISTENERS is invalid: {}", e)))
+            .unwrap_or_else(|_| vec![(3000, DEFAULT_PROFILE.to_string())]);
+
+        let fault_profiles = env::var("FAULT_PROFILES")
+            .map(|s| serde_json::from_str::<HashMap<String, Map<String, Value>>>(&s)
+                .unwrap_or_else(|e| panic!("FAULT_PROFILES is invalid: expected a JSON object of profile name to config fields: {}", e)))
+            .unwrap_or_default();
+
+        let config = Config {
+            target_url,
+            success_probability,
+            connect_timeout,
+            disable_pooling,
+            upstream_sni,
+            admin_token,
+            emit_timing,
+            emit_rps_header,
+            route_rules,
+            tls_cert_file,
+            tls_key_file,
+            tls_handshake_delay,
+            failure_body_templates,
+            shutdown_drain_delay,
+            log_file,
+            log_rotation,
+            default_success_status,
+            chaos_enabled,
+            delay_schedule,
+            max_cpu_burn,
+            failure_rate_by_method,
+            client_request_timeout,
+            rotating_bodies,
+            sequence_responses,
+            upstream_retries,
+            compress_upstream_requests,
+            fixtures_dir,
+            forward_headers,
+            emit_handler_header,
+            warm_key_ttl,
+            enable_trailers,
+            cold_start_delay,
+            strip_request_headers,
+            strip_response_headers,
+            serve_stale_on_error,
+            slow_request_threshold,
+            upstream_max_request_bytes,
+            allow_smuggling,
+            vhost_map,
+            default_response_content_type,
+            fanout_urls,
+            synthetic_interval,
+            synthetic_request,
+            capture_dir,
+            max_header_count,
+            max_header_bytes,
+            fault_active_window,
+            accel_base_url,
+            control_file,
+            success_wrapper_template,
+            adaptive_base_ms,
+            adaptive_per_inflight_ms,
+            dns_overrides,
+            max_inflated_header_bytes,
+            upstream_auth,
+            upstream_min_tls,
+            upstream_max_tls,
+            failure_success_body,
+            gc_pause_interval,
+            gc_pause_duration,
+            gc_pause_mode,
+            max_delay_budget,
+            log_body_max_len,
+            listeners,
+            fault_profiles,
+            profile: DEFAULT_PROFILE.to_string(),
+        };
+
+        // Check every profile in use up front, so a bad one fails at startup
+        for (port, profile) in &config.listeners {
+            if let Err(e) = config.for_profile(profile) {
+                panic!("LISTENERS port {} uses an invalid profile: {}", port, e);
+            }
+        }
+
+        config
+    }
+
+    // This config with the named fault profile applied. The default profile
+    // is the config as configured.
+    pub fn for_profile(&self, name: &str) -> Result<Config, String> {
+        let mut config = self.clone();
+        if name != DEFAULT_PROFILE {
+            let fields = self.fault_profiles
+                .get(name)
+                .ok_or_else(|| format!("{} is not defined in FAULT_PROFILES", name))?;
+            config.apply_patch(fields).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        config.profile = name.to_string();
+        Ok(config)
+    }
+
+    // Apply a partial update of runtime-adjustable fields. Every field is
+    // validated before any is applied, so a rejected patch leaves the config
+    // untouched.
+    pub fn apply_patch(&mut self, fields: &Map<String, Value>) -> Result<(), String> {
+        let mut updated = self.clone();
+
+        for (key, value) in fields {
+            match key.as_str() {
+                "target_url" => {
+                    let url = value
+                        .as_str()
+                        .ok_or("target_url must be a string")?;
+                    let uri = url
+                        .parse::<hyper::Uri>()
+                        .map_err(|e| format!("target_url is not a valid URL: {}", e))?;
+                    if uri.scheme().is_none() || uri.host().is_none() {
+                        return Err("target_url must be an absolute URL".to_string());
+                    }
+                    updated.target_url = url.to_string();
+                }
+                "success_probability" => {
+                    let probability = value
+                        .as_f64()
+                        .ok_or("success_probability must be a number")?;
+                    if !(0.0..=1.0).contains(&probability) {
+                        return Err("success_probability must be between 0.0 and 1.0".to_string());
+                    }
+                    updated.success_probability = probability;
+                }
+                "slow_request_threshold_ms" => {
+                    updated.slow_request_threshold = match value {
+                        Value::Null => None,
+                        value => Some(Duration::from_millis(value
+                            .as_u64()
+                            .ok_or("slow_request_threshold_ms must be a non-negative integer or null")?)),
+                    };
+                }
+                "chaos_enabled" => {
+                    updated.chaos_enabled = value
+                        .as_bool()
+                        .ok_or("chaos_enabled must be a boolean")?;
+                }
+                "delay_schedule" => {
+                    updated.delay_schedule = DelayRule::parse_list(&value.to_string())
+                        .map_err(|e| format!("delay_schedule is invalid: {}", e))?;
+                }
+                other => return Err(format!("Unknown or read-only field: {}", other)),
+            }
+        }
+
+        *self = updated;
+        Ok(())
+    }
+
+    // Body template for a simulated failure: an exact status code match takes
+    // precedence over a status class such as "5xx"
+    pub fn failure_body_template(&self, status: StatusCode) -> Option<&Value> {
+        let code = status.as_u16();
+        self.failure_body_templates
+            .get(&code.to_string())
+            .or_else(|| self.failure_body_templates.get(&format!("{}xx", code / 100)))
+    }
+
+    // Failure rate for a request method, and where it came from: a
+    // FAILURE_RATE_BY_METHOD entry, or the global SUCCESS_PROBABILITY
+    pub fn failure_rate_for(&self, method: &axum::http::Method) -> (f64, &'static str) {
+        match self.failure_rate_by_method.get(method.as_str()) {
+            Some(&rate) => (rate, "method"),
+            None => (1.0 - self.success_probability, "global"),
+        }
+    }
+
+    // Effective configuration as reported by the admin API. The admin token is
+    // never echoed back.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "target_url": self.target_url,
+            "success_probability": self.success_probability,
+            "connect_timeout_ms": self.connect_timeout.map(|d| d.as_millis() as u64),
+            "disable_pooling": self.disable_pooling,
+            "upstream_sni": self.upstream_sni,
+            "emit_timing": self.emit_timing,
+            "emit_rps_header": self.emit_rps_header,
+            "route_rules": self.route_rules.iter().map(RouteRule::to_json).collect::<Vec<_>>(),
+            "tls_enabled": self.tls_cert_file.is_some(),
+            "tls_handshake_delay_ms": self.tls_handshake_delay.map(|d| d.as_millis() as u64),
+            "failure_body_templates": self.failure_body_templates,
+            "shutdown_drain_delay_ms": self.shutdown_drain_delay.as_millis() as u64,
+            "default_success_status": self.default_success_status.map(|s| s.as_u16()),
+            "chaos_enabled": self.chaos_enabled,
+            "delay_schedule": self.delay_schedule.iter().map(DelayRule::to_json).collect::<Vec<_>>(),
+            "max_cpu_burn_ms": self.max_cpu_burn.as_millis() as u64,
+            "failure_rate_by_method": self.failure_rate_by_method,
+            "client_request_timeout_ms": self.client_request_timeout.map(|d| d.as_millis() as u64),
+            "rotating_bodies": self.rotating_bodies,
+            "sequence_responses": self.sequence_responses.iter().map(SequenceResponse::to_json).collect::<Vec<_>>(),
+            "upstream_retries": self.upstream_retries,
+            "compress_upstream_requests": self.compress_upstream_requests,
+            "fixtures_dir": self.fixtures_dir,
+            "forward_headers": self.forward_headers.iter().map(HeaderName::as_str).collect::<Vec<_>>(),
+            "emit_handler_header": self.emit_handler_header,
+            "warm_key_ttl_secs": self.warm_key_ttl.as_secs(),
+            "enable_trailers": self.enable_trailers,
+            "cold_start_delay_ms": self.cold_start_delay.map(|d| d.as_millis() as u64),
+            "strip_request_headers": self.strip_request_headers.iter().map(HeaderName::as_str).collect::<Vec<_>>(),
+            "strip_response_headers": self.strip_response_headers.iter().map(HeaderName::as_str).collect::<Vec<_>>(),
+            "serve_stale_on_error": self.serve_stale_on_error,
+            "slow_request_threshold_ms": self.slow_request_threshold.map(|d| d.as_millis() as u64),
+            "upstream_max_request_bytes": self.upstream_max_request_bytes,
+            "allow_smuggling": self.allow_smuggling,
+            "vhost_map": vhost::to_json(&self.vhost_map),
+            "default_response_content_type": self.default_response_content_type.as_ref().and_then(|v| v.to_str().ok()),
+            "fanout_urls": self.fanout_urls,
+            "synthetic_interval_ms": self.synthetic_interval.map(|d| d.as_millis() as u64),
+            "synthetic_request": self.synthetic_request.to_json(),
+            "capture_dir": self.capture_dir,
+            "max_header_count": self.max_header_count,
+            "max_header_bytes": self.max_header_bytes,
+            "fault_active_window_secs": self.fault_active_window.map(|d| d.as_secs()),
+            "accel_base_url": self.accel_base_url,
+            "control_file": self.control_file,
+            "success_wrapper_template": self.success_wrapper_template.as_ref().map(WrapperTemplate::to_json),
+            "adaptive_base_ms": self.adaptive_base_ms,
+            "adaptive_per_inflight_ms": self.adaptive_per_inflight_ms,
+            "dns_overrides": self.dns_overrides,
+            "max_inflated_header_bytes": self.max_inflated_header_bytes,
+            "upstream_auth": self.upstream_auth.as_ref().map(TokenSource::to_json),
+            "upstream_min_tls": self.upstream_min_tls.map(TlsVersion::as_str),
+            "upstream_max_tls": self.upstream_max_tls.map(TlsVersion::as_str),
+            "failure_success_body": self.failure_success_body,
+            "gc_pause_interval_secs": self.gc_pause_interval.map(|d| d.as_secs()),
+            "gc_pause_duration_ms": self.gc_pause_duration.as_millis() as u64,
+            "gc_pause_mode": self.gc_pause_mode.as_str(),
+            "max_delay_budget_ms": self.max_delay_budget.map(|d| d.as_millis() as u64),
+            "log_body_max_len": self.log_body_max_len,
+            "listeners": self.listeners
+                .iter()
+                .map(|(port, profile)| (port.to_string(), Value::from(profile.as_str())))
+                .collect::<Map<_, _>>(),
+            "fault_profiles": self.fault_profiles,
+            "profile": self.profile
+        })
+    }
+}
+
+// Parse a status code in the range HTTP defines (100-599)
+pub fn parse_status_code(value: &str) -> Result<StatusCode, String> {
+    value
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|code| (100..=599).contains(code))
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| format!("{} is not a status code between 100 and 599", value.trim()))
+}
+
+// Parse `LISTENERS`, a JSON object mapping ports to fault profile names
+fn parse_listeners(value: &str) -> Result<Vec<(u16, String)>, String> {
+    let listeners: HashMap<String, String> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of port to profile name: {}", e))?;
+    if listeners.is_empty() {
+        return Err("at least one listener is required".to_string());
+    }
+
+    let mut listeners = listeners
+        .into_iter()
+        .map(|(port, profile)| {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| format!("{} is not a port number", port))?;
+            Ok((port, profile))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    listeners.sort();
+    Ok(listeners)
+}
+
+// Parse a JSON object mapping request methods to failure rates. Method names
+// are case-insensitive and stored uppercase.
+fn parse_failure_rate_by_method(value: &str) -> Result<HashMap<String, f64>, String> {
+    let rates: HashMap<String, f64> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of method to rate: {}", e))?;
+
+    rates
+        .into_iter()
+        .map(|(method, rate)| {
+            let method = method.to_ascii_uppercase();
+            if method.parse::<axum::http::Method>().is_err() {
+                return Err(format!("{} is not a request method", method));
+            }
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("failure rate for {} must be between 0.0 and 1.0", method));
+            }
+            Ok((method, rate))
+        })
+        .collect()
+}
+
+// Parse a JSON object mapping status codes ("429") or classes ("5xx") to
+// response bodies
+fn parse_failure_body_templates(value: &str) -> Result<HashMap<String, Value>, String> {
+    let templates: HashMap<String, Value> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object: {}", e))?;
+
+    for key in templates.keys() {
+        let valid = match key.strip_suffix("xx") {
+            Some(class) => matches!(class, "1" | "2" | "3" | "4" | "5"),
+            None => key
+                .parse::<u16>()
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .is_some(),
+        };
+        if !valid {
+            return Err(format!("{} is not a status code or class like 5xx", key));
+        }
+    }
+
+    Ok(templates)
+}
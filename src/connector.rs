@@ -0,0 +1,387 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Uri;
+use hyper_tls::MaybeHttpsStream;
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use native_tls::Protocol;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsConnector;
+use tower_service::Service;
+
+use crate::config::Config;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// HTTP client for upstream requests with body type `B`
+pub type UpstreamClient<B> = Client<TimeoutConnector<UpstreamConnector>, B>;
+
+// Shared HTTP client for proxying buffered requests
+pub type HttpClient = UpstreamClient<Full<Bytes>>;
+
+// Client for request bodies streamed straight from the incoming request
+// (`X-Stream-Request`)
+pub type StreamingClient = UpstreamClient<axum::body::Body>;
+
+// Build an upstream client. `sni` overrides the TLS server name for every
+// connection the client makes; clients with different overrides must not
+// share a connection pool, so each override gets its own client. With
+// `http2` the client only speaks HTTP/2 (offering `h2` via ALPN over TLS);
+// otherwise it uses HTTP/1.1.
+pub fn build_client<B>(config: &Config, sni: Option<String>, http2: bool) -> UpstreamClient<B>
+where
+    B: hyper::body::Body + Send,
+    B::Data: Send,
+{
+    let connector = TimeoutConnector::new(
+        UpstreamConnector::new(sni, http2, &config.dns_overrides, config.upstream_min_tls, config.upstream_max_tls),
+        config.connect_timeout,
+    );
+
+    let mut builder = Client::builder(TokioExecutor::new());
+    if config.disable_pooling {
+        // Keep no idle connections so every request opens a fresh one
+        builder.pool_max_idle_per_host(0);
+    }
+    builder.http2_only(http2);
+    builder.build(connector)
+}
+
+// Connector for http and https upstreams using native-tls (the same backend
+// as hyper-tls). Unlike hyper-tls' HttpsConnector, the name used for the TLS
+// handshake can be overridden instead of always being the URI host. With
+// native-tls that name is used both as the SNI server name and for
+// certificate hostname verification, which is what an IP-addressed upstream
+// presenting a certificate for a hostname needs.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector<OverrideResolver>,
+    tls: TlsConnector,
+    sni: Option<String>,
+    // Whether UPSTREAM_MIN_TLS or UPSTREAM_MAX_TLS constrain the handshake
+    tls_constrained: bool,
+}
+
+impl UpstreamConnector {
+    pub fn new(
+        sni: Option<String>,
+        http2: bool,
+        dns_overrides: &HashMap<String, IpAddr>,
+        min_tls: Option<TlsVersion>,
+        max_tls: Option<TlsVersion>,
+    ) -> Self {
+        let resolver = OverrideResolver {
+            overrides: Arc::new(dns_overrides.clone()),
+            gai: GaiResolver::new(),
+        };
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+
+        let mut tls = native_tls::TlsConnector::builder();
+        if http2 {
+            tls.request_alpns(&["h2"]);
+        }
+        // Without UPSTREAM_MIN_TLS native-tls keeps its own minimum (TLS 1.0),
+        // and without UPSTREAM_MAX_TLS the newest version the system supports
+        if let Some(min_tls) = min_tls {
+            tls.min_protocol_version(Some(min_tls.protocol()));
+        }
+        tls.max_protocol_version(max_tls.map(TlsVersion::protocol));
+        let tls = tls
+            .build()
+            .expect("Failed to initialize TLS connector")
+            .into();
+
+        let tls_constrained = min_tls.is_some() || max_tls.is_some();
+        UpstreamConnector { http, tls, sni, tls_constrained }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = MaybeHttpsStream<TokioIo<TcpStream>>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let is_https = dst.scheme_str() == Some("https");
+        let server_name = self.sni.clone().unwrap_or_else(|| {
+            dst.host()
+                .unwrap_or("")
+                .trim_matches(|c| c == '[' || c == ']')
+                .to_owned()
+        });
+        let connecting = self.http.call(dst);
+        let tls = self.tls.clone();
+        let tls_constrained = self.tls_constrained;
+
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if is_https {
+                let stream = tls.connect(&server_name, TokioIo::new(tcp)).await.map_err(|e| {
+                    if tls_constrained && is_version_mismatch(&e) {
+                        BoxError::from(TlsVersionMismatch(e))
+                    } else {
+                        e.into()
+                    }
+                })?;
+                Ok(MaybeHttpsStream::from(stream))
+            } else {
+                Ok(MaybeHttpsStream::Http(tcp))
+            }
+        })
+    }
+}
+
+// TLS version allowed by UPSTREAM_MIN_TLS or UPSTREAM_MAX_TLS. Declared
+// oldest first, so versions compare in version order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    // Parse a TLS version, `1.0` to `1.3`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            other => Err(format!("{} is not a TLS version, expected 1.0, 1.1, 1.2 or 1.3", other)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+
+    fn protocol(self) -> Protocol {
+        match self {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        }
+    }
+}
+
+// Whether the name is a valid DNS host name: dot-separated labels of ASCII
+// letters, digits and hyphens, not starting or ending with a hyphen
+pub fn is_dns_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+// Parse DNS_OVERRIDES, a JSON object mapping host names to the IP address
+// they resolve to, e.g. `{"api.example.com": "10.0.0.5"}`. Host names are
+// case-insensitive.
+pub fn parse_dns_overrides(value: &str) -> Result<HashMap<String, IpAddr>, String> {
+    let entries: HashMap<String, String> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of host names to IP addresses: {}", e))?;
+
+    entries
+        .into_iter()
+        .map(|(host, ip)| {
+            let ip = ip
+                .parse::<IpAddr>()
+                .map_err(|_| format!("{} is not an IP address (for {})", ip, host))?;
+            Ok((host.to_ascii_lowercase(), ip))
+        })
+        .collect()
+}
+
+// DNS resolver that answers DNS_OVERRIDES host names with their configured
+// address and resolves every other name with getaddrinfo. Only the address
+// connected to changes: the URI, and so the Host header and the TLS server
+// name, keep the host name.
+#[derive(Clone)]
+pub struct OverrideResolver {
+    overrides: Arc<HashMap<String, IpAddr>>,
+    gai: GaiResolver,
+}
+
+impl Service<Name> for OverrideResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.gai.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        // Port 0 makes the connector use the URI's port
+        if let Some(ip) = self.overrides.get(&name.as_str().to_ascii_lowercase()) {
+            let addrs = vec![SocketAddr::new(*ip, 0)];
+            return Box::pin(std::future::ready(Ok(addrs.into_iter())));
+        }
+        let resolving = self.gai.call(name);
+        Box::pin(async move {
+            let addrs = resolving.await?;
+            Ok(addrs.collect::<Vec<_>>().into_iter())
+        })
+    }
+}
+
+// Wraps a connector so that establishing a connection (TCP connect plus any
+// TLS handshake done by the inner connector) is bounded by a timeout. This is
+// independent of how long the upstream then takes to respond.
+#[derive(Clone)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Option<Duration>,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(inner: C, timeout: Option<Duration>) -> Self {
+        TimeoutConnector { inner, timeout }
+    }
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: Send + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let connecting = self.inner.call(dst);
+        let timeout = self.timeout;
+
+        Box::pin(async move {
+            match timeout {
+                Some(duration) => match tokio::time::timeout(duration, connecting).await {
+                    Ok(result) => result.map_err(Into::into),
+                    Err(_) => Err(ConnectTimeout(duration).into()),
+                },
+                None => connecting.await.map_err(Into::into),
+            }
+        })
+    }
+}
+
+// Error returned when the connection could not be established within the
+// configured connect timeout.
+#[derive(Debug)]
+pub struct ConnectTimeout(Duration);
+
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connect timed out after {}ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+// Error returned when the TLS handshake failed because the upstream supports
+// no version in the UPSTREAM_MIN_TLS to UPSTREAM_MAX_TLS range
+#[derive(Debug)]
+pub struct TlsVersionMismatch(native_tls::Error);
+
+impl fmt::Display for TlsVersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no TLS version in the allowed range is supported by the upstream: {}", self.0)
+    }
+}
+
+impl std::error::Error for TlsVersionMismatch {}
+
+// OpenSSL reason codes (sslerr.h) for a failed version negotiation: no
+// version enabled locally, the upstream's protocol_version alert, or a
+// ServerHello outside the allowed range
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+const VERSION_MISMATCH_REASONS: &[std::ffi::c_int] = &[
+    191,  // SSL_R_NO_PROTOCOLS_AVAILABLE
+    1070, // SSL_R_TLSV1_ALERT_PROTOCOL_VERSION
+    258,  // SSL_R_UNSUPPORTED_PROTOCOL
+    166,  // SSL_R_VERSION_TOO_HIGH
+    396,  // SSL_R_VERSION_TOO_LOW
+    267,  // SSL_R_WRONG_VERSION_NUMBER
+];
+
+// native-tls has no error kinds, so a failed version negotiation is
+// recognised by the reason codes in the OpenSSL error stack it wraps
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+fn is_version_mismatch(err: &native_tls::Error) -> bool {
+    const ERR_LIB_SSL: std::ffi::c_int = 20;
+    let mut current: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(e) = current {
+        if let Some(stack) = e.downcast_ref::<openssl::error::ErrorStack>() {
+            return stack.errors().iter().any(|error| {
+                error.library_code() == ERR_LIB_SSL && VERSION_MISMATCH_REASONS.contains(&error.reason_code())
+            });
+        }
+        current = e.source();
+    }
+    false
+}
+
+// Schannel and Secure Transport errors have no reason codes native-tls
+// exposes, so there a version mismatch is reported as a plain connect error
+#[cfg(any(target_os = "windows", target_vendor = "apple"))]
+fn is_version_mismatch(_err: &native_tls::Error) -> bool {
+    false
+}
+
+// Walks the error source chain looking for a failed TLS version negotiation.
+pub fn is_tls_version_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<TlsVersionMismatch>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+// Walks the error source chain looking for a connect timeout.
+pub fn is_connect_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<ConnectTimeout>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
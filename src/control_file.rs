@@ -0,0 +1,49 @@
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+use crate::SharedState;
+
+// How often CONTROL_FILE is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Poll CONTROL_FILE and, whenever its content changes, apply it to every
+// listener's config like a `PATCH /admin/config` body. A file that cannot be
+// read or applied is logged once and the current config is kept until the
+// content changes again.
+pub async fn watch(path: String, states: Vec<SharedState>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        let content = tokio::fs::read_to_string(&path).await.map_err(|e| e.to_string());
+        if last.as_ref() == Some(&content) {
+            continue;
+        }
+        last = Some(content.clone());
+
+        let applied = content.and_then(|content| apply(&content, &states));
+        match applied {
+            Ok(fields) => tracing::info!("Applied CONTROL_FILE {}: {}", path, fields),
+            Err(e) => tracing::warn!("Ignoring CONTROL_FILE {}, keeping the current config: {}", path, e),
+        }
+    }
+}
+
+// Apply the file's fields to every listener, or to none of them if any is
+// rejected. Returns the fields applied.
+fn apply(content: &str, states: &[SharedState]) -> Result<Value, String> {
+    let fields = serde_json::from_str::<Map<String, Value>>(content)
+        .map_err(|e| format!("expected a JSON object: {}", e))?;
+
+    let mut configs = states.iter().map(|state| state.config.write().unwrap()).collect::<Vec<_>>();
+    let mut updated = Vec::new();
+    for config in &configs {
+        let mut config = (**config).clone();
+        config.apply_patch(&fields)?;
+        updated.push(config);
+    }
+    for (config, updated) in configs.iter_mut().zip(updated) {
+        **config = updated;
+    }
+    Ok(Value::Object(fields))
+}
@@ -0,0 +1,85 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Latest request seen for one `X-Debounce-Key`. A newer request bumps
+// `latest` and wakes every older request waiting on `superseded`.
+#[derive(Default)]
+struct Slot {
+    latest: AtomicU64,
+    notify: Notify,
+}
+
+// In-flight requests per `X-Debounce-Key`. A key is dropped once its last
+// request has finished, so the table only holds keys currently in use.
+#[derive(Default)]
+pub struct Debouncer {
+    keys: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+impl Debouncer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Register a request for the key, superseding every earlier request that
+    // is still in flight for it
+    pub fn register(self: &Arc<Self>, key: &str) -> DebounceToken {
+        let slot = self.keys
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let token = slot.latest.fetch_add(1, Ordering::SeqCst) + 1;
+        slot.notify.notify_waiters();
+        DebounceToken {
+            debouncer: self.clone(),
+            key: key.to_string(),
+            slot,
+            token,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "active_keys": self.keys.lock().unwrap().len() })
+    }
+}
+
+// A request's place in its key's sequence
+pub struct DebounceToken {
+    debouncer: Arc<Debouncer>,
+    pub key: String,
+    slot: Arc<Slot>,
+    token: u64,
+}
+
+impl DebounceToken {
+    // Resolves once a newer request with the same key has arrived. The
+    // `Notified` future is enabled before `latest` is checked, so a request
+    // registered in between still wakes it.
+    pub async fn superseded(&self) {
+        loop {
+            let notified = self.slot.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.slot.latest.load(Ordering::SeqCst) != self.token {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for DebounceToken {
+    fn drop(&mut self) {
+        // Only the map and this token still refer to the slot: no other
+        // request is in flight for the key
+        let mut keys = self.debouncer.keys.lock().unwrap();
+        if Arc::strong_count(&self.slot) == 2 {
+            keys.remove(&self.key);
+        }
+    }
+}
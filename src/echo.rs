@@ -0,0 +1,116 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, Extensions, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::ops::ControlFlow;
+
+use crate::fault::FaultContext;
+use crate::SharedState;
+
+// Operations of `X-Echo-Transform`
+#[derive(Clone, Copy)]
+enum Transform {
+    Uppercase,
+    Reverse,
+    Base64,
+    Hash,
+}
+
+impl Transform {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "uppercase" => Ok(Transform::Uppercase),
+            "reverse" => Ok(Transform::Reverse),
+            "base64" => Ok(Transform::Base64),
+            "hash" => Ok(Transform::Hash),
+            other => Err(format!("unknown transform {}, expected uppercase, reverse, base64 or hash", other)),
+        }
+    }
+
+    // Text bodies are transformed character by character, anything else
+    // byte by byte
+    fn apply(self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            Transform::Uppercase => match String::from_utf8(body) {
+                Ok(text) => text.to_uppercase().into_bytes(),
+                Err(e) => e.into_bytes().to_ascii_uppercase(),
+            },
+            Transform::Reverse => match String::from_utf8(body) {
+                Ok(text) => text.chars().rev().collect::<String>().into_bytes(),
+                Err(e) => e.into_bytes().into_iter().rev().collect(),
+            },
+            Transform::Base64 => BASE64_STANDARD.encode(body).into_bytes(),
+            Transform::Hash => Sha256::digest(body)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+                .into_bytes(),
+        }
+    }
+}
+
+// Parse `X-Echo-Transform`, a comma-separated chain of transforms applied
+// from left to right
+fn parse_chain(headers: &HeaderMap) -> Result<Vec<Transform>, String> {
+    let Some(h) = headers.get("X-Echo-Transform") else {
+        return Ok(Vec::new());
+    };
+    h.to_str()
+        .map_err(|e| e.to_string())?
+        .split(',')
+        .filter(|name| !name.trim().is_empty())
+        .map(Transform::parse)
+        .collect()
+}
+
+// Return the request body passed through the `X-Echo-Transform` chain, as a
+// deterministic stand-in for an upstream. Nothing is sent to the upstream.
+// The delay headers apply, and failures when requested with
+// `X-Failure-Rate`, as for /fixture.
+pub async fn echo_transform_handler(
+    State(state): State<SharedState>,
+    method: Method,
+    headers: HeaderMap,
+    extensions: Extensions,
+    body: Bytes,
+) -> Response {
+    let chain = match parse_chain(&headers) {
+        Ok(chain) => chain,
+        Err(details) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Echo-Transform",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+
+    // Base64 and hashes are text whatever the input was; the other
+    // transforms keep the request's Content-Type
+    let content_type = if chain.iter().any(|t| matches!(t, Transform::Base64 | Transform::Hash)) {
+        HeaderValue::from_static("text/plain; charset=utf-8")
+    } else {
+        headers
+            .get(header::CONTENT_TYPE)
+            .cloned()
+            .unwrap_or_else(|| HeaderValue::from_static("application/octet-stream"))
+    };
+
+    let mut ctx = FaultContext::without_body(&state, method, headers, &extensions, "/echo-transform".to_string());
+    if let ControlFlow::Break(response) = state.echo_faults.apply(&mut ctx).await {
+        return response;
+    }
+
+    let transformed = chain.iter().fold(body.to_vec(), |body, transform| transform.apply(body));
+    let mut response = transformed.into_response();
+    response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    response
+}
+
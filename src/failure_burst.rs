@@ -0,0 +1,50 @@
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+use crate::random;
+
+// Two-state failure model driven by `X-Fail-Enter-Prob` and
+// `X-Fail-Exit-Prob`. Each request moves the chain one step: a healthy chain
+// starts failing with the enter probability, a failing one recovers with the
+// exit probability, and the request fails while the chain is failing. Runs
+// of failures therefore average 1 / exit requests instead of being spread
+// out independently.
+#[derive(Default)]
+pub struct FailureBurst {
+    // Whether the chain is failing, and how many requests in a row have seen
+    // that state
+    state: Mutex<(bool, u64)>,
+}
+
+impl FailureBurst {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Advance the chain for one request. Returns whether the request fails
+    // and the length of the current run, counting this request.
+    pub fn step(&self, enter_probability: f64, exit_probability: f64) -> (bool, u64) {
+        let mut state = self.state.lock().unwrap();
+        let (failing, run) = *state;
+        let next = if failing {
+            !random::chance(exit_probability)
+        } else {
+            random::chance(enter_probability)
+        };
+        *state = (next, if next == failing { run + 1 } else { 1 });
+        *state
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (failing, run) = *self.state.lock().unwrap();
+        json!({
+            "state": if failing { "failing" } else { "healthy" },
+            "run_length": run
+        })
+    }
+
+    // Start over healthy
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = (false, 0);
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Method, Request, StatusCode, Uri},
+    response::Json,
+};
+use bytes::Bytes;
+use futures_util::future::join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use http_body_util::Full;
+use serde_json::{json, Value};
+use std::time::Instant;
+
+use crate::connector::HttpClient;
+use crate::{proxy, send_upstream, SharedState};
+
+// Upper bound for FANOUT_URLS, so one request cannot open unbounded upstream
+// calls
+pub const MAX_FANOUT_URLS: usize = 16;
+
+// Parse `FANOUT_URLS`, a comma-separated list of absolute upstream URLs
+pub fn parse_fanout_urls(value: &str) -> Result<Vec<String>, String> {
+    let urls = value
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            let uri = url
+                .parse::<Uri>()
+                .map_err(|e| format!("{} is not a valid URL: {}", url, e))?;
+            if uri.scheme().is_none() || uri.host().is_none() {
+                return Err(format!("{} must be an absolute URL", url));
+            }
+            Ok(url.to_string())
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if urls.len() > MAX_FANOUT_URLS {
+        return Err(format!("at most {} URLs are allowed", MAX_FANOUT_URLS));
+    }
+    Ok(urls)
+}
+
+// Send the body to one upstream and describe the outcome: its status, body
+// and latency, or the forwarding error
+async fn call(client: &HttpClient, url: &str, content_type: Option<&header::HeaderValue>, body: Bytes) -> (bool, Value) {
+    let mut builder = Request::builder().method(Method::POST).uri(url);
+    if let Some(content_type) = content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+    let req = match builder.body(Full::new(body)) {
+        Ok(req) => req,
+        Err(e) => return (false, json!({ "url": url, "error": "Invalid URL", "details": e.to_string() })),
+    };
+
+    let start = Instant::now();
+    let result = send_upstream(client, req, url).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(upstream) => {
+            let (body, encoding) = proxy::body_value(&upstream.body);
+            let mut report = json!({
+                "url": url,
+                "status": upstream.status.as_u16(),
+                "latency_ms": latency_ms,
+                "body": body
+            });
+            if let Some(encoding) = encoding {
+                report["response_encoding"] = json!(encoding);
+            }
+            (true, report)
+        }
+        Err((_, Json(mut error))) => {
+            // Named `url` like the successful outcomes
+            if let Some(error) = error.as_object_mut() {
+                error.remove("target_url");
+            }
+            error["url"] = json!(url);
+            error["latency_ms"] = json!(latency_ms);
+            (false, error)
+        }
+    }
+}
+
+// Send the request body to every FANOUT_URLS upstream concurrently. With
+// `X-Fanout-Wait-For: all` (the default) every upstream's outcome is
+// reported; with `first`, the first upstream to respond wins and the calls
+// still in flight are cancelled.
+pub async fn fanout_handler(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    let wait_for_first = match headers.get("X-Fanout-Wait-For").map(|h| h.to_str().unwrap_or_default()) {
+        None | Some("all") => false,
+        Some("first") => true,
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Fanout-Wait-For",
+                    "details": "X-Fanout-Wait-For must be all or first"
+                }))
+            );
+        }
+    };
+
+    let urls = state.config.read().unwrap().fanout_urls.clone();
+    if urls.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Fan-out not configured",
+                "details": "Set FANOUT_URLS to the upstreams to send requests to"
+            }))
+        );
+    }
+    let client = match state.client_for(&headers) {
+        Ok(client) => client,
+        Err(rejection) => return rejection,
+    };
+
+    let started = Instant::now();
+    let content_type = headers.get(header::CONTENT_TYPE);
+    let calls = urls.iter().map(|url| call(&client, url, content_type, body.clone()));
+
+    // With `all`, outcomes are listed in FANOUT_URLS order. With `first`,
+    // they are listed in completion order up to the first upstream that
+    // answers, whatever its status, and dropping the rest cancels them.
+    let outcomes = if wait_for_first {
+        let mut calls = calls.collect::<FuturesUnordered<_>>();
+        let mut outcomes = Vec::new();
+        while let Some((ok, report)) = calls.next().await {
+            outcomes.push((ok, report));
+            if ok {
+                break;
+            }
+        }
+        outcomes
+    } else {
+        join_all(calls).await
+    };
+    let answered = outcomes.iter().any(|(ok, _)| *ok);
+    let responses = outcomes.into_iter().map(|(_, report)| report).collect::<Vec<_>>();
+
+    let status = if answered { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+    (status, Json(json!({
+        "wait_for": if wait_for_first { "first" } else { "all" },
+        "upstreams": urls.len(),
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "responses": responses
+    })))
+}
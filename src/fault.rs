@@ -0,0 +1,1113 @@
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    http::{header, Extensions, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use http_body_util::Full;
+use serde_json::{json, Map, Value};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::accel;
+use crate::body_hash::{BodyHashCheck, Mismatch};
+use crate::capture;
+use crate::config::{self, Config};
+use crate::debounce::{DebounceToken, Debouncer};
+use crate::connector::HttpClient;
+use crate::failure_burst::FailureBurst;
+use crate::fault_log::FaultLog;
+use crate::metrics::{Outcome, RequestOutcome};
+use crate::request_id::RequestId;
+use crate::server::ConnectionState;
+use crate::timing::Timing;
+use crate::upstream_auth::{self, UpstreamAuth};
+use crate::warm_keys::WarmKeys;
+use crate::wrapper::{self, WrapperTemplate};
+use crate::{
+    clone_request, compression, encode_body, note_chaos_disabled, parse_json_body, proxy, random, retry, schedule,
+    send_upstream_maybe_hedged, vhost, SharedState, UpstreamResponse,
+};
+
+// Per-request state that faults read and adjust before the request is
+// proxied. The config is a snapshot taken when the request arrived.
+pub struct FaultContext {
+    pub config: Config,
+    pub client: HttpClient,
+    pub upstream_auth: Arc<UpstreamAuth>,
+    // When the handler received the request
+    pub arrived: Instant,
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub payload: Value,
+    pub target_url: String,
+    // Retries allowed for the upstream call (`X-Upstream-Retries` or
+    // UPSTREAM_RETRIES), before the method check
+    pub max_retries: u32,
+    pub timing: Timing,
+    pub outcome: RequestOutcome,
+    pub fault_log: FaultLog,
+    pub connection: ConnectionState,
+    pub request_id: RequestId,
+    // `X-Debounce-Key` registration and the status a superseded request is
+    // answered with
+    pub debounce: Option<(DebounceToken, StatusCode)>,
+    // Status reported instead of the upstream's when proxying succeeds
+    pub forced_status: Option<StatusCode>,
+    // Return the upstream body unchanged instead of the success wrapper
+    pub return_original: bool,
+    // Fields added to the success wrapper by the faults that ran
+    pub report: Map<String, Value>,
+}
+
+impl FaultContext {
+    // Build the context for a request, rejecting bodies that are not JSON
+    pub fn new(
+        state: &SharedState,
+        method: Method,
+        headers: HeaderMap,
+        extensions: &Extensions,
+        body: Bytes,
+    ) -> Result<Self, (StatusCode, Json<Value>)> {
+        let mut ctx = FaultContext::without_body(state, method, headers, extensions, String::new());
+        ctx.client = state.client_for(&ctx.headers)?;
+        ctx.payload = parse_json_body(&ctx.headers, &body)?;
+        ctx.max_retries = retry::max_retries(&ctx.config, &ctx.headers)?;
+        ctx.body = body;
+
+        // `X-Proxy-Url` overrides the target URL for testing, then VHOST_MAP
+        let (target_url, vhost) = vhost::resolve_target(&ctx.config, &ctx.headers);
+        if let Some(vhost) = vhost {
+            ctx.report.insert("vhost".to_string(), Value::from(vhost));
+        }
+
+        // `X-Upstream-Scheme` switches the target between http and https
+        ctx.target_url = match vhost::upstream_scheme(&ctx.headers)? {
+            Some(scheme) => {
+                ctx.report.insert("upstream_scheme".to_string(), Value::from(scheme));
+                vhost::with_scheme(&target_url, scheme)
+            }
+            None => target_url,
+        };
+
+        Ok(ctx)
+    }
+
+    // Context for a request that is answered locally rather than proxied, so
+    // there is no body to parse or upstream to call. `target` names what is
+    // served in failure bodies. The per-request records (`Timing`,
+    // `RequestOutcome`, `FaultLog`, `ConnectionState`, `RequestId`) come from
+    // the request extensions.
+    pub fn without_body(
+        state: &SharedState,
+        method: Method,
+        headers: HeaderMap,
+        extensions: &Extensions,
+        target: String,
+    ) -> Self {
+        let mut config = state.config.read().unwrap().clone();
+        // Past FAULT_ACTIVE_WINDOW_SECS the request is handled as with chaos
+        // disabled, whatever the flag says
+        config.chaos_enabled &= !state.fault_window_ended(&config);
+        let mut ctx = FaultContext {
+            config,
+            client: state.client.clone(),
+            upstream_auth: state.upstream_auth.clone(),
+            arrived: Instant::now(),
+            method,
+            headers,
+            body: Bytes::new(),
+            payload: Value::Null,
+            target_url: target,
+            max_retries: 0,
+            timing: extensions.get().cloned().unwrap_or_default(),
+            outcome: extensions.get().cloned().unwrap_or_default(),
+            fault_log: extensions.get().cloned().unwrap_or_default(),
+            connection: extensions.get().cloned().unwrap_or_default(),
+            request_id: extensions.get().cloned().unwrap_or_default(),
+            debounce: None,
+            forced_status: None,
+            return_original: false,
+            report: Map::new(),
+        };
+        ctx.note_delay_budget();
+        ctx
+    }
+}
+
+impl FaultContext {
+    // Sleep for an injected delay. A request registered with
+    // `X-Debounce-Key` wakes early when a newer request with the same key
+    // arrives, and is answered with the debounce status instead.
+    pub async fn sleep(&mut self, duration: Duration) -> ControlFlow<Response> {
+        let duration = self.charge_delay_budget(duration);
+        let Some((token, status)) = &self.debounce else {
+            tokio::time::sleep(duration).await;
+            return ControlFlow::Continue(());
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => ControlFlow::Continue(()),
+            _ = token.superseded() => {
+                self.outcome.mark(Outcome::SimulatedFailure);
+                self.fault_log.record("debounced", status.as_u16());
+                ControlFlow::Break((
+                    *status,
+                    Json(json!({
+                        "error": "Superseded by a newer request",
+                        "debounce_key": token.key
+                    }))
+                ).into_response())
+            }
+        }
+    }
+
+    // With MAX_DELAY_BUDGET_MS, take the delay out of what is left of the
+    // connection's budget. Once the budget runs out the delay is cut short,
+    // and later ones on the connection are skipped.
+    fn charge_delay_budget(&mut self, duration: Duration) -> Duration {
+        let granted = self.connection.charge_delay(self.config.max_delay_budget, duration);
+        self.note_delay_budget();
+        granted
+    }
+
+    // Report `delay_budget_exhausted` once the connection has spent its
+    // budget, whether or not this request asked for a delay
+    fn note_delay_budget(&mut self) {
+        let budget = self.config.max_delay_budget;
+        if let Some(budget) = budget.filter(|_| self.connection.delay_budget_spent(budget)) {
+            self.report.insert("delay_budget_exhausted".to_string(), Value::from(true));
+            self.fault_log.record("delay_budget_exhausted", budget.as_millis());
+        }
+    }
+}
+
+// A composable unit of fault injection. Faults run in registration order;
+// breaking short-circuits the pipeline with the given response instead of
+// proxying the request.
+#[async_trait]
+pub trait Fault: Send + Sync {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response>;
+}
+
+// Ordered faults applied to a request before it is proxied
+#[derive(Clone, Default)]
+pub struct FaultPipeline {
+    faults: Vec<Arc<dyn Fault>>,
+}
+
+impl FaultPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(mut self, fault: impl Fault + 'static) -> Self {
+        self.faults.push(Arc::new(fault));
+        self
+    }
+
+    // Apply each fault in turn, stopping at the first that answers the request
+    pub async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        for fault in &self.faults {
+            fault.apply(ctx).await?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Apply the faults and, unless one of them answered the request, proxy it
+    // upstream
+    pub async fn run(&self, mut ctx: FaultContext) -> Response {
+        match self.apply(&mut ctx).await {
+            ControlFlow::Break(response) => response,
+            ControlFlow::Continue(()) => forward(ctx).await,
+        }
+    }
+}
+
+// Send the request upstream and wrap the response with the faults' reports
+async fn forward(mut ctx: FaultContext) -> Response {
+    // Convert the JSON payload to bytes in the requested format
+    let body_bytes = match encode_body(&ctx.headers, &ctx.payload, &ctx.body) {
+        Ok(bytes) => bytes,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let gzip = match compression::gzip_requested(&ctx.config, &ctx.headers) {
+        Ok(gzip) => gzip,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let hash_check = match BodyHashCheck::from_headers(&ctx.headers) {
+        Ok(check) => check,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let target_latency = match parse_ms_header(&ctx.headers, "X-Target-Latency-Ms") {
+        Ok(target) => target.filter(|_| ctx.config.chaos_enabled),
+        Err(rejection) => return rejection.into_response(),
+    };
+    let capture_dir = match capture::capture_dir(&ctx.config, &ctx.headers) {
+        Ok(dir) => dir,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let budget = match parse_ms_header(&ctx.headers, "X-Latency-Budget-Ms") {
+        Ok(budget) => budget.filter(|_| ctx.config.chaos_enabled),
+        Err(rejection) => return rejection.into_response(),
+    };
+    let template = match wrapper::from_headers(&ctx.headers) {
+        Ok(template) => template.or_else(|| ctx.config.success_wrapper_template.clone()),
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    // `X-Latency-Budget-Ms`: give up before calling the upstream when the
+    // delays already applied have used up the budget, and otherwise allow the
+    // upstream call (with any retries and hedges) only what is left of it
+    let upstream_timeout = match budget {
+        Some(budget) => {
+            let elapsed = ctx.arrived.elapsed();
+            if elapsed >= budget {
+                ctx.outcome.mark(Outcome::SimulatedFailure);
+                ctx.fault_log.record("budget_exceeded", elapsed.as_millis());
+                return budget_exceeded(budget, elapsed, &ctx.target_url).into_response();
+            }
+            ctx.report.insert("latency_budget".to_string(), json!({
+                "budget_ms": budget.as_millis() as u64,
+                "upstream_timeout_ms": (budget - elapsed).as_millis() as u64
+            }));
+            Some((budget, budget - elapsed))
+        }
+        None => None,
+    };
+
+    let token = match ctx.upstream_auth.token(&ctx.config).await {
+        Ok(token) => token,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    // Create and send the proxied request
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .uri(&ctx.target_url)
+        .header("content-type", "application/json");
+    let body_bytes = if gzip {
+        builder = builder.header("content-encoding", "gzip");
+        compression::gzip(&body_bytes)
+    } else {
+        body_bytes
+    };
+    if let Some(key) = ctx.headers.get("Idempotency-Key") {
+        builder = builder.header("Idempotency-Key", key);
+    }
+    let builder = upstream_auth::authorize(builder, token.as_deref());
+    let builder = proxy::forward_allowed_headers(&ctx.config.forward_headers, &ctx.headers, builder);
+    let builder = proxy::strip_request_headers(&ctx.config.strip_request_headers, builder);
+    let req = builder.body(Full::new(body_bytes)).unwrap();
+
+    // The upstream call is always a POST, so it is only retried when the
+    // client vouches for it being idempotent
+    let retryable = retry::is_retryable(&Method::POST, &ctx.headers);
+    let retries = if retryable { ctx.max_retries } else { 0 };
+
+    let upstream_start = Instant::now();
+    let mut attempts_started = 0;
+    let calls = retry::with_retries(retries, || {
+        attempts_started += 1;
+        send_upstream_maybe_hedged(&ctx.client, clone_request(&req), &ctx.target_url, &ctx.headers)
+    });
+    let ((upstream, hedge), attempts) = match upstream_timeout {
+        Some((budget, timeout)) => match tokio::time::timeout(timeout, calls).await {
+            Ok(output) => output,
+            Err(_) => {
+                let elapsed = ctx.arrived.elapsed();
+                ctx.fault_log.record("budget_exceeded", elapsed.as_millis());
+                ((Err(budget_exceeded(budget, elapsed, &ctx.target_url)), None), attempts_started)
+            }
+        },
+        None => calls.await,
+    };
+    // ACCEL_BASE_URL: follow `X-Accel-Redirect` to the response to return
+    let (upstream, accel_followed) = match (upstream, ctx.config.accel_base_url.as_deref()) {
+        (Ok(upstream), Some(base_url)) => match accel::follow(&ctx.client, base_url, upstream).await {
+            Ok((upstream, followed)) => (Ok(upstream), followed),
+            Err(rejection) => (Err(rejection), Vec::new()),
+        },
+        (upstream, _) => (upstream, Vec::new()),
+    };
+    if let Some(report) = accel::report(&accel_followed) {
+        ctx.report.insert("accel_redirect".to_string(), report);
+    }
+    let upstream_elapsed = upstream_start.elapsed();
+    ctx.timing.record_upstream(upstream_elapsed);
+    if let Ok(upstream) = &upstream {
+        ctx.timing.record_upstream_status(upstream.status);
+    }
+    if attempts > 1 {
+        ctx.fault_log.record("retries", attempts - 1);
+    }
+    if let Some(winner) = hedge.as_ref().and_then(|h| h.get("winner")) {
+        ctx.fault_log.record("hedge_winner", winner);
+    }
+
+    // `X-Target-Latency-Ms`: pad the response so the request takes the
+    // target time in total, or return at once if it already has
+    if let Some(target) = target_latency {
+        let padding = ctx.charge_delay_budget(target.saturating_sub(ctx.arrived.elapsed()));
+        if !padding.is_zero() {
+            tokio::time::sleep(padding).await;
+            ctx.timing.record_delay(padding);
+            ctx.fault_log.record("latency_padding", padding.as_millis());
+        }
+        ctx.report.insert("target_latency".to_string(), json!({
+            "target_ms": target.as_millis() as u64,
+            "upstream_ms": upstream_elapsed.as_millis() as u64,
+            "padding_ms": padding.as_millis() as u64
+        }));
+    }
+    let UpstreamResponse { status, reason, version, headers: upstream_headers, body: body_bytes } = match upstream {
+        Ok(upstream) => upstream,
+        Err(error_response) => {
+            ctx.outcome.mark(Outcome::UpstreamFailure);
+            let mut response = error_response.into_response();
+            add_attempts_header(&mut response, ctx.max_retries, attempts);
+            return response;
+        }
+    };
+
+    // `X-Expect-Body-Sha256`: check the body as received from the upstream
+    let mismatch = hash_check.and_then(|check| check.verify(&body_bytes));
+    if let Some(mut response) = mismatch.as_ref().and_then(Mismatch::strict_response) {
+        ctx.outcome.mark(Outcome::UpstreamFailure);
+        add_attempts_header(&mut response, ctx.max_retries, attempts);
+        return response;
+    }
+
+    // `X-Capture: true`: save the body as received from the upstream
+    let capture_file = capture_dir.map(|dir| {
+        capture::save(dir, &ctx.request_id, upstream_headers.get(header::CONTENT_TYPE), body_bytes.clone())
+    });
+
+    let mut response = if inject_meta_requested(&ctx.headers) {
+        inject_meta(&ctx, status, &upstream_headers, body_bytes)
+    } else if ctx.return_original || matches!(template, Some(WrapperTemplate::Passthrough)) {
+        let status = if ctx.return_original { status } else { ctx.forced_status.unwrap_or(status) };
+        let content_type = proxy::response_content_type(&ctx.config, &upstream_headers, &body_bytes);
+        let mut response = (status, body_bytes).into_response();
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_TYPE);
+        if let Some(content_type) = content_type {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        response
+    } else {
+        let (body, encoding) = proxy::body_value(&body_bytes);
+        let mut response = json!({
+            "status": "success",
+            "target_url": ctx.target_url,
+            "response": body
+        });
+        if let Some(encoding) = encoding {
+            response["response_encoding"] = json!(encoding);
+        }
+        for (key, value) in ctx.report {
+            response[key] = value;
+        }
+        if let Some(hedge) = hedge {
+            response["hedge"] = hedge;
+        }
+        if ctx.max_retries > 0 {
+            response["retry"] = retry::report(ctx.max_retries, retryable, attempts);
+        }
+        if ctx.headers.contains_key("X-Upstream-Http-Version") {
+            response["upstream_http_version"] = json!(format!("{:?}", version));
+        }
+        note_chaos_disabled(&ctx.config, &mut response);
+        if let Some(WrapperTemplate::Template(template)) = &template {
+            response = wrapper::render(template, &response);
+        }
+        (ctx.forced_status.unwrap_or(status), Json(response)).into_response()
+    };
+    proxy::preserve_reason(&mut response, status, reason);
+    add_attempts_header(&mut response, ctx.max_retries, attempts);
+    if let Some(mismatch) = mismatch {
+        mismatch.flag(&mut response);
+    }
+    if let Some(name) = capture_file {
+        capture::add_header(response.headers_mut(), &name);
+    }
+    accel::add_header(response.headers_mut(), &accel_followed);
+    response
+}
+
+// Parse a duration in milliseconds from the header, e.g.
+// `X-Target-Latency-Ms`, the total time a request should take
+fn parse_ms_header(headers: &HeaderMap, name: &str) -> Result<Option<Duration>, (StatusCode, Json<Value>)> {
+    let Some(h) = headers.get(name) else {
+        return Ok(None);
+    };
+    h.to_str()
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(|ms| Some(Duration::from_millis(ms)))
+        .ok_or_else(|| (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("Invalid {}", name),
+                "details": format!("{} must be a non-negative integer", name)
+            }))
+        ))
+}
+
+// 504 for a request whose `X-Latency-Budget-Ms` ran out after `elapsed`
+fn budget_exceeded(budget: Duration, elapsed: Duration, target_url: &str) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::GATEWAY_TIMEOUT,
+        Json(json!({
+            "error": "Latency budget exceeded",
+            "error_type": "latency_budget_exceeded",
+            "budget_ms": budget.as_millis() as u64,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "target_url": target_url
+        }))
+    )
+}
+
+fn inject_meta_requested(headers: &HeaderMap) -> bool {
+    headers
+        .get("X-Inject-Meta")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false)
+}
+
+// `X-Inject-Meta: true`: return the upstream body with a `_proxy` object
+// added at the top level, for correlating responses with the proxy's logs.
+// Bodies that are not JSON objects are returned unchanged with the metadata
+// in `X-Proxy-Meta` instead.
+fn inject_meta(ctx: &FaultContext, status: StatusCode, upstream_headers: &HeaderMap, body: Bytes) -> Response {
+    let meta = json!({
+        "request_id": ctx.request_id.as_str(),
+        "applied_delay_ms": ctx.timing.delay().as_millis() as u64,
+        "fault_injected": !ctx.fault_log.is_empty(),
+        "target_url": ctx.target_url
+    });
+
+    if let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&body) {
+        object.insert("_proxy".to_string(), meta);
+        return (status, Json(Value::Object(object))).into_response();
+    }
+
+    let content_type = proxy::response_content_type(&ctx.config, upstream_headers, &body);
+    let mut response = (status, body).into_response();
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    if let Some(content_type) = content_type {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if let Ok(value) = HeaderValue::from_str(&meta.to_string()) {
+        headers.insert("X-Proxy-Meta", value);
+    }
+    response
+}
+
+// `X-Upstream-Attempts`: how many upstream calls were made, when retries
+// were requested
+fn add_attempts_header(response: &mut Response, max_retries: u32, attempts: u32) {
+    if max_retries > 0 {
+        response.headers_mut().insert("X-Upstream-Attempts", HeaderValue::from(attempts));
+    }
+}
+
+// `X-Force-Status` or DEFAULT_SUCCESS_STATUS: the status reported to the
+// client when proxying succeeds, regardless of the upstream's status
+pub struct ForceStatus;
+
+#[async_trait]
+impl Fault for ForceStatus {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        ctx.forced_status = match ctx.headers.get("X-Force-Status") {
+            None => ctx.config.default_success_status,
+            Some(h) => match h.to_str().map_err(|e| e.to_string()).and_then(config::parse_status_code) {
+                Ok(status) => Some(status),
+                Err(details) => {
+                    return ControlFlow::Break((
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({
+                            "error": "Invalid X-Force-Status",
+                            "details": details
+                        }))
+                    ).into_response());
+                }
+            },
+        };
+        if let Some(status) = ctx.forced_status {
+            ctx.fault_log.record("status_override", status.as_u16());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+// Constant (`X-Constant-Delay-Ms`), random (`X-Max-Random-Delay-Ms`) and
+// scheduled (DELAY_SCHEDULE) delays, reported as `applied_delays`
+pub struct Delay;
+
+#[async_trait]
+impl Fault for Delay {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let chaos_enabled = ctx.config.chaos_enabled;
+
+        // Parse delay configuration from headers. With chaos disabled no delay
+        // is injected, whatever the headers ask for.
+        let constant_delay_ms: Option<u64> = ctx.headers
+            .get("X-Constant-Delay-Ms")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse().ok())
+            .filter(|_| chaos_enabled);
+
+        let max_random_delay_ms: Option<u64> = ctx.headers
+            .get("X-Max-Random-Delay-Ms")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse().ok())
+            .filter(|_| chaos_enabled);
+        let random_delay_ms = max_random_delay_ms.map(random::up_to);
+
+        // Extra delay from the DELAY_SCHEDULE window covering the current time
+        let scheduled = schedule::active_rule(&ctx.config.delay_schedule)
+            .filter(|_| chaos_enabled)
+            .map(|(index, rule)| (index, rule.delay_ms()));
+
+        let delay_start = Instant::now();
+        let total_ms = constant_delay_ms.unwrap_or(0)
+            + random_delay_ms.unwrap_or(0)
+            + scheduled.map_or(0, |(_, delay_ms)| delay_ms);
+        let slept = if total_ms > 0 {
+            ctx.sleep(Duration::from_millis(total_ms)).await
+        } else {
+            ControlFlow::Continue(())
+        };
+        ctx.timing.record_delay(delay_start.elapsed());
+        slept?;
+        if let Some(delay_ms) = constant_delay_ms {
+            ctx.fault_log.record("delay", delay_ms);
+        }
+        if let Some(delay_ms) = random_delay_ms {
+            ctx.fault_log.record("random_delay", delay_ms);
+        }
+        if let Some((_, delay_ms)) = scheduled {
+            ctx.fault_log.record("schedule_delay", delay_ms);
+        }
+
+        ctx.report.insert("applied_delays".to_string(), json!({
+            "constant_delay_ms": constant_delay_ms,
+            "random_delay_ms": max_random_delay_ms.map(|max| format!("0-{}", max)),
+            "schedule": scheduled.map(|(index, delay_ms)| json!({
+                "rule": index,
+                "delay_ms": delay_ms
+            }))
+        }));
+        ControlFlow::Continue(())
+    }
+}
+
+// Scope of the request ordinal a warmup delay is computed from
+#[derive(Clone, Copy)]
+enum WarmupScope {
+    Connection,
+    Global,
+}
+
+// Parse `X-Warmup-Count`, `X-Warmup-Base-Delay-Ms` and `X-Warmup-Scope`.
+// Returns None when no warmup was requested.
+fn parse_warmup(headers: &HeaderMap) -> Result<Option<(u64, u64, WarmupScope)>, String> {
+    let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap_or(""));
+    let (count, base_delay_ms) = match (header("X-Warmup-Count"), header("X-Warmup-Base-Delay-Ms")) {
+        (None, None) => return Ok(None),
+        (Some(count), Some(base_delay_ms)) => (count, base_delay_ms),
+        _ => return Err("X-Warmup-Count and X-Warmup-Base-Delay-Ms must be set together".to_string()),
+    };
+    let count = count
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or("X-Warmup-Count must be a positive integer")?;
+    let base_delay_ms = base_delay_ms
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| "X-Warmup-Base-Delay-Ms must be a non-negative integer")?;
+    let scope = match header("X-Warmup-Scope") {
+        None | Some("connection") => WarmupScope::Connection,
+        Some("global") => WarmupScope::Global,
+        Some(_) => return Err("X-Warmup-Scope must be connection or global".to_string()),
+    };
+    Ok(Some((count, base_delay_ms, scope)))
+}
+
+// Warmup curve for JIT- or cache-warming upstreams: the nth request (from 0)
+// of a connection, or of the whole proxy with `X-Warmup-Scope: global`, is
+// delayed by `base * (count - n) / count`, reaching zero after `count`
+// requests. Only requests carrying the warmup headers are counted.
+pub struct Warmup {
+    // Ordinal for the global scope, shared with the admin reset
+    global_requests: Arc<AtomicU64>,
+}
+
+impl Warmup {
+    pub fn new(global_requests: Arc<AtomicU64>) -> Self {
+        Warmup { global_requests }
+    }
+}
+
+#[async_trait]
+impl Fault for Warmup {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let (count, base_delay_ms, scope) = match parse_warmup(&ctx.headers) {
+            Ok(Some(warmup)) if ctx.config.chaos_enabled => warmup,
+            Ok(_) => return ControlFlow::Continue(()),
+            Err(details) => {
+                return ControlFlow::Break((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid warmup headers",
+                        "details": details
+                    }))
+                ).into_response());
+            }
+        };
+
+        let (counter, scope_name) = match scope {
+            WarmupScope::Connection => (&*ctx.connection.warmup_requests, "connection"),
+            WarmupScope::Global => (&*self.global_requests, "global"),
+        };
+        let ordinal = counter.fetch_add(1, Ordering::Relaxed);
+        let delay_ms = (base_delay_ms as u128 * count.saturating_sub(ordinal) as u128 / count as u128) as u64;
+
+        if delay_ms > 0 {
+            let delay_start = Instant::now();
+            let slept = ctx.sleep(Duration::from_millis(delay_ms)).await;
+            ctx.timing.record_delay(delay_start.elapsed());
+            slept?;
+            ctx.fault_log.record("warmup_delay", delay_ms);
+        }
+
+        ctx.report.insert("warmup".to_string(), json!({
+            "scope": scope_name,
+            "ordinal": ordinal,
+            "count": count,
+            "base_delay_ms": base_delay_ms,
+            "delay_ms": delay_ms
+        }));
+        ControlFlow::Continue(())
+    }
+}
+
+// Parse `X-Warm-Delay-Start-Ms` and `X-Warm-Delay-Floor-Ms`, keyed by
+// `X-Warm-Key` or else `Idempotency-Key`. Returns None when no warm delay was
+// requested.
+fn parse_warm_delay(headers: &HeaderMap) -> Result<Option<(String, u64, u64)>, String> {
+    let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap_or("").trim());
+    let Some(start_ms) = header("X-Warm-Delay-Start-Ms") else {
+        if header("X-Warm-Delay-Floor-Ms").is_some() {
+            return Err("X-Warm-Delay-Floor-Ms requires X-Warm-Delay-Start-Ms".to_string());
+        }
+        return Ok(None);
+    };
+    let start_ms = start_ms
+        .parse::<u64>()
+        .map_err(|_| "X-Warm-Delay-Start-Ms must be a non-negative integer")?;
+    let floor_ms = match header("X-Warm-Delay-Floor-Ms") {
+        None => 0,
+        Some(floor_ms) => floor_ms
+            .parse::<u64>()
+            .ok()
+            .filter(|&floor_ms| floor_ms <= start_ms)
+            .ok_or("X-Warm-Delay-Floor-Ms must be an integer no greater than X-Warm-Delay-Start-Ms")?,
+    };
+    let key = header("X-Warm-Key")
+        .or_else(|| header("Idempotency-Key"))
+        .filter(|key| !key.is_empty())
+        .ok_or("X-Warm-Delay-Start-Ms requires X-Warm-Key or Idempotency-Key")?;
+    Ok(Some((key.to_string(), start_ms, floor_ms)))
+}
+
+// Backend that gets faster each time the same key is retried, e.g. as its
+// cache warms: call k of a key is delayed by
+// `floor + (start - floor) / 2^(k-1)`, halving the distance to the floor on
+// every call
+pub struct WarmDelay {
+    keys: Arc<WarmKeys>,
+}
+
+impl WarmDelay {
+    pub fn new(keys: Arc<WarmKeys>) -> Self {
+        WarmDelay { keys }
+    }
+}
+
+#[async_trait]
+impl Fault for WarmDelay {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let (key, start_ms, floor_ms) = match parse_warm_delay(&ctx.headers) {
+            Ok(Some(warm_delay)) if ctx.config.chaos_enabled => warm_delay,
+            Ok(_) => return ControlFlow::Continue(()),
+            Err(details) => {
+                return ControlFlow::Break((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid warm delay headers",
+                        "details": details
+                    }))
+                ).into_response());
+            }
+        };
+
+        let call_index = self.keys.next_call(&key, ctx.config.warm_key_ttl);
+        let halvings = u32::try_from(call_index - 1).unwrap_or(u32::MAX);
+        let delay_ms = floor_ms + (start_ms - floor_ms).checked_shr(halvings).unwrap_or(0);
+
+        if delay_ms > 0 {
+            let delay_start = Instant::now();
+            let slept = ctx.sleep(Duration::from_millis(delay_ms)).await;
+            ctx.timing.record_delay(delay_start.elapsed());
+            slept?;
+            ctx.fault_log.record("warm_delay", delay_ms);
+        }
+
+        ctx.report.insert("warm_delay".to_string(), json!({
+            "key": key,
+            "call_index": call_index,
+            "start_ms": start_ms,
+            "floor_ms": floor_ms,
+            "delay_ms": delay_ms
+        }));
+        ControlFlow::Continue(())
+    }
+}
+
+// `X-Debounce-Key`: a newer request with the same key cancels this one while
+// it is still in an injected delay, answering it with `X-Debounce-Status`
+// (409 by default). Registered first, so requests are ordered by arrival.
+pub struct Debounce {
+    debouncer: Arc<Debouncer>,
+}
+
+impl Debounce {
+    pub fn new(debouncer: Arc<Debouncer>) -> Self {
+        Debounce { debouncer }
+    }
+}
+
+#[async_trait]
+impl Fault for Debounce {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let key = ctx.headers
+            .get("X-Debounce-Key")
+            .and_then(|h| h.to_str().ok())
+            .filter(|key| !key.is_empty() && ctx.config.chaos_enabled);
+        let Some(key) = key else {
+            return ControlFlow::Continue(());
+        };
+        let status = match ctx.headers.get("X-Debounce-Status") {
+            None => StatusCode::CONFLICT,
+            Some(h) => match h.to_str().map_err(|e| e.to_string()).and_then(config::parse_status_code) {
+                Ok(status) => status,
+                Err(details) => {
+                    return ControlFlow::Break((
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({
+                            "error": "Invalid X-Debounce-Status",
+                            "details": details
+                        }))
+                    ).into_response());
+                }
+            },
+        };
+
+        ctx.debounce = Some((self.debouncer.register(key), status));
+        ControlFlow::Continue(())
+    }
+}
+
+// `X-Return-Original: true` returns the upstream body unchanged, byte for
+// byte
+pub struct ReturnOriginal;
+
+#[async_trait]
+impl Fault for ReturnOriginal {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        ctx.return_original = ctx.headers
+            .get("X-Return-Original")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<bool>().ok())
+            .unwrap_or(false);
+        ControlFlow::Continue(())
+    }
+}
+
+// Canned 200 for /failure requests that were not failed, from
+// `X-Success-Body` or FAILURE_SUCCESS_BODY, so the endpoint works as a
+// 200/500 generator without an upstream. Registered last, after every fault
+// that can fail the request. Without either, the request is proxied.
+pub struct CannedSuccess;
+
+#[async_trait]
+impl Fault for CannedSuccess {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let body = match ctx.headers.get("X-Success-Body") {
+            Some(h) => String::from_utf8_lossy(h.as_bytes()).into_owned(),
+            None => match &ctx.config.failure_success_body {
+                Some(body) => body.clone(),
+                None => return ControlFlow::Continue(()),
+            },
+        };
+
+        let content_type = if serde_json::from_str::<Value>(&body).is_ok() {
+            "application/json"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        let mut response = body.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert("X-Canned-Response", HeaderValue::from_static("true"));
+        ControlFlow::Break(response)
+    }
+}
+
+// Simulated application-level rejection of bodies larger than
+// `X-Fail-Over-Bytes`. This is deterministic, so it is registered before the
+// probabilistic failure.
+pub struct FailOverBytes;
+
+#[async_trait]
+impl Fault for FailOverBytes {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let fail_over_bytes = ctx.headers
+            .get("X-Fail-Over-Bytes")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|_| ctx.config.chaos_enabled);
+        let Some(limit) = fail_over_bytes.filter(|&limit| ctx.body.len() > limit) else {
+            return ControlFlow::Continue(());
+        };
+
+        ctx.outcome.mark(Outcome::SimulatedFailure);
+        ctx.fault_log.record("failure", StatusCode::PAYLOAD_TOO_LARGE.as_u16());
+        ControlFlow::Break((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({
+                "error": "Payload too large",
+                "limit_bytes": limit,
+                "actual_bytes": ctx.body.len()
+            }))
+        ).into_response())
+    }
+}
+
+// Parse `X-Fail-Enter-Prob` and `X-Fail-Exit-Prob`, which must be sent
+// together. Returns None when neither was sent.
+fn parse_failure_burst(headers: &HeaderMap) -> Result<Option<(f64, f64)>, String> {
+    let probability = |name: &str| {
+        headers.get(name).map(|h| {
+            h.to_str()
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("{} must be a number between 0.0 and 1.0", name))
+        })
+    };
+    match (probability("X-Fail-Enter-Prob"), probability("X-Fail-Exit-Prob")) {
+        (None, None) => Ok(None),
+        (Some(enter), Some(exit)) => Ok(Some((enter?, exit?))),
+        _ => Err("X-Fail-Enter-Prob and X-Fail-Exit-Prob must be sent together".to_string()),
+    }
+}
+
+// Condition on a request header from `X-Fail-If-Header` (fail when it is
+// present) or `X-Fail-Unless-Header` (fail when it is absent)
+struct HeaderCondition {
+    name: HeaderName,
+    fail_if_present: bool,
+}
+
+impl HeaderCondition {
+    fn is_met(&self, headers: &HeaderMap) -> bool {
+        headers.contains_key(&self.name) == self.fail_if_present
+    }
+
+    // `if:<name>` or `unless:<name>`, for X-Proxy-Faults
+    fn describe(&self) -> String {
+        format!("{}:{}", if self.fail_if_present { "if" } else { "unless" }, self.name)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "header": self.name.as_str(),
+            "condition": if self.fail_if_present { "present" } else { "absent" }
+        })
+    }
+}
+
+// Parse `X-Fail-If-Header` and `X-Fail-Unless-Header`, each naming one header
+fn parse_header_conditions(headers: &HeaderMap) -> Result<Vec<HeaderCondition>, String> {
+    [("X-Fail-If-Header", true), ("X-Fail-Unless-Header", false)]
+        .into_iter()
+        .filter_map(|(control, fail_if_present)| {
+            headers.get(control).map(|h| {
+                h.to_str()
+                    .ok()
+                    .and_then(|s| HeaderName::from_bytes(s.trim().as_bytes()).ok())
+                    .map(|name| HeaderCondition { name, fail_if_present })
+                    .ok_or_else(|| format!("{} must be a header name", control))
+            })
+        })
+        .collect()
+}
+
+// Fail at the rate from `X-Failure-Rate`, FAILURE_RATE_BY_METHOD or
+// SUCCESS_PROBABILITY with the status from `X-Failure-Status-Code`. With
+// `requested_only`, only `X-Failure-Rate` can cause a failure.
+//
+// `X-Fail-If-Header` and `X-Fail-Unless-Header` fail every request whose
+// headers meet the condition, whatever the rate; requests that do not meet
+// it go on to the rate as usual. The condition that fired is reported as
+// `failure_condition`.
+//
+// `X-Fail-Enter-Prob` and `X-Fail-Exit-Prob` replace the rate with a shared
+// two-state chain, so failures come in bursts; the chain's state is reported
+// as `failure_burst` in both the success and failure bodies.
+pub struct RandomFailure {
+    requested_only: bool,
+    // Chain for bursty failures, shared with the admin reset
+    burst: Arc<FailureBurst>,
+}
+
+impl RandomFailure {
+    pub fn new(requested_only: bool, burst: Arc<FailureBurst>) -> Self {
+        RandomFailure { requested_only, burst }
+    }
+}
+
+#[async_trait]
+impl Fault for RandomFailure {
+    async fn apply(&self, ctx: &mut FaultContext) -> ControlFlow<Response> {
+        let burst = match parse_failure_burst(&ctx.headers) {
+            Ok(burst) => burst,
+            Err(details) => {
+                return ControlFlow::Break((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid failure burst headers",
+                        "details": details
+                    }))
+                ).into_response());
+            }
+        };
+
+        let conditions = match parse_header_conditions(&ctx.headers) {
+            Ok(conditions) => conditions,
+            Err(details) => {
+                return ControlFlow::Break((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid header condition",
+                        "details": details
+                    }))
+                ).into_response());
+            }
+        };
+
+        // Check for custom failure rate header, then the rate configured for
+        // the request method, then the global rate. Requests that only ask
+        // for a header condition never fail at random.
+        let (failure_rate, failure_rate_source) = match ctx.headers
+            .get("X-Failure-Rate")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<f64>().ok())
+        {
+            Some(rate) => (rate, "header"),
+            None if self.requested_only && burst.is_none() && conditions.is_empty() => {
+                return ControlFlow::Continue(());
+            }
+            None if self.requested_only && burst.is_none() => (0.0, "none"),
+            None => ctx.config.failure_rate_for(&ctx.method),
+        };
+
+        // Get custom failure status code from header, default to 500
+        let failure_status = ctx.headers
+            .get("X-Failure-Status-Code")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<u16>().ok())
+            .map(StatusCode::from_u16)
+            .and_then(Result::ok)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        // With chaos disabled every request is proxied, and the chain is left
+        // where it is
+        if !ctx.config.chaos_enabled {
+            return ControlFlow::Continue(());
+        }
+        let triggered = conditions.iter().find(|condition| condition.is_met(&ctx.headers));
+        let (should_fail, burst_report) = match burst {
+            _ if triggered.is_some() => (true, None),
+            Some((enter_probability, exit_probability)) => {
+                let (failing, run) = self.burst.step(enter_probability, exit_probability);
+                let report = json!({
+                    "state": if failing { "failing" } else { "healthy" },
+                    "run_length": run,
+                    "enter_prob": enter_probability,
+                    "exit_prob": exit_probability
+                });
+                ctx.report.insert("failure_burst".to_string(), report.clone());
+                (failing, Some(report))
+            }
+            None => (random::chance(failure_rate), None),
+        };
+        if !should_fail {
+            return ControlFlow::Continue(());
+        }
+
+        // Body precedence: X-Failure-Body header, then the configured template
+        // for the status code or class, then the default failure body
+        let custom_body = match ctx.headers.get("X-Failure-Body") {
+            Some(h) => match h.to_str().ok().and_then(|s| serde_json::from_str::<Value>(s).ok()) {
+                Some(body) => Some(body),
+                None => {
+                    return ControlFlow::Break((
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({
+                            "error": "Invalid X-Failure-Body",
+                            "details": "X-Failure-Body must be valid JSON"
+                        }))
+                    ).into_response());
+                }
+            },
+            None => ctx.config.failure_body_template(failure_status).cloned(),
+        };
+
+        ctx.outcome.mark(Outcome::SimulatedFailure);
+        ctx.fault_log.record("failure", failure_status.as_u16());
+        if let Some(condition) = triggered {
+            ctx.fault_log.record("failure_condition", condition.describe());
+        }
+        ControlFlow::Break((
+            failure_status,
+            Json(custom_body.unwrap_or_else(|| {
+                let mut body = json!({
+                    "error": "Simulated failure",
+                    "target_url": ctx.target_url,
+                    "status_code": failure_status.as_u16(),
+                    "request_body": ctx.payload
+                });
+                match (triggered, burst_report) {
+                    (Some(condition), _) => body["failure_condition"] = condition.to_json(),
+                    (None, Some(report)) => body["failure_burst"] = report,
+                    (None, None) => {
+                        body["failure_rate"] = json!(failure_rate);
+                        body["failure_rate_source"] = json!(failure_rate_source);
+                    }
+                }
+                body
+            }))
+        ).into_response())
+    }
+}
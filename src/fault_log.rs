@@ -0,0 +1,53 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+// Per-request record of the faults that fired. Inserted as a request
+// extension by `report_faults`; faults and fault middleware add an entry when
+// they change the request or response.
+#[derive(Clone, Default)]
+pub struct FaultLog(Arc<Mutex<BTreeMap<&'static str, String>>>);
+
+impl FaultLog {
+    pub fn record(&self, name: &'static str, value: impl Display) {
+        self.0.lock().unwrap().insert(name, value.to_string());
+    }
+
+    // Whether any fault has fired so far
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+// Summarize the faults that fired as `X-Proxy-Faults`, e.g.
+// `delay=500,random_delay=230,status_override=503`. Entries are sorted by
+// name so the same faults always produce the same header, and a request with
+// none gets `X-Proxy-Faults: none`.
+pub async fn report_faults(mut request: Request<Body>, next: Next) -> Response {
+    let log = FaultLog::default();
+    request.extensions_mut().insert(log.clone());
+
+    let mut response = next.run(request).await;
+
+    let entries = log.0.lock().unwrap();
+    let value = if entries.is_empty() {
+        "none".to_string()
+    } else {
+        entries
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response.headers_mut().insert("X-Proxy-Faults", value);
+    }
+
+    response
+}
@@ -0,0 +1,96 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, Extensions, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::io::ErrorKind;
+use std::ops::ControlFlow;
+use std::path::{Component, Path as FsPath};
+use tokio_util::io::ReaderStream;
+
+use crate::fault::FaultContext;
+use crate::SharedState;
+
+// A fixture name must be a single plain file name inside FIXTURES_DIR: no
+// separators, no `.`/`..` components and no hidden files
+fn is_valid_name(name: &str) -> bool {
+    let mut components = FsPath::new(name).components();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\', '\0'])
+}
+
+fn not_found(details: String) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "error": "Fixture not found",
+            "details": details
+        }))
+    ).into_response()
+}
+
+// Serve a file from FIXTURES_DIR, streamed from disk with a Content-Type
+// guessed from its extension. The fixture faults (delays and, when requested
+// with `X-Failure-Rate`, failures) run before the file is opened.
+pub async fn fixture_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    extensions: Extensions,
+) -> Response {
+    if !is_valid_name(&name) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid fixture name",
+                "details": "Fixture names must be a plain file name without path separators"
+            }))
+        ).into_response();
+    }
+
+    let target = format!("/fixture/{}", name);
+    let mut ctx = FaultContext::without_body(&state, method, headers, &extensions, target);
+    let Some(dir) = ctx.config.fixtures_dir.clone() else {
+        return not_found("FIXTURES_DIR is not set".to_string());
+    };
+    if let ControlFlow::Break(response) = state.fixture_faults.apply(&mut ctx).await {
+        return response;
+    }
+
+    let path = FsPath::new(&dir).join(&name);
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return not_found(format!("No fixture named {}", name)),
+        Err(e) => return read_error(e),
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return not_found(format!("No fixture named {}", name)),
+        Err(e) => return read_error(e),
+    };
+
+    let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+    let mut response = Body::from_stream(ReaderStream::new(file)).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type.as_ref()).unwrap(),
+    );
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(metadata.len()));
+    response
+}
+
+fn read_error(e: std::io::Error) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "error": "Failed to read fixture",
+            "details": e.to_string()
+        }))
+    ).into_response()
+}
@@ -0,0 +1,123 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::metrics::{self, Outcome, RequestOutcome};
+use crate::SharedState;
+
+// Length of each pause when GC_PAUSE_DURATION_MS is not set
+pub const DEFAULT_PAUSE_DURATION: Duration = Duration::from_millis(200);
+
+// What requests arriving during a simulated GC pause get (GC_PAUSE_MODE)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GcPauseMode {
+    // Held until the pause ends, then handled as usual
+    Hang,
+    // Answered with 503 straight away
+    Unavailable,
+}
+
+impl GcPauseMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "hang" => Ok(GcPauseMode::Hang),
+            "503" => Ok(GcPauseMode::Unavailable),
+            other => Err(format!("{} is not a GC pause mode, expected hang or 503", other)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GcPauseMode::Hang => "hang",
+            GcPauseMode::Unavailable => "503",
+        }
+    }
+}
+
+// Whether the listener is in a simulated GC pause. A watch channel, so held
+// requests wake as soon as the pause ends.
+pub struct GcPause {
+    paused: watch::Sender<bool>,
+}
+
+impl GcPause {
+    pub fn new() -> Self {
+        GcPause { paused: watch::channel(false).0 }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    fn set(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+}
+
+// With GC_PAUSE_INTERVAL_SECS set, pause every listener for
+// GC_PAUSE_DURATION_MS at the start of each interval, all at the same time
+pub async fn run(states: Vec<SharedState>, interval: Duration, duration: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes immediately; the first pause is one interval in
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        for state in &states {
+            state.gc_pause.set(true);
+        }
+        tracing::debug!("Simulated GC pause of {}ms started", duration.as_millis());
+        tokio::time::sleep(duration).await;
+        for state in &states {
+            state.gc_pause.set(false);
+        }
+    }
+}
+
+// During a simulated GC pause, hold requests until it ends (`hang`) or answer
+// them with 503 (`503`). Held requests report the time they waited in
+// `X-GC-Pause-Ms`. Probes, metrics and admin calls are not affected, and
+// nothing happens while chaos is disabled.
+pub async fn pause(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (mode, chaos_enabled) = {
+        let config = state.config.read().unwrap();
+        (config.gc_pause_mode, config.chaos_enabled && !state.fault_window_ended(&config))
+    };
+    if !chaos_enabled || !state.gc_pause.is_paused() || metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    match mode {
+        GcPauseMode::Unavailable => {
+            if let Some(outcome) = request.extensions().get::<RequestOutcome>() {
+                outcome.mark(Outcome::SimulatedFailure);
+            }
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": "Simulated GC pause",
+                    "details": "The proxy is in a simulated stop-the-world pause"
+                }))
+            ).into_response()
+        }
+        GcPauseMode::Hang => {
+            let start = Instant::now();
+            // The sender lives in the state, so waiting only ends with the pause
+            let _ = state.gc_pause.paused.subscribe().wait_for(|paused| !paused).await;
+            let waited = start.elapsed();
+            let mut response = next.run(request).await;
+            response.headers_mut().insert("X-GC-Pause-Ms", HeaderValue::from(waited.as_millis() as u64));
+            response
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, Method, Request, StatusCode},
+    response::Json,
+};
+use bytes::Bytes;
+use http_body_util::Full;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use crate::upstream_auth;
+use crate::{send_upstream, vhost, SharedState};
+
+// Upper bounds to keep a single request from turning into an unbounded job
+const MAX_COUNT: u64 = 10_000;
+const MAX_INTERVAL_MS: u64 = 60_000;
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<Option<u64>, String> {
+    headers
+        .get(name)
+        .map(|h| {
+            h.to_str()
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or_else(|| format!("{} must be a non-negative integer", name))
+        })
+        .transpose()
+}
+
+// Nearest-rank percentile over sorted latencies
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+// Replay the request body to the upstream `X-Loadgen-Count` times, starting
+// one request every `X-Loadgen-Interval-Ms`, and report aggregate results
+pub async fn loadgen_handler(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    let (count, interval_ms) = match (
+        header_u64(&headers, "X-Loadgen-Count"),
+        header_u64(&headers, "X-Loadgen-Interval-Ms"),
+    ) {
+        (Ok(count), Ok(interval_ms)) => (count.unwrap_or(1), interval_ms.unwrap_or(0)),
+        (Err(details), _) | (_, Err(details)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid load generation parameters",
+                    "details": details
+                }))
+            );
+        }
+    };
+
+    if count == 0 || count > MAX_COUNT || interval_ms > MAX_INTERVAL_MS {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid load generation parameters",
+                "details": format!(
+                    "X-Loadgen-Count must be between 1 and {} and X-Loadgen-Interval-Ms at most {}",
+                    MAX_COUNT, MAX_INTERVAL_MS
+                )
+            }))
+        );
+    }
+
+    let client = match state.client_for(&headers) {
+        Ok(client) => client,
+        Err(rejection) => return rejection,
+    };
+    let config = state.config.read().unwrap().clone();
+    let (target_url, _) = vhost::resolve_target(&config, &headers);
+    let token = match state.upstream_auth.token(&config).await {
+        Ok(token) => token,
+        Err(rejection) => return rejection,
+    };
+
+    let body_bytes = match serde_json::to_vec(&payload) {
+        Ok(bytes) => Bytes::from(bytes),
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Failed to serialize request body",
+                    "details": e.to_string()
+                }))
+            );
+        }
+    };
+
+    // Fire requests on a fixed schedule rather than waiting for each to
+    // complete, so a slow upstream does not lower the offered rate
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_millis(interval_ms.max(1)));
+    let mut tasks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if interval_ms > 0 {
+            interval.tick().await;
+        }
+
+        let client = client.clone();
+        let target_url = target_url.clone();
+        let body_bytes = body_bytes.clone();
+        let token = token.clone();
+        tasks.push(tokio::spawn(async move {
+            let builder = Request::builder()
+                .method(Method::POST)
+                .uri(&target_url)
+                .header("content-type", "application/json");
+            let req = upstream_auth::authorize(builder, token.as_deref())
+                .body(Full::new(body_bytes))
+                .map_err(|_| ())?;
+
+            let start = Instant::now();
+            let result = send_upstream(&client, req, &target_url).await;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+            match result {
+                Ok(resp) if resp.status.is_success() => Ok(latency_ms),
+                _ => Err(()),
+            }
+        }));
+    }
+
+    let mut latencies = Vec::with_capacity(count as usize);
+    let mut failures = 0u64;
+    for task in tasks {
+        match task.await {
+            Ok(Ok(latency_ms)) => latencies.push(latency_ms),
+            _ => failures += 1,
+        }
+    }
+    latencies.sort_by(|a, b| a.total_cmp(b));
+
+    (StatusCode::OK, Json(json!({
+        "status": "completed",
+        "target_url": target_url,
+        "requests": count,
+        "interval_ms": interval_ms,
+        "success": latencies.len(),
+        "failed": failures,
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "latency_ms": {
+            "min": latencies.first(),
+            "p50": percentile(&latencies, 50.0),
+            "p90": percentile(&latencies, 90.0),
+            "p99": percentile(&latencies, 99.0),
+            "max": latencies.last()
+        }
+    })))
+}
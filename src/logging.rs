@@ -0,0 +1,471 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::State,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use hyper::body::{Frame, SizeHint};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+use crate::request_id::RequestId;
+use crate::timing::Timing;
+use crate::SharedState;
+
+// How the log file configured via LOG_FILE is rotated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+    // Rotate once the file would exceed this many bytes
+    Size(u64),
+}
+
+impl LogRotation {
+    // Parse `never`, `hourly`, `daily` or `size:<bytes>` where the size may
+    // carry a KB, MB or GB suffix
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "never" => Ok(LogRotation::Never),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            other => {
+                let size = other
+                    .strip_prefix("size:")
+                    .ok_or_else(|| format!("unknown rotation {}", value))?;
+                let (digits, multiplier) = if let Some(n) = size.strip_suffix("gb") {
+                    (n, 1024 * 1024 * 1024)
+                } else if let Some(n) = size.strip_suffix("mb") {
+                    (n, 1024 * 1024)
+                } else if let Some(n) = size.strip_suffix("kb") {
+                    (n, 1024)
+                } else {
+                    (size, 1)
+                };
+                digits
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .map(|n| LogRotation::Size(n * multiplier))
+                    .ok_or_else(|| format!("invalid rotation size {}", size))
+            }
+        }
+    }
+}
+
+// Install the global tracing subscriber. Logs go to stdout unless LOG_FILE is
+// set, in which case they go through a non-blocking writer to the rotating
+// file so logging never stalls the request path. The returned guard flushes
+// buffered lines on drop and must be held for the life of the process.
+pub fn init(config: &Config) -> Option<WorkerGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let Some(log_file) = &config.log_file else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+        return None;
+    };
+
+    match open_writer(Path::new(log_file), config.log_rotation) {
+        Ok(writer) => {
+            let (writer, guard) = tracing_appender::non_blocking(writer);
+            tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .with_writer(writer)
+                .with_ansi(false)
+                .init();
+            Some(guard)
+        }
+        Err(e) => {
+            tracing_subscriber::fmt().with_env_filter(filter).init();
+            tracing::warn!("Cannot write to LOG_FILE {}, logging to stdout instead: {}", log_file, e);
+            None
+        }
+    }
+}
+
+fn open_writer(path: &Path, rotation: LogRotation) -> io::Result<Box<dyn Write + Send>> {
+    let directory = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "LOG_FILE has no file name"))?;
+
+    let rotation = match rotation {
+        LogRotation::Size(max_bytes) => {
+            return Ok(Box::new(SizeRotatingWriter::open(path.to_path_buf(), max_bytes)?));
+        }
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name.to_string_lossy())
+        .build(directory)
+        .map_err(io::Error::other)?;
+    Ok(Box::new(appender))
+}
+
+// File writer that moves the file aside to `<path>.1` (replacing any previous
+// backup) once it reaches the size limit, then starts a fresh file
+struct SizeRotatingWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    written: u64,
+    file: File,
+}
+
+impl SizeRotatingWriter {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(SizeRotatingWriter { path, max_bytes, written, file })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(".1");
+        fs::rename(&self.path, backup)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Collects at most `limit` bytes. Writes past the limit fail, which stops a
+// decoder from inflating the rest of a large or malicious body.
+struct CappedBuffer {
+    bytes: Vec<u8>,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl CappedBuffer {
+    fn new(limit: usize) -> Self {
+        CappedBuffer { bytes: Vec::new(), limit, overflowed: false }
+    }
+}
+
+impl Write for CappedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = self.limit - self.bytes.len();
+        if room == 0 && !buf.is_empty() {
+            self.overflowed = true;
+            return Err(io::Error::other("LOG_BODY_MAX_LEN reached"));
+        }
+        let n = room.min(buf.len());
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Decompresses a body's content coding for the log
+enum Decoder {
+    Gzip(GzDecoder<CappedBuffer>),
+    // HTTP's `deflate` is zlib-wrapped deflate
+    Deflate(ZlibDecoder<CappedBuffer>),
+}
+
+impl Decoder {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.write_all(buf),
+            Decoder::Deflate(decoder) => decoder.write_all(buf),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.try_finish(),
+            Decoder::Deflate(decoder) => decoder.try_finish(),
+        }
+    }
+
+    fn output(&self) -> &CappedBuffer {
+        match self {
+            Decoder::Gzip(decoder) => decoder.get_ref(),
+            Decoder::Deflate(decoder) => decoder.get_ref(),
+        }
+    }
+}
+
+// The start of a body as logged with LOG_BODY_MAX_LEN. Gzip and deflate
+// bodies are decompressed as they stream past, and the limit applies to the
+// decompressed bytes. Only the logged copy is decompressed; the body itself
+// is passed on unchanged.
+struct BodyCapture {
+    raw: CappedBuffer,
+    raw_len: usize,
+    encoding: String,
+    decoder: Option<Decoder>,
+}
+
+impl BodyCapture {
+    fn new(headers: &HeaderMap, limit: usize) -> Self {
+        let encoding = headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let decoder = match encoding.as_str() {
+            "gzip" | "x-gzip" => Some(Decoder::Gzip(GzDecoder::new(CappedBuffer::new(limit)))),
+            "deflate" => Some(Decoder::Deflate(ZlibDecoder::new(CappedBuffer::new(limit)))),
+            _ => None,
+        };
+        BodyCapture { raw: CappedBuffer::new(limit), raw_len: 0, encoding, decoder }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.raw_len += chunk.len();
+        let room = self.raw.limit - self.raw.bytes.len();
+        self.raw.bytes.extend_from_slice(&chunk[..room.min(chunk.len())]);
+
+        if let Some(decoder) = &mut self.decoder {
+            if !decoder.output().overflowed && decoder.write_all(chunk).is_err() && !decoder.output().overflowed {
+                // Not actually compressed as labelled: log the raw bytes
+                self.decoder = None;
+            }
+        }
+    }
+
+    // The captured text, quoted, with the coding it was decompressed from
+    // and whether it was cut at the limit. A compressed body that ends early
+    // still shows what could be decompressed.
+    fn describe(mut self) -> String {
+        if let Some(decoder) = &mut self.decoder {
+            if !decoder.output().overflowed && decoder.finish().is_err() && decoder.output().bytes.is_empty() {
+                self.decoder = None;
+            }
+        }
+        let (coding, text, truncated) = match &self.decoder {
+            Some(decoder) => (
+                Some(&self.encoding),
+                String::from_utf8_lossy(&decoder.output().bytes),
+                decoder.output().overflowed,
+            ),
+            None => (None, String::from_utf8_lossy(&self.raw.bytes), self.raw_len > self.raw.limit),
+        };
+        let mut description = format!("{:?}", text);
+        if let Some(coding) = coding {
+            description = format!("{} decoded={}", description, coding);
+        }
+        if truncated {
+            description.push_str(" truncated");
+        }
+        description
+    }
+}
+
+// Passes a body through unchanged while capturing its start, and logs the
+// capture once the body has been read to the end or dropped
+struct LoggedBody {
+    inner: Body,
+    kind: &'static str,
+    request_id: RequestId,
+    capture: Option<BodyCapture>,
+}
+
+impl LoggedBody {
+    fn wrap(inner: Body, kind: &'static str, request_id: RequestId, capture: BodyCapture) -> Body {
+        Body::new(LoggedBody { inner, kind, request_id, capture: Some(capture) })
+    }
+
+    fn log(&mut self) {
+        let Some(capture) = self.capture.take() else { return };
+        if capture.raw_len > 0 {
+            tracing::info!(
+                target: "access",
+                "{} body id={} {}",
+                self.kind, self.request_id, capture.describe()
+            );
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(capture)) = (frame.data_ref(), &mut self.capture) {
+                    capture.push(data);
+                }
+            }
+            None => self.log(),
+            Some(Err(_)) => {}
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.log();
+    }
+}
+
+// Log one line per request with method, path, status, latency and request
+// id. With SLOW_REQUEST_THRESHOLD_MS set, only slower requests are logged at
+// `info`, as warnings with the delay and upstream breakdown; the rest drop to
+// `debug`. With LOG_BODY_MAX_LEN set, the start of the request and response
+// bodies is logged too, once each body has been read.
+pub async fn access_log(
+    State(state): State<SharedState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let request_id = request.extensions().get::<RequestId>().cloned().unwrap_or_default();
+    let timing = Timing::default();
+    request.extensions_mut().insert(timing.clone());
+    let start = Instant::now();
+    let log_body_max_len = state.config.read().unwrap().log_body_max_len;
+    if let Some(limit) = log_body_max_len {
+        let capture = BodyCapture::new(request.headers(), limit);
+        let id = request_id.clone();
+        request = request.map(|body| LoggedBody::wrap(body, "request", id, capture));
+    }
+
+    let mut response = next.run(request).await;
+    if let Some(limit) = log_body_max_len {
+        let capture = BodyCapture::new(response.headers(), limit);
+        let id = request_id.clone();
+        response = response.map(|body| LoggedBody::wrap(body, "response", id, capture));
+    }
+
+    let elapsed = start.elapsed();
+    let status = response.status().as_u16();
+    match state.config.read().unwrap().slow_request_threshold {
+        None => tracing::info!(
+            target: "access",
+            "{} {} {} {}ms id={}",
+            method, path, status, elapsed.as_millis(), request_id
+        ),
+        Some(threshold) if elapsed > threshold => tracing::warn!(
+            target: "access",
+            "slow request: {} {} {} {}ms {} id={}",
+            method, path, status, elapsed.as_millis(), timing.describe(), request_id
+        ),
+        Some(_) => tracing::debug!(
+            target: "access",
+            "{} {} {} {}ms id={}",
+            method, path, status, elapsed.as_millis(), request_id
+        ),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    fn encoded(encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        headers
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn captured(headers: &HeaderMap, limit: usize, chunks: &[&[u8]]) -> String {
+        let mut capture = BodyCapture::new(headers, limit);
+        for chunk in chunks {
+            capture.push(chunk);
+        }
+        capture.describe()
+    }
+
+    #[test]
+    fn gzip_body_is_logged_decompressed() {
+        let body = gzip(br#"{"ok":true}"#);
+        let (first, rest) = body.split_at(5);
+        assert_eq!(captured(&encoded("gzip"), 100, &[first, rest]), r#""{\"ok\":true}" decoded=gzip"#);
+    }
+
+    #[test]
+    fn deflate_body_is_logged_decompressed() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let body = encoder.finish().unwrap();
+        assert_eq!(captured(&encoded("deflate"), 100, &[&body]), r#""hello" decoded=deflate"#);
+    }
+
+    #[test]
+    fn limit_applies_to_decompressed_size() {
+        let body = gzip(&[b'a'; 100_000]);
+        assert!(body.len() < 1000);
+        assert_eq!(captured(&encoded("gzip"), 8, &[&body]), r#""aaaaaaaa" decoded=gzip truncated"#);
+    }
+
+    #[test]
+    fn mislabelled_body_is_logged_raw() {
+        assert_eq!(captured(&encoded("gzip"), 100, &[b"plain text"]), r#""plain text""#);
+        assert_eq!(captured(&HeaderMap::new(), 5, &[b"plain", b" text"]), r#""plain" truncated"#);
+    }
+
+    #[tokio::test]
+    async fn logged_body_is_forwarded_unchanged() {
+        let body = gzip(b"payload");
+        let logged = LoggedBody::wrap(
+            Body::from(body.clone()),
+            "request",
+            RequestId::default(),
+            BodyCapture::new(&encoded("gzip"), 3),
+        );
+        assert_eq!(axum::body::to_bytes(logged, usize::MAX).await.unwrap(), body);
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::SharedState;
+
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+const MAX_TIMEOUT_MS: u64 = 300_000;
+
+// Events that have not reached every waiting poller yet. A poller that falls
+// this far behind skips ahead to the latest event.
+const EVENT_CAPACITY: usize = 16;
+
+// Events fired through `POST /longpoll/fire`, delivered to every poller
+// waiting at the time
+pub struct LongPoll {
+    events: broadcast::Sender<Value>,
+}
+
+impl LongPoll {
+    pub fn new() -> Self {
+        LongPoll { events: broadcast::channel(EVENT_CAPACITY).0 }
+    }
+
+    // Number of pollers currently waiting
+    pub fn waiting(&self) -> usize {
+        self.events.receiver_count()
+    }
+}
+
+// Hold the request until an event is fired or `timeout_ms` elapses. An event
+// is returned with 200, a timeout with 204 so the client polls again. Only
+// events fired while the request is waiting are delivered.
+pub async fn longpoll_handler(
+    State(state): State<SharedState>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let timeout_ms = match query.get("timeout_ms") {
+        None => DEFAULT_TIMEOUT_MS,
+        Some(value) => match value.parse::<u64>().ok().filter(|ms| (1..=MAX_TIMEOUT_MS).contains(ms)) {
+            Some(ms) => ms,
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid timeout_ms",
+                        "details": format!("timeout_ms must be an integer between 1 and {}", MAX_TIMEOUT_MS)
+                    }))
+                ).into_response();
+            }
+        },
+    };
+
+    let start = Instant::now();
+    let mut events = state.longpoll.events.subscribe();
+    let timeout = tokio::time::sleep(Duration::from_millis(timeout_ms));
+    tokio::pin!(timeout);
+    loop {
+        tokio::select! {
+            _ = &mut timeout => return StatusCode::NO_CONTENT.into_response(),
+            event = events.recv() => match event {
+                Ok(event) => {
+                    return Json(json!({
+                        "event": event,
+                        "waited_ms": start.elapsed().as_millis() as u64
+                    })).into_response();
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return StatusCode::NO_CONTENT.into_response(),
+            },
+        }
+    }
+}
+
+// Fire an event, waking every waiting poller. The optional JSON body is the
+// event returned to them.
+pub async fn fire_handler(State(state): State<SharedState>, body: Bytes) -> (StatusCode, Json<Value>) {
+    let event = if body.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(event) => event,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid event",
+                        "details": format!("Request body must be empty or JSON: {}", e)
+                    }))
+                );
+            }
+        }
+    };
+
+    // Sending only fails when nobody is waiting
+    let delivered = state.longpoll.events.send(event).unwrap_or(0);
+    (StatusCode::OK, Json(json!({
+        "status": "fired",
+        "delivered": delivered
+    })))
+}
@@ -1,173 +1,759 @@
+// The config dump in `Config::to_json` is one large `json!` literal
+#![recursion_limit = "256"]
+
 use axum::{
-    routing::{post, get},
+    routing::{any, post, get},
     Router,
-    http::{StatusCode, HeaderMap, Method, Request},
-    response::Json,
+    http::{StatusCode, Extensions, HeaderMap, Method, Request},
+    response::{IntoResponse, Json, Response},
     extract::State,
+    handler::Handler,
 };
-use rand::Rng;
+use futures_util::FutureExt;
+use hyper::ext::ReasonPhrase;
 use serde_json::json;
-use std::sync::Arc;
-use hyper_util::client::legacy::Client;
-use hyper_tls::HttpsConnector;
-use hyper_util::rt::TokioExecutor;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use http_body_util::{Full, BodyExt};
-use dotenv::dotenv;
 use serde_json::Value;
-use chrono;
-use std::time::Duration;
-use std::env;
+use std::time::{Duration, Instant};
 use bytes::Bytes;
+use std::collections::HashMap;
+
+mod ab;
+mod accel;
+mod adaptive;
+mod admin;
+mod armed_failure;
+mod body_hash;
+mod capture;
+mod compression;
+mod config;
+mod connector;
+mod control_file;
+mod debounce;
+mod echo;
+mod failure_burst;
+mod fanout;
+mod fault;
+mod fault_log;
+mod fixture;
+mod gc_pause;
+mod loadgen;
+mod logging;
+mod longpoll;
+mod maintenance;
+mod metrics;
+mod paginate;
+mod middleware;
+mod proxy;
+mod random;
+mod request_id;
+mod retry;
+mod rotation;
+mod sequence;
+mod schedule;
+mod server;
+mod smuggle;
+mod stale_cache;
+mod synthetic;
+mod timing;
+mod trailers;
+mod upload_pacing;
+mod upstream_auth;
+mod vhost;
+mod warm_keys;
+mod wrapper;
+
+use adaptive::InFlight;
+use armed_failure::ArmedFailure;
+use config::Config;
+use connector::{HttpClient, StreamingClient};
+use debounce::Debouncer;
+use failure_burst::FailureBurst;
+use fault::{FaultContext, FaultPipeline};
+use gc_pause::GcPause;
+use longpoll::LongPoll;
+use maintenance::Maintenance;
+use metrics::{RateRecorder, Stats};
+use rotation::BodyRotation;
+use sequence::Sequence;
+use stale_cache::StaleCache;
+use synthetic::SyntheticMonitor;
+use upstream_auth::UpstreamAuth;
+use warm_keys::WarmKeys;
 
-// Configuration struct to hold environment variables
-#[derive(Clone)]
-struct Config {
-    target_url: String,
-    success_probability: f64,
+// State shared by all handlers. The config sits behind a lock so the admin
+// API can adjust it at runtime.
+struct AppState {
+    client: HttpClient,
+    streaming_client: StreamingClient,
+    config: RwLock<Config>,
+    rates: RateRecorder,
+    stats: Stats,
+    // Clients for per-request overrides of the TLS server name
+    // (`X-Upstream-Sni`) and protocol (`X-Upstream-Http-Version`), keyed by
+    // server name override and whether the client speaks HTTP/2, with when
+    // each was last used. At most MAX_OVERRIDE_CLIENTS are kept.
+    override_clients: Mutex<HashMap<OverrideKey, (HttpClient, Instant)>>,
+    // Round-robin position for each path in ROTATING_BODIES
+    rotation: BodyRotation,
+    // Position in SEQUENCE_RESPONSES
+    sequence: Sequence,
+    // Faults applied by /delay and /failure, in order
+    delay_faults: FaultPipeline,
+    failure_faults: FaultPipeline,
+    // Faults applied by /fixture before the file is served
+    fixture_faults: FaultPipeline,
+    // Faults applied by /paginate before each page is served
+    paginate_faults: FaultPipeline,
+    // Faults applied by /echo-transform before the body is echoed
+    echo_faults: FaultPipeline,
+    // Request ordinal of the global warmup curve (`X-Warmup-Scope: global`)
+    warmup_requests: Arc<AtomicU64>,
+    // Calls seen per `X-Warm-Key`
+    warm_keys: Arc<WarmKeys>,
+    // Requests in flight per `X-Debounce-Key`
+    debouncer: Arc<Debouncer>,
+    // State of the bursty failure chain (`X-Fail-Enter-Prob`)
+    failure_burst: Arc<FailureBurst>,
+    // Results of the SYNTHETIC_INTERVAL_MS probes
+    synthetic: SyntheticMonitor,
+    // Requests being handled, for the adaptive delay
+    in_flight: Arc<InFlight>,
+    // Events for requests held by /longpoll
+    longpoll: LongPoll,
+    // Maintenance response set through the admin API
+    maintenance: Maintenance,
+    // One-shot failure armed through the admin API
+    armed_failure: ArmedFailure,
+    // Whether a GC_PAUSE_INTERVAL_SECS pause is under way
+    gc_pause: GcPause,
+    // Bearer token sent upstream, cached until close to expiry
+    upstream_auth: Arc<UpstreamAuth>,
+    // Last good response per path, served on upstream failure with
+    // SERVE_STALE_ON_ERROR
+    stale_cache: StaleCache,
+    // Cleared by the first request after startup, which pays
+    // COLD_START_DELAY_MS
+    cold_start_pending: AtomicBool,
+    // Set once shutdown begins so /readyz reports not-ready while in-flight
+    // requests drain
+    draining: AtomicBool,
+    // When the listener started, the start of FAULT_ACTIVE_WINDOW_SECS
+    started: Instant,
 }
 
-impl Config {
-    fn from_env() -> Self {
-        dotenv().ok();
-        
-        let target_url = env::var("TARGET_URL")
-            .expect("TARGET_URL must be set");
-            
-        let success_probability = env::var("SUCCESS_PROBABILITY")
-            .unwrap_or_else(|_| "0.8".to_string())
-            .parse::<f64>()
-            .expect("SUCCESS_PROBABILITY must be a float between 0.0 and 1.0");
-            
-        Config {
-            target_url,
-            success_probability,
+impl AppState {
+    // Whether FAULT_ACTIVE_WINDOW_SECS is set and has passed
+    fn fault_window_ended(&self, config: &Config) -> bool {
+        config.fault_active_window.is_some_and(|window| self.started.elapsed() >= window)
+    }
+
+    // Whether faults are injected: chaos is enabled and the fault window, if
+    // any, has not ended. The window is checked here rather than by turning
+    // chaos off, so SIGUSR1 or the admin API cannot turn faults back on
+    // after it.
+    fn chaos_active(&self) -> bool {
+        let config = self.config.read().unwrap();
+        config.chaos_enabled && !self.fault_window_ended(&config)
+    }
+
+    // Client to use for a request, honoring `X-Upstream-Sni` and
+    // `X-Upstream-Http-Version` overrides
+    fn client_for(&self, headers: &HeaderMap) -> Result<HttpClient, (StatusCode, Json<Value>)> {
+        let (sni, http2) = client_overrides(headers)?;
+        if sni.is_none() && !http2 {
+            return Ok(self.client.clone());
         }
+
+        let mut clients = self.override_clients.lock().unwrap();
+        let key = (sni, http2);
+        if let Some((client, last_used)) = clients.get_mut(&key) {
+            *last_used = Instant::now();
+            return Ok(client.clone());
+        }
+
+        // The keys come from request headers, so drop the least recently
+        // used client rather than growing without bound
+        if clients.len() >= MAX_OVERRIDE_CLIENTS {
+            let oldest = clients
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                clients.remove(&oldest);
+            }
+        }
+
+        let client = {
+            let config = self.config.read().unwrap();
+            let sni = key.0.clone().or_else(|| config.upstream_sni.clone());
+            connector::build_client(&config, sni, http2)
+        };
+        clients.insert(key, (client.clone(), Instant::now()));
+        Ok(client)
     }
+
+    // Client for a streamed request body. Streaming is opt-in and rare, so
+    // clients for overrides are built per request rather than cached.
+    fn streaming_client_for(&self, headers: &HeaderMap) -> Result<StreamingClient, (StatusCode, Json<Value>)> {
+        let (sni, http2) = client_overrides(headers)?;
+        if sni.is_none() && !http2 {
+            return Ok(self.streaming_client.clone());
+        }
+
+        let config = self.config.read().unwrap();
+        let sni = sni.or_else(|| config.upstream_sni.clone());
+        Ok(connector::build_client(&config, sni, http2))
+    }
+}
+
+// Parse the `X-Upstream-Sni` and `X-Upstream-Http-Version` overrides: the
+// TLS server name and whether to speak HTTP/2
+fn client_overrides(headers: &HeaderMap) -> Result<(Option<String>, bool), (StatusCode, Json<Value>)> {
+    let sni = match headers.get("X-Upstream-Sni").map(|h| h.to_str()) {
+        None => None,
+        Some(Ok(name)) if connector::is_dns_name(name) => Some(name.to_ascii_lowercase()),
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Upstream-Sni",
+                    "details": "X-Upstream-Sni must be a DNS host name"
+                }))
+            ));
+        }
+    };
+    let http2 = match headers.get("X-Upstream-Http-Version").map(|h| h.to_str()) {
+        None => false,
+        Some(Ok("1.1")) => false,
+        Some(Ok("2")) => true,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Upstream-Http-Version",
+                    "details": "X-Upstream-Http-Version must be 1.1 or 2"
+                }))
+            ));
+        }
+    };
+    Ok((sni, http2))
 }
 
-// Shared HTTP client for proxying requests
-type HttpClient = Client<HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, Full<Bytes>>;
-type SharedState = Arc<(HttpClient, Config)>;
+// TLS server name override and whether the client speaks HTTP/2
+type OverrideKey = (Option<String>, bool);
+
+// Most clients kept for `X-Upstream-Sni` and `X-Upstream-Http-Version`
+// overrides, each with its own connection pool
+const MAX_OVERRIDE_CLIENTS: usize = 32;
+
+type SharedState = Arc<AppState>;
 
 #[tokio::main]
 async fn main() {
     let config = Config::from_env();
+    let _log_guard = logging::init(&config);
     
-    // Create HTTPS connector
-    let https = HttpsConnector::new();
-    let client = Client::builder(TokioExecutor::new())
-        .build::<_, Full<Bytes>>(https);
-    
-    // Create shared state
-    let state = Arc::new((client, config));
+    // Load TLS termination settings before config moves into shared state
+    let tls = server::TlsSettings::from_config(&config);
 
-    let app = Router::new()
+    // Create the upstream client, bounding TCP connect + TLS handshake time
+    let client = connector::build_client(&config, config.upstream_sni.clone(), false);
+    let streaming_client = connector::build_client(&config, config.upstream_sni.clone(), false);
+
+    // One app per listener, each with its own state and fault profile but
+    // sharing the upstream clients
+    let mut states = Vec::new();
+    let mut apps = Vec::new();
+    for (port, profile) in &config.listeners {
+        let profile_config = config.for_profile(profile).expect("profiles are validated at startup");
+        let state = build_state(profile_config, client.clone(), streaming_client.clone());
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", *port)).await.unwrap();
+        tracing::info!("Listening on: {} (profile: {})", listener.local_addr().unwrap(), profile);
+        states.push(state.clone());
+        tokio::spawn(synthetic::run(state.clone()));
+        apps.push((listener, build_app(state)));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(control_signals(states.clone()));
+    if let Some(window) = config.fault_active_window {
+        tokio::spawn(log_fault_window_end(window));
+    }
+    if let Some(interval) = config.gc_pause_interval {
+        tokio::spawn(gc_pause::run(states.clone(), interval, config.gc_pause_duration));
+    }
+    if let Some(path) = config.control_file.clone() {
+        tokio::spawn(control_file::watch(path, states.clone()));
+    }
+
+    let shutdown = shutdown_signal(states).shared();
+    futures_util::future::join_all(apps.into_iter().map(|(listener, app)| {
+        server::serve(listener, app, tls.clone(), shutdown.clone())
+    })).await;
+}
+
+// Shared state for one listener
+fn build_state(config: Config, client: HttpClient, streaming_client: StreamingClient) -> SharedState {
+    let rotation = BodyRotation::new(&config.rotating_bodies);
+    let warmup_requests = Arc::new(AtomicU64::new(0));
+    let warm_keys = Arc::new(WarmKeys::new());
+    let failure_burst = Arc::new(FailureBurst::new());
+    let debouncer = Arc::new(Debouncer::new());
+    let in_flight = Arc::new(InFlight::new());
+    let upstream_auth = Arc::new(UpstreamAuth::new(client.clone()));
+    Arc::new(AppState {
+        client,
+        streaming_client,
+        config: RwLock::new(config),
+        rates: RateRecorder::new(),
+        stats: Stats::new(),
+        override_clients: Mutex::new(HashMap::new()),
+        rotation,
+        sequence: Sequence::new(),
+        delay_faults: FaultPipeline::new()
+            .register(fault::Debounce::new(debouncer.clone()))
+            .register(fault::ForceStatus)
+            .register(fault::Delay)
+            .register(fault::Warmup::new(warmup_requests.clone()))
+            .register(fault::WarmDelay::new(warm_keys.clone()))
+            .register(adaptive::AdaptiveDelay::new(in_flight.clone())),
+        failure_faults: FaultPipeline::new()
+            .register(fault::ReturnOriginal)
+            .register(fault::FailOverBytes)
+            .register(fault::RandomFailure::new(false, failure_burst.clone()))
+            .register(fault::CannedSuccess),
+        fixture_faults: FaultPipeline::new()
+            .register(fault::Delay)
+            .register(fault::RandomFailure::new(true, failure_burst.clone())),
+        paginate_faults: FaultPipeline::new()
+            .register(fault::Delay),
+        echo_faults: FaultPipeline::new()
+            .register(fault::Delay)
+            .register(fault::RandomFailure::new(true, failure_burst.clone())),
+        warmup_requests,
+        warm_keys,
+        debouncer,
+        failure_burst,
+        synthetic: SyntheticMonitor::new(),
+        in_flight,
+        longpoll: LongPoll::new(),
+        maintenance: Maintenance::new(),
+        armed_failure: ArmedFailure::new(),
+        gc_pause: GcPause::new(),
+        upstream_auth,
+        stale_cache: StaleCache::new(),
+        cold_start_pending: AtomicBool::new(true),
+        draining: AtomicBool::new(false),
+        started: Instant::now(),
+    })
+}
+
+// State for a listener configured from the environment, for tests. A target
+// URL is set first, since `Config::from_env` requires one.
+#[cfg(test)]
+fn test_state(adjust: impl FnOnce(&mut Config)) -> SharedState {
+    static TARGET_URL: std::sync::Once = std::sync::Once::new();
+    TARGET_URL.call_once(|| {
+        if std::env::var_os("TARGET_URL").is_none() {
+            std::env::set_var("TARGET_URL", "http://127.0.0.1:9/");
+        }
+    });
+
+    let mut config = Config::from_env();
+    adjust(&mut config);
+    let client = connector::build_client(&config, None, false);
+    let streaming_client = connector::build_client(&config, None, false);
+    build_state(config, client, streaming_client)
+}
+
+// Routes and middleware served on every listener
+fn build_app(state: SharedState) -> Router {
+    // Fault-injecting endpoints, wrapped by the response-mutating middleware
+    let fault_routes = Router::new()
         .route("/delay", post(delay_handler))
         .route("/failure", post(failure_handler))
+        .route("/fixture/:name", get(fixture::fixture_handler))
+        .route("/paginate", get(paginate::paginate_handler))
+        .route("/echo-transform", post(echo::echo_transform_handler))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::cpu_burn))
+        .layer(axum::middleware::from_fn(middleware::drop_fields))
+        .layer(axum::middleware::from_fn(middleware::simulate_etag))
+        .layer(axum::middleware::from_fn(middleware::random_padding))
+        .layer(axum::middleware::from_fn(middleware::amplify_response))
+        .layer(axum::middleware::from_fn(middleware::duplicate_json_key))
+        .layer(axum::middleware::from_fn(middleware::append_trailing_garbage))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::fail_on_write))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::backpressure))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::intermittent_hang))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), trailers::response_trailers))
+        .layer(axum::middleware::from_fn(middleware::retry_after))
+        .layer(axum::middleware::from_fn(middleware::date_skew))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::randomize_ordering))
+        .layer(axum::middleware::from_fn(middleware::duplicate_header))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::inflate_response_header))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), armed_failure::fire))
+        .layer(axum::middleware::from_fn(fault_log::report_faults))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), maintenance::short_circuit))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), timing::record_timing))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), metrics::record_rate))
+        // Outermost, so headers added by the layers above are covered too
+        .layer(axum::middleware::from_fn(middleware::randomize_header_case));
+
+    Router::new()
+        .merge(fault_routes)
         .route("/healthcheck", get(healthcheck))
-        .with_state(state);
-    
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Listening on: {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics::metrics))
+        .route("/stats", get(metrics::stats))
+        .route("/stats/reset", post(metrics::reset_stats))
+        .route("/synthetic", get(synthetic::synthetic))
+        .route("/loadgen", post(loadgen::loadgen_handler))
+        .route("/fanout", post(fanout::fanout_handler))
+        .route("/sequence", any(sequence::sequence_handler))
+        .route("/reset-counters", post(sequence::reset_counters_handler))
+        .route("/ab", any(ab::ab_handler))
+        .route("/longpoll", get(longpoll::longpoll_handler))
+        .route("/longpoll/fire", post(longpoll::fire_handler))
+        .fallback(proxy::proxy_handler.layer(axum::middleware::from_fn(middleware::simulate_etag)))
+        .route("/admin/config", get(admin::get_config).patch(admin::patch_config))
+        .route("/admin/state", get(admin::get_state))
+        .route("/admin/reset", post(admin::reset))
+        .route("/admin/maintenance", post(admin::set_maintenance))
+        .route("/admin/arm-failure", post(admin::arm_failure))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), gc_pause::pause))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::cold_start))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::fault_window))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::read_request_body))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), upload_pacing::upload_pacing))
+        // Outside the body readers, so nothing has read the body yet
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::expect_continue))
+        // Outside every layer that injects a wait
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::delay_budget))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::header_limits))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), adaptive::track_in_flight))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), metrics::record_stats))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::handled_by))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::strip_response_headers))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), logging::access_log))
+        .layer(axum::middleware::from_fn(request_id::assign_request_id))
+        // After every other layer has built the response
+        .layer(axum::middleware::from_fn(middleware::drop_response_headers))
+        .with_state(state)
+}
+
+// Resolve on Ctrl+C or SIGTERM. Every listener's draining flag is flipped
+// first, then the configured drain delay gives load balancers time to observe
+// /readyz failing before the listeners stop accepting.
+async fn shutdown_signal(states: Vec<SharedState>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    for state in &states {
+        state.draining.store(true, Ordering::SeqCst);
+    }
+    let drain_delay = states
+        .iter()
+        .map(|state| state.config.read().unwrap().shutdown_drain_delay)
+        .max()
+        .unwrap_or_default();
+    tracing::info!("Shutdown signal received, draining for {}ms", drain_delay.as_millis());
+    tokio::time::sleep(drain_delay).await;
+}
+
+// Out-of-band control for when the admin API cannot be reached: SIGUSR1
+// toggles chaos on every listener (off if any listener has it on, otherwise
+// on) and SIGUSR2 resets every listener's request statistics
+#[cfg(unix)]
+async fn control_signals(states: Vec<SharedState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut toggle_chaos = signal(SignalKind::user_defined1()).expect("Failed to listen for SIGUSR1");
+    let mut reset_stats = signal(SignalKind::user_defined2()).expect("Failed to listen for SIGUSR2");
+    loop {
+        tokio::select! {
+            _ = toggle_chaos.recv() => {
+                let enabled = !states.iter().any(|state| state.config.read().unwrap().chaos_enabled);
+                for state in &states {
+                    state.config.write().unwrap().chaos_enabled = enabled;
+                }
+                tracing::warn!("SIGUSR1 received, chaos {}", if enabled { "enabled" } else { "disabled" });
+            }
+            _ = reset_stats.recv() => {
+                for state in &states {
+                    state.stats.reset();
+                }
+                tracing::warn!("SIGUSR2 received, request statistics reset");
+            }
+        }
+    }
+}
+
+// Log the end of the FAULT_ACTIVE_WINDOW_SECS window. Faults stop on their
+// own, see `AppState::chaos_active`.
+async fn log_fault_window_end(window: Duration) {
+    tokio::time::sleep(window).await;
+    tracing::warn!("Fault window of {}s ended, faults are no longer injected", window.as_secs());
 }
 
 #[axum::debug_handler]
 async fn delay_handler(
     State(state): State<SharedState>,
+    method: Method,
     headers: HeaderMap,
-    Json(payload): Json<Value>,
-) -> (StatusCode, Json<Value>) {
-    let (client, config) = &*state;
-    
-    // Parse delay configuration from headers
-    let constant_delay_ms: Option<u64> = headers
-        .get("X-Constant-Delay-Ms")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse().ok());
-
-    let max_random_delay_ms: Option<u64> = headers
-        .get("X-Max-Random-Delay-Ms")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse().ok());
+    extensions: Extensions,
+    body: Bytes,
+) -> Response {
+    match FaultContext::new(&state, method, headers, &extensions, body) {
+        Ok(ctx) => state.delay_faults.run(ctx).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
 
-    // Apply delays if specified
-    if let Some(delay_ms) = constant_delay_ms {
-        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+// Mark responses served while CHAOS_ENABLED=false, so it is obvious why the
+// requested faults were not applied
+fn note_chaos_disabled(config: &Config, response: &mut Value) {
+    if !config.chaos_enabled {
+        response["chaos"] = json!("disabled");
     }
+}
 
-    if let Some(max_delay_ms) = max_random_delay_ms {
-        let random_delay = rand::thread_rng().gen_range(0..=max_delay_ms);
-        tokio::time::sleep(Duration::from_millis(random_delay)).await;
+// Parse a JSON request body, rejecting non-JSON content types like the Json
+// extractor does
+fn parse_json_body(headers: &HeaderMap, body: &[u8]) -> Result<Value, (StatusCode, Json<Value>)> {
+    let is_json = headers
+        .get("content-type")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<mime::Mime>().ok())
+        .is_some_and(|m| m.subtype() == "json" || m.suffix().is_some_and(|s| s == "json"));
+    if !is_json {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({
+                "error": "Expected request with `Content-Type: application/json`"
+            }))
+        ));
     }
 
-    // Allow header override of target URL for testing
-    let target_url = headers
-        .get("X-Proxy-Url")
+    serde_json::from_slice(body).map_err(|e| (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Failed to parse request body",
+            "details": e.to_string()
+        }))
+    ))
+}
+
+// Encode the forwarded body according to `X-Body-Format`: `compact`
+// (default) re-serializes the parsed JSON without whitespace, `pretty`
+// re-serializes it indented, and `passthrough` forwards the original bytes
+// unchanged
+fn encode_body(
+    headers: &HeaderMap,
+    payload: &Value,
+    raw: &Bytes,
+) -> Result<Bytes, (StatusCode, Json<Value>)> {
+    let format = headers
+        .get("X-Body-Format")
         .and_then(|h| h.to_str().ok())
-        .unwrap_or(&config.target_url);
+        .unwrap_or("compact");
 
-    // Convert the JSON payload to bytes
-    let body_bytes = match serde_json::to_vec(&payload) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            return (
+    let encoded = match format {
+        "compact" => serde_json::to_vec(payload),
+        "pretty" => serde_json::to_vec_pretty(payload),
+        "passthrough" => return Ok(raw.clone()),
+        other => {
+            return Err((
                 StatusCode::BAD_REQUEST,
                 Json(json!({
-                    "error": "Failed to serialize request body",
-                    "details": e.to_string()
+                    "error": "Invalid X-Body-Format",
+                    "details": format!("Unknown body format {}; expected compact, pretty or passthrough", other)
                 }))
-            );
+            ));
         }
     };
 
-    // Create and send the proxied request
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri(target_url)
-        .header("content-type", "application/json")
-        .body(Full::new(Bytes::from(body_bytes)))
-        .unwrap();
+    encoded.map(Bytes::from).map_err(|e| (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Failed to serialize request body",
+            "details": e.to_string()
+        }))
+    ))
+}
 
-    match client.request(req).await {
-        Ok(resp) => {
-            let status = resp.status();
-            let body_bytes = match resp.into_body().collect().await {
-                Ok(collected) => collected.to_bytes(),
-                Err(e) => {
-                    return (
-                        StatusCode::BAD_GATEWAY,
-                        Json(json!({
-                            "error": "Failed to read response body",
-                            "details": e.to_string()
-                        }))
-                    );
-                }
-            };
-            
-            let body: Value = match serde_json::from_slice(&body_bytes) {
-                Ok(json) => json,
-                Err(_) => Value::Null,
-            };
-            
-            (status, Json(json!({
-                "status": "success",
-                "applied_delays": {
-                    "constant_delay_ms": constant_delay_ms,
-                    "random_delay_ms": max_random_delay_ms.map(|max| format!("0-{}", max))
-                },
-                "target_url": target_url,
-                "response": body
-            })))
-        }
-        Err(e) => (
+// Fully collected upstream response
+struct UpstreamResponse {
+    status: StatusCode,
+    // Reason phrase of the status line, when HTTP/1 and not the standard one
+    reason: Option<ReasonPhrase>,
+    version: axum::http::Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+// Send the proxied request and collect the full upstream response body
+async fn send_upstream(
+    client: &HttpClient,
+    req: Request<Full<Bytes>>,
+    target_url: &str,
+) -> Result<UpstreamResponse, (StatusCode, Json<Value>)> {
+    let resp = client
+        .request(req)
+        .await
+        .map_err(|e| forward_error_response(&e, target_url))?;
+    collect_upstream(resp).await
+}
+
+// Collect the full body of an upstream response
+async fn collect_upstream(
+    resp: hyper::Response<hyper::body::Incoming>,
+) -> Result<UpstreamResponse, (StatusCode, Json<Value>)> {
+    let (parts, body) = resp.into_parts();
+    match body.collect().await {
+        Ok(collected) => Ok(UpstreamResponse {
+            status: parts.status,
+            reason: parts.extensions.get::<ReasonPhrase>().cloned(),
+            version: parts.version,
+            headers: parts.headers,
+            body: collected.to_bytes(),
+        }),
+        Err(e) => Err((
             StatusCode::BAD_GATEWAY,
             Json(json!({
-                "error": "Failed to forward request",
-                "details": e.to_string(),
-                "target_url": target_url
+                "error": "Failed to read response body",
+                "details": e.to_string()
             }))
-        )
+        )),
+    }
+}
+
+// Copy of a buffered upstream request, for sending it again
+fn clone_request(req: &Request<Full<Bytes>>) -> Request<Full<Bytes>> {
+    let mut copy = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .body(req.body().clone())
+        .unwrap();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+// Send the request, and if it has not completed after the hedge delay, send
+// an identical second attempt and race the two. The first successful
+// response wins and the other attempt is dropped, cancelling it; if one
+// attempt fails the other's result is used. Returns the result with the
+// winning attempt (1 or 2).
+async fn send_hedged(
+    client: &HttpClient,
+    req: Request<Full<Bytes>>,
+    target_url: &str,
+    hedge_delay: Duration,
+) -> (Result<UpstreamResponse, (StatusCode, Json<Value>)>, u8) {
+    let hedge = clone_request(&req);
+
+    let first = send_upstream(client, req, target_url);
+    tokio::pin!(first);
+    tokio::select! {
+        result = &mut first => return (result, 1),
+        _ = tokio::time::sleep(hedge_delay) => {}
+    }
+
+    let second = send_upstream(client, hedge, target_url);
+    tokio::pin!(second);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(response) => (Ok(response), 1),
+            Err(_) => (second.await, 2),
+        },
+        result = &mut second => match result {
+            Ok(response) => (Ok(response), 2),
+            Err(_) => (first.await, 1),
+        },
+    }
+}
+
+// Parse `X-Hedge-Delay-Ms`, the delay before a hedged second attempt is sent
+fn hedge_delay(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get("X-Hedge-Delay-Ms")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_millis)
+}
+
+// Send the request, hedged when requested. Also returns the hedging report
+// for the response body.
+async fn send_upstream_maybe_hedged(
+    client: &HttpClient,
+    req: Request<Full<Bytes>>,
+    target_url: &str,
+    headers: &HeaderMap,
+) -> (Result<UpstreamResponse, (StatusCode, Json<Value>)>, Option<Value>) {
+    let Some(delay) = hedge_delay(headers) else {
+        return (send_upstream(client, req, target_url).await, None);
+    };
+
+    let (result, winner) = send_hedged(client, req, target_url, delay).await;
+    (result, Some(json!({
+        "delay_ms": delay.as_millis() as u64,
+        "winner": winner
+    })))
+}
+
+// Map a failed upstream call to an error response, distinguishing connect
+// timeouts (504) from failed TLS version negotiation and other forwarding
+// errors (502)
+fn forward_error_response(
+    e: &hyper_util::client::legacy::Error,
+    target_url: &str,
+) -> (StatusCode, Json<Value>) {
+    let (status, error_type) = if connector::is_connect_timeout(e) {
+        (StatusCode::GATEWAY_TIMEOUT, "connect_timeout")
+    } else if connector::is_tls_version_mismatch(e) {
+        (StatusCode::BAD_GATEWAY, "tls_version")
+    } else {
+        (StatusCode::BAD_GATEWAY, "upstream_error")
+    };
+
+    (status, Json(json!({
+        "error": "Failed to forward request",
+        "error_type": error_type,
+        "details": e.to_string(),
+        "target_url": target_url
+    })))
+}
+
+// Readiness probe: fails as soon as shutdown begins so traffic is routed away
+async fn readyz(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    if state.draining.load(Ordering::SeqCst) {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(json!({
+            "status": "draining",
+            "timestamp": chrono::Utc::now().to_rfc3339()
+        })))
+    } else {
+        (StatusCode::OK, Json(json!({
+            "status": "ready",
+            "timestamp": chrono::Utc::now().to_rfc3339()
+        })))
     }
 }
 
@@ -182,117 +768,104 @@ async fn healthcheck() -> (StatusCode, Json<Value>) {
 #[axum::debug_handler]
 async fn failure_handler(
     State(state): State<SharedState>,
+    method: Method,
     headers: HeaderMap,
-    Json(payload): Json<Value>,
-) -> (StatusCode, Json<Value>) {
-    let (client, config) = &*state;
-    
-    // Check if we should return original response
-    let return_original = headers
-        .get("X-Return-Original")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse::<bool>().ok())
-        .unwrap_or(false);
-
-    // Check for custom failure rate header
-    let failure_rate: f64 = headers
-        .get("X-Failure-Rate")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1.0 - config.success_probability);
+    extensions: Extensions,
+    body: Bytes,
+) -> Response {
+    match FaultContext::new(&state, method, headers, &extensions, body) {
+        Ok(ctx) => state.failure_faults.run(ctx).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
 
-    // Get custom failure status code from header, default to 500
-    let failure_status = headers
-        .get("X-Failure-Status-Code")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse::<u16>().ok())
-        .map(StatusCode::from_u16)
-        .and_then(Result::ok)
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Generate random number before any await points
-    let should_succeed = rand::thread_rng().gen_bool(1.0 - failure_rate);
+    fn sni_headers(sni: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Upstream-Sni", sni.parse().unwrap());
+        headers
+    }
 
-    // Allow header override of target URL for testing
-    let target_url = headers
-        .get("X-Proxy-Url")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or(&config.target_url);
+    #[test]
+    fn override_clients_stay_bounded_for_many_server_names() {
+        let state = test_state(|_| {});
+        for i in 0..MAX_OVERRIDE_CLIENTS * 4 {
+            assert!(state.client_for(&sni_headers(&format!("host-{}.example.test", i))).is_ok());
+        }
 
-    // If return_original is false, check if we should fail based on probability
-    if !should_succeed {
-        return (
-            failure_status,
-            Json(json!({
-                "error": "Simulated failure",
-                "target_url": target_url,
-                "failure_rate": failure_rate,
-                "status_code": failure_status.as_u16(),
-                "request_body": payload
-            }))
-        );
+        let clients = state.override_clients.lock().unwrap();
+        assert_eq!(clients.len(), MAX_OVERRIDE_CLIENTS);
+        let last = format!("host-{}.example.test", MAX_OVERRIDE_CLIENTS * 4 - 1);
+        assert!(clients.contains_key(&(Some(last), false)));
+        assert!(!clients.contains_key(&(Some("host-0.example.test".to_string()), false)));
     }
 
-    // Convert the JSON payload to bytes
-    let body_bytes = match serde_json::to_vec(&payload) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(json!({
-                    "error": "Failed to serialize request body",
-                    "details": e.to_string()
-                }))
-            );
+    #[test]
+    fn override_clients_keep_recently_used_server_names() {
+        let state = test_state(|_| {});
+        for i in 0..MAX_OVERRIDE_CLIENTS {
+            state.client_for(&sni_headers(&format!("host-{}.example.test", i))).unwrap();
         }
-    };
+        state.client_for(&sni_headers("host-0.example.test")).unwrap();
+        state.client_for(&sni_headers("new.example.test")).unwrap();
 
-    // Create and send the proxied request
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri(target_url)
-        .header("content-type", "application/json")
-        .body(Full::new(Bytes::from(body_bytes)))
-        .unwrap();
+        let clients = state.override_clients.lock().unwrap();
+        assert!(clients.contains_key(&(Some("host-0.example.test".to_string()), false)));
+        assert!(!clients.contains_key(&(Some("host-1.example.test".to_string()), false)));
+    }
 
-    match client.request(req).await {
-        Ok(resp) => {
-            let status = resp.status();
-            let body_bytes = match resp.into_body().collect().await {
-                Ok(collected) => collected.to_bytes(),
-                Err(e) => {
-                    return (
-                        StatusCode::BAD_GATEWAY,
-                        Json(json!({
-                            "error": "Failed to read response body",
-                            "details": e.to_string()
-                        }))
-                    );
-                }
-            };
-            
-            let body: Value = match serde_json::from_slice(&body_bytes) {
-                Ok(json) => json,
-                Err(_) => Value::Null,
-            };
-            
-            if return_original {
-                (status, Json(body))
-            } else {
-                (status, Json(json!({
-                    "status": "success",
-                    "target_url": target_url,
-                    "response": body
-                })))
+    #[tokio::test]
+    async fn proxied_conditional_get_round_trips_to_304() {
+        use axum::http::header;
+        use tower::ServiceExt;
+
+        let upstream = Router::new()
+            .route("/tagged", get(|| async { ([(header::ETAG, "\"v1\"")], "tagged body") }))
+            .route("/untagged", get(|| async { "untagged body" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+        let app = build_app(test_state(|config| config.target_url = target_url));
+
+        let get = |path: &str, if_none_match: Option<&str>| {
+            let mut request = Request::builder().uri(path).header("X-Simulate-Etag", "true");
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(axum::body::Body::empty()).unwrap())
+        };
+
+        for path in ["/tagged", "/untagged"] {
+            let response = get(path, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+            if path == "/tagged" {
+                // The upstream's own tag is passed through
+                assert_eq!(etag, "\"v1\"");
             }
+
+            let response = get(path, Some(&etag)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", path);
+            assert_eq!(response.headers()[header::ETAG], etag.as_str());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(body.is_empty());
+
+            let response = get(path, Some("\"stale\"")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
         }
-        Err(e) => (
-            StatusCode::BAD_GATEWAY,
-            Json(json!({
-                "error": "Failed to forward request",
-                "details": e.to_string(),
-                "target_url": target_url
-            }))
-        )
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn invalid_server_names_are_rejected() {
+        let state = test_state(|_| {});
+        for sni in ["", ".", "under_score.example", "-leading.example", "a..b", &"a".repeat(64)] {
+            let (status, _) = state.client_for(&sni_headers(sni)).err().unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", sni);
+        }
+        assert!(state.override_clients.lock().unwrap().is_empty());
+        assert!(state.client_for(&sni_headers("API.Example.com.")).is_ok());
+    }
+}
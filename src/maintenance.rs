@@ -0,0 +1,90 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Map, Value};
+use std::sync::RwLock;
+
+use crate::metrics::{Outcome, RequestOutcome};
+use crate::SharedState;
+
+// Response returned by every fault endpoint while maintenance mode is on
+#[derive(Clone)]
+struct MaintenanceResponse {
+    status: StatusCode,
+    body: Value,
+}
+
+// Admin-controlled maintenance switch. Off at startup.
+#[derive(Default)]
+pub struct Maintenance(RwLock<Option<MaintenanceResponse>>);
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Apply a `POST /admin/maintenance` body: `{"enabled", "status", "body"}`.
+    // `status` defaults to 503 and `body` to a generic maintenance error.
+    pub fn update(&self, fields: &Map<String, Value>) -> Result<(), String> {
+        let enabled = fields
+            .get("enabled")
+            .and_then(Value::as_bool)
+            .ok_or("enabled must be true or false")?;
+        if !enabled {
+            *self.0.write().unwrap() = None;
+            return Ok(());
+        }
+
+        let status = match fields.get("status") {
+            None => StatusCode::SERVICE_UNAVAILABLE,
+            Some(status) => status
+                .as_u64()
+                .filter(|code| (100..=599).contains(code))
+                .and_then(|code| StatusCode::from_u16(code as u16).ok())
+                .ok_or("status must be a status code between 100 and 599")?,
+        };
+        let body = fields.get("body").cloned().unwrap_or_else(|| json!({
+            "error": "Service under maintenance"
+        }));
+
+        *self.0.write().unwrap() = Some(MaintenanceResponse { status, body });
+        Ok(())
+    }
+
+    pub fn reset(&self) {
+        *self.0.write().unwrap() = None;
+    }
+
+    pub fn to_json(&self) -> Value {
+        match &*self.0.read().unwrap() {
+            Some(response) => json!({
+                "enabled": true,
+                "status": response.status.as_u16(),
+                "body": response.body
+            }),
+            None => json!({ "enabled": false }),
+        }
+    }
+}
+
+// While maintenance mode is on, answer fault endpoints with the maintenance
+// response without running any faults or contacting the upstream
+pub async fn short_circuit(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let maintenance = state.maintenance.0.read().unwrap().clone();
+    let Some(MaintenanceResponse { status, body }) = maintenance else {
+        return next.run(request).await;
+    };
+
+    if let Some(outcome) = request.extensions().get::<RequestOutcome>() {
+        outcome.mark(Outcome::SimulatedFailure);
+    }
+    (status, Json(body)).into_response()
+}
@@ -0,0 +1,360 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{Json, Response},
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::SharedState;
+
+// Windows (in seconds) over which request rates are reported
+const WINDOWS: [u64; 3] = [1, 10, 60];
+
+// One bucket per second for the largest window, plus the current (partial)
+// second which is excluded from rates.
+const BUCKETS: usize = 61;
+
+// Upper bounds (in ms) of the latency histogram buckets; a final bucket
+// collects everything slower
+const LATENCY_BUCKETS_MS: [u64; 13] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+// Operational endpoints that are not counted in /stats
+const UNCOUNTED_PATHS: [&str; 7] = ["/healthcheck", "/readyz", "/metrics", "/stats", "/stats/reset", "/reset-counters", "/synthetic"];
+
+// Probes, metrics and admin calls, as opposed to traffic under test
+pub fn is_operational(path: &str) -> bool {
+    UNCOUNTED_PATHS.contains(&path) || path.starts_with("/admin/")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    SimulatedFailure,
+    UpstreamFailure,
+}
+
+// Per-request outcome set by handlers; unmarked responses are classified by
+// status code.
+#[derive(Clone, Default)]
+pub struct RequestOutcome(Arc<AtomicU8>);
+
+impl RequestOutcome {
+    const UNSET: u8 = 0;
+
+    pub fn mark(&self, outcome: Outcome) {
+        self.0.store(outcome as u8 + 1, Ordering::Relaxed);
+    }
+
+    // Outcome explicitly marked by the handler, if any
+    pub fn marked(&self) -> Option<Outcome> {
+        match self.0.load(Ordering::Relaxed) {
+            Self::UNSET => None,
+            1 => Some(Outcome::Success),
+            2 => Some(Outcome::SimulatedFailure),
+            _ => Some(Outcome::UpstreamFailure),
+        }
+    }
+
+    fn resolve(&self, status: StatusCode) -> Outcome {
+        match self.marked() {
+            Some(outcome) => outcome,
+            None if status.is_server_error() => Outcome::UpstreamFailure,
+            None => Outcome::Success,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    second: AtomicU64,
+    success: AtomicU64,
+    simulated_failure: AtomicU64,
+    upstream_failure: AtomicU64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    success: u64,
+    simulated_failure: u64,
+    upstream_failure: u64,
+}
+
+// Sliding-window request counter bucketed by wall-clock second
+pub struct RateRecorder {
+    buckets: Vec<Bucket>,
+}
+
+impl RateRecorder {
+    pub fn new() -> Self {
+        RateRecorder {
+            buckets: (0..BUCKETS).map(|_| Bucket::default()).collect(),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        let now = Self::now();
+        let bucket = &self.buckets[(now % BUCKETS as u64) as usize];
+
+        // The first request in a new second reclaims the bucket
+        let previous = bucket.second.load(Ordering::Acquire);
+        if previous != now
+            && bucket
+                .second
+                .compare_exchange(previous, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            bucket.success.store(0, Ordering::Relaxed);
+            bucket.simulated_failure.store(0, Ordering::Relaxed);
+            bucket.upstream_failure.store(0, Ordering::Relaxed);
+        }
+
+        let counter = match outcome {
+            Outcome::Success => &bucket.success,
+            Outcome::SimulatedFailure => &bucket.simulated_failure,
+            Outcome::UpstreamFailure => &bucket.upstream_failure,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Sum the completed seconds within the window
+    fn counts(&self, window: u64) -> Counts {
+        let now = Self::now();
+        let mut counts = Counts::default();
+        for bucket in &self.buckets {
+            let second = bucket.second.load(Ordering::Acquire);
+            if second < now && second >= now.saturating_sub(window) {
+                counts.success += bucket.success.load(Ordering::Relaxed);
+                counts.simulated_failure += bucket.simulated_failure.load(Ordering::Relaxed);
+                counts.upstream_failure += bucket.upstream_failure.load(Ordering::Relaxed);
+            }
+        }
+        counts
+    }
+
+    fn rps(&self, window: u64) -> f64 {
+        let counts = self.counts(window);
+        (counts.success + counts.simulated_failure + counts.upstream_failure) as f64 / window as f64
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut windows = serde_json::Map::new();
+        for window in WINDOWS {
+            let counts = self.counts(window);
+            let rate = |n: u64| n as f64 / window as f64;
+            windows.insert(format!("{}s", window), json!({
+                "total": rate(counts.success + counts.simulated_failure + counts.upstream_failure),
+                "success": rate(counts.success),
+                "failed": rate(counts.simulated_failure + counts.upstream_failure),
+                "simulated_failure": rate(counts.simulated_failure),
+                "upstream_failure": rate(counts.upstream_failure)
+            }));
+        }
+        Value::Object(windows)
+    }
+}
+
+// Record each request's outcome and, when EMIT_RPS_HEADER is enabled, report
+// the current rates as `X-Proxy-Rps: 1s=12.0;10s=10.4;60s=9.8`
+pub async fn record_rate(
+    State(state): State<SharedState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let outcome = request
+        .extensions()
+        .get::<RequestOutcome>()
+        .cloned()
+        .unwrap_or_default();
+    request.extensions_mut().insert(outcome.clone());
+
+    let mut response = next.run(request).await;
+
+    state.rates.record(outcome.resolve(response.status()));
+
+    if state.config.read().unwrap().emit_rps_header {
+        let value = WINDOWS
+            .iter()
+            .map(|&window| format!("{}s={:.1}", window, state.rates.rps(window)))
+            .collect::<Vec<_>>()
+            .join(";");
+        response
+            .headers_mut()
+            .insert("X-Proxy-Rps", HeaderValue::from_str(&value).unwrap());
+    }
+
+    response
+}
+
+// Cumulative request counters and latency histogram, reported on /stats
+pub struct Stats {
+    total: AtomicU64,
+    by_handler: Mutex<BTreeMap<String, u64>>,
+    injected_failures: AtomicU64,
+    upstream_errors: AtomicU64,
+    latency_sum_us: AtomicU64,
+    latency_buckets: Vec<AtomicU64>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            total: AtomicU64::new(0),
+            by_handler: Mutex::new(BTreeMap::new()),
+            injected_failures: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            latency_sum_us: AtomicU64::new(0),
+            latency_buckets: (0..=LATENCY_BUCKETS_MS.len()).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn record(&self, handler: &str, outcome: Outcome, latency: Duration) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        *self.by_handler.lock().unwrap().entry(handler.to_string()).or_insert(0) += 1;
+        match outcome {
+            Outcome::Success => {}
+            Outcome::SimulatedFailure => {
+                self.injected_failures.fetch_add(1, Ordering::Relaxed);
+            }
+            Outcome::UpstreamFailure => {
+                self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.latency_sum_us.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| latency_ms <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.total.store(0, Ordering::Relaxed);
+        self.by_handler.lock().unwrap().clear();
+        self.injected_failures.store(0, Ordering::Relaxed);
+        self.upstream_errors.store(0, Ordering::Relaxed);
+        self.latency_sum_us.store(0, Ordering::Relaxed);
+        for bucket in &self.latency_buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Upper bound of the bucket containing the given percentile. Latencies
+    // beyond the last bucket are reported as null.
+    fn percentile_ms(&self, counts: &[u64], total: u64, p: f64) -> Option<u64> {
+        if total == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (i, count) in counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return LATENCY_BUCKETS_MS.get(i).copied();
+            }
+        }
+        None
+    }
+
+    pub fn to_json(&self) -> Value {
+        let counts: Vec<u64> = self.latency_buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let observed: u64 = counts.iter().sum();
+        let avg_ms = (observed > 0)
+            .then(|| self.latency_sum_us.load(Ordering::Relaxed) as f64 / observed as f64 / 1000.0);
+
+        // The overflow bucket has no upper bound and is reported as null
+        let histogram: Vec<Value> = counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| json!({ "le_ms": LATENCY_BUCKETS_MS.get(i), "count": count }))
+            .collect();
+
+        json!({
+            "total_requests": self.total.load(Ordering::Relaxed),
+            "requests_by_handler": *self.by_handler.lock().unwrap(),
+            "injected_failures": self.injected_failures.load(Ordering::Relaxed),
+            "upstream_errors": self.upstream_errors.load(Ordering::Relaxed),
+            "latency_ms": {
+                "avg": avg_ms,
+                "p50": self.percentile_ms(&counts, observed, 50.0),
+                "p90": self.percentile_ms(&counts, observed, 90.0),
+                "p99": self.percentile_ms(&counts, observed, 99.0),
+                "histogram": histogram
+            }
+        })
+    }
+}
+
+// Name of the handler serving a request: its route without the leading slash
+// and path parameters (`delay`, `fixture`, `stats/reset`), or "proxy" for
+// the generic proxy route
+pub fn handler_name(request: &Request<Body>) -> String {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| {
+            p.as_str()
+                .split('/')
+                .filter(|segment| !segment.is_empty() && !segment.starts_with(':'))
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .unwrap_or_else(|| "proxy".to_string())
+}
+
+// Count every proxied request in /stats, keyed by the route that handled it.
+// Requests falling through to the generic proxy route are counted as "proxy".
+pub async fn record_stats(
+    State(state): State<SharedState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let handler = handler_name(&request);
+    let outcome = RequestOutcome::default();
+    request.extensions_mut().insert(outcome.clone());
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    state.stats.record(&handler, outcome.resolve(response.status()), start.elapsed());
+    response
+}
+
+// Cumulative request statistics since startup or the last reset
+pub async fn stats(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(state.stats.to_json()))
+}
+
+pub async fn reset_stats(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    state.stats.reset();
+    (StatusCode::OK, Json(json!({
+        "status": "reset",
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
+
+// Current request rates over each window
+pub async fn metrics(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(json!({
+        "rps": state.rates.to_json(),
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
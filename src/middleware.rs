@@ -0,0 +1,1906 @@
+use axum::{
+    body::{Body, BodyDataStream, HttpBody},
+    extract::{DefaultBodyLimit, State},
+    http::{header, Extensions, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use http_body_util::{Empty, Limited};
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tower::{Layer, ServiceExt};
+
+use crate::fault_log::FaultLog;
+use crate::metrics::{self, Outcome, RequestOutcome};
+use crate::server::ConnectionState;
+use crate::{proxy, random, SharedState};
+use crate::timing::Timing;
+
+// Headers that control message framing or the connection itself. Sending
+// more than one of these makes the response unparseable (or ambiguous in a
+// way that enables smuggling), so they are never duplicated, and they are
+// only dropped with `X-Force-Drop`.
+const FRAMING_HEADERS: &[&str] = &[
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "keep-alive",
+    "upgrade",
+    "trailer",
+    "te",
+];
+
+// Default and maximum spread of the random delay used to reorder responses
+const DEFAULT_ORDERING_MAX_MS: u64 = 100;
+const MAX_ORDERING_MAX_MS: u64 = 10_000;
+
+// Upper bound for `X-Backpressure-Pause-Ms`
+const MAX_BACKPRESSURE_PAUSE_MS: u64 = 60_000;
+
+// Upper bound for `X-Intermittent-Hang-Ms`
+const MAX_HANG_MS: u64 = 300_000;
+
+// Largest `X-Date-Skew-Secs` either way: a year
+const MAX_DATE_SKEW_SECS: u64 = 365 * 24 * 60 * 60;
+
+// Upper bound on duplicates to keep responses within client header limits
+const MAX_DUPLICATE_COUNT: usize = 100;
+
+// Upper bound for `X-Random-Pad-Bytes`
+const MAX_RANDOM_PAD_BYTES: usize = 65536;
+
+// Upper bound for `X-Amplify-Response`, so a typo cannot exhaust memory
+const MAX_AMPLIFY_FACTOR: usize = 1000;
+
+// Upper bound for the copies of the body `X-Amplify-Response` returns, as
+// a large upstream body times a large factor would still exhaust memory
+const MAX_AMPLIFY_BYTES: usize = 64 * 1024 * 1024;
+
+// hyper's HTTP/1 parser rejects requests with more headers than this with
+// 431 before they reach the app, so MAX_HEADER_COUNT cannot go higher
+pub const HEADER_COUNT_CEILING: usize = 100;
+
+// Default for MAX_HEADER_BYTES
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 65536;
+
+// Default for MAX_INFLATED_HEADER_BYTES: 1 MiB, well past the 8-64 KiB
+// header limits of common clients
+pub const DEFAULT_MAX_INFLATED_HEADER_BYTES: usize = 1024 * 1024;
+
+// Parse `X-Duplicate-Header: <name>:<count>`
+fn parse_duplicate_header(value: &str) -> Result<(HeaderName, usize), String> {
+    let (name, count) = value
+        .rsplit_once(':')
+        .ok_or("expected <header-name>:<count>")?;
+
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name: {}", name.trim()))?;
+    if FRAMING_HEADERS.contains(&name.as_str()) {
+        return Err(format!("{} affects message framing and cannot be duplicated", name));
+    }
+
+    let count = count
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("invalid count: {}", count.trim()))?;
+    if count == 0 || count > MAX_DUPLICATE_COUNT {
+        return Err(format!("count must be between 1 and {}", MAX_DUPLICATE_COUNT));
+    }
+
+    Ok((name, count))
+}
+
+// Append the requested header to the response `count` times, each with a
+// distinct value, so clients receive genuine duplicates.
+pub async fn duplicate_header(request: Request<Body>, next: Next) -> Response {
+    let duplicate = match request
+        .headers()
+        .get("X-Duplicate-Header")
+        .map(|h| h.to_str().map_err(|e| e.to_string()).and_then(parse_duplicate_header))
+    {
+        None => None,
+        Some(Ok(duplicate)) => Some(duplicate),
+        Some(Err(details)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Duplicate-Header",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let mut response = next.run(request).await;
+
+    if let Some((name, count)) = duplicate {
+        if let Some(fault_log) = fault_log {
+            fault_log.record("duplicated_header", count);
+        }
+        let headers = response.headers_mut();
+        for i in 1..=count {
+            let value = HeaderValue::from_str(&format!("duplicate-{}", i)).unwrap();
+            headers.append(name.clone(), value);
+        }
+    }
+
+    response
+}
+
+// With `X-Inflate-Response-Header-Bytes: N`, add an `X-Inflated-Header`
+// whose value is N bytes of filler, to push the response past the client's
+// header size limit. N is capped by MAX_INFLATED_HEADER_BYTES.
+pub async fn inflate_response_header(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(h) = request.headers().get("X-Inflate-Response-Header-Bytes") else {
+        return next.run(request).await;
+    };
+    let max = state.config.read().unwrap().max_inflated_header_bytes;
+    let Some(len) = h.to_str().ok().and_then(|s| s.trim().parse::<usize>().ok()).filter(|n| (1..=max).contains(n)) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Inflate-Response-Header-Bytes",
+                "details": format!("X-Inflate-Response-Header-Bytes must be an integer between 1 and {} (MAX_INFLATED_HEADER_BYTES)", max)
+            }))
+        ).into_response();
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let mut response = next.run(request).await;
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("inflated_header", len);
+    }
+    let filler = HeaderValue::from_bytes(&vec![b'x'; len]).unwrap();
+    response.headers_mut().insert("X-Inflated-Header", filler);
+    response
+}
+
+// Remove each member of the objects in `value` with probability `rate`. Only
+// the first level of objects is visited (the root object, or the objects in
+// a root array) unless `deep`, which recurses through every nested object
+// and array. The path of each removed member is recorded, e.g. `user.email`
+// or `[0].id`.
+fn drop_members(value: &mut Value, path: &str, rate: f64, deep: bool, rng: &mut impl Rng, dropped: &mut Vec<String>) {
+    match value {
+        Value::Object(object) => {
+            let names: Vec<String> = object.keys().cloned().collect();
+            for name in names {
+                let member_path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+                if rng.gen_bool(rate) {
+                    object.remove(&name);
+                    dropped.push(member_path);
+                } else if deep {
+                    drop_members(object.get_mut(&name).unwrap(), &member_path, rate, deep, rng, dropped);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                drop_members(item, &format!("{}[{}]", path, index), rate, deep, rng, dropped);
+            }
+        }
+        _ => {}
+    }
+}
+
+// With `X-Drop-Fields-Rate: P`, remove each top-level member of a JSON
+// response with probability P, or every member at any depth with
+// `X-Drop-Deep: true`, to check that clients cope with missing fields.
+// `X-Random-Seed` makes the choice reproducible. The removed paths are
+// reported in `X-Dropped-Fields` as a JSON array. Non-JSON bodies are left
+// alone.
+pub async fn drop_fields(request: Request<Body>, next: Next) -> Response {
+    let Some(rate) = request.headers().get("X-Drop-Fields-Rate") else {
+        return next.run(request).await;
+    };
+    let rate = match rate.to_str().ok().and_then(|s| s.parse::<f64>().ok()).filter(|p| (0.0..=1.0).contains(p)) {
+        Some(rate) => rate,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Drop-Fields-Rate",
+                    "details": "X-Drop-Fields-Rate must be a probability between 0.0 and 1.0"
+                }))
+            ).into_response();
+        }
+    };
+    let deep = request
+        .headers()
+        .get("X-Drop-Deep")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    let seed = match request.headers().get("X-Random-Seed").map(|h| h.to_str().ok().and_then(|s| s.parse::<u64>().ok())) {
+        None => None,
+        Some(Some(seed)) => Some(seed),
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Random-Seed",
+                    "details": "X-Random-Seed must be a non-negative integer"
+                }))
+            ).into_response();
+        }
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    let mut dropped = Vec::new();
+    drop_members(&mut value, "", rate, deep, &mut random::seeded(seed), &mut dropped);
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("dropped_fields", dropped.len());
+    }
+    // JSON escapes control characters, so only non-ASCII names can make
+    // the header invalid
+    let report = serde_json::to_string(&dropped).unwrap();
+    if let Ok(report) = HeaderValue::from_bytes(report.as_bytes()) {
+        parts.headers.insert("X-Dropped-Fields", report);
+    }
+    if dropped.is_empty() {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(serde_json::to_vec(&value).unwrap()))
+}
+
+// With `X-Random-Pad-Bytes: N`, add N random letters and digits to the
+// response where a well-behaved client ignores them, so its size no longer
+// tracks the content: a `_padding` field for JSON object bodies, an
+// `X-Padding` header otherwise.
+pub async fn random_padding(request: Request<Body>, next: Next) -> Response {
+    let len = match request.headers().get("X-Random-Pad-Bytes").map(|h| {
+        h.to_str()
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|&len| len <= MAX_RANDOM_PAD_BYTES)
+    }) {
+        None => return next.run(request).await,
+        Some(Some(len)) => len,
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Random-Pad-Bytes",
+                    "details": format!("X-Random-Pad-Bytes must be an integer between 0 and {}", MAX_RANDOM_PAD_BYTES)
+                }))
+            ).into_response();
+        }
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("random_padding", len);
+    }
+    let padding = random::alphanumeric(len);
+    if let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&bytes) {
+        object.insert("_padding".to_string(), Value::from(padding));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::from(Value::Object(object).to_string()));
+    }
+
+    parts.headers.insert("X-Padding", HeaderValue::from_str(&padding).unwrap());
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+// With `X-Amplify-Response: N`, return N copies of the response body: a JSON
+// array of N copies for JSON bodies, the body repeated N times otherwise.
+// The copies are joined as bytes, so each is exactly the original.
+pub async fn amplify_response(request: Request<Body>, next: Next) -> Response {
+    let factor = match request.headers().get("X-Amplify-Response").map(|h| {
+        h.to_str()
+            .ok()
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .filter(|n| (1..=MAX_AMPLIFY_FACTOR).contains(n))
+    }) {
+        None => return next.run(request).await,
+        Some(Some(factor)) => factor,
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Amplify-Response",
+                    "details": format!("X-Amplify-Response must be an integer between 1 and {}", MAX_AMPLIFY_FACTOR)
+                }))
+            ).into_response();
+        }
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    // Stop reading as soon as the copies would go over MAX_AMPLIFY_BYTES
+    let max_body_bytes = MAX_AMPLIFY_BYTES / factor;
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) if proxy::exceeds_body_limit(&e) => {
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({
+                    "error": "Amplified response too large",
+                    "details": format!(
+                        "{} copies of the response body would exceed {} bytes; bodies up to {} bytes can be amplified that many times",
+                        factor, MAX_AMPLIFY_BYTES, max_body_bytes
+                    ),
+                    "limit_bytes": MAX_AMPLIFY_BYTES
+                }))
+            ).into_response();
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("amplified", factor);
+    }
+    let amplified = if serde_json::from_slice::<Value>(&bytes).is_ok() {
+        let mut array = Vec::with_capacity((bytes.len() + 1) * factor + 1);
+        array.push(b'[');
+        for i in 0..factor {
+            if i > 0 {
+                array.push(b',');
+            }
+            array.extend_from_slice(&bytes);
+        }
+        array.push(b']');
+        array
+    } else {
+        bytes.repeat(factor)
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert("X-Original-Content-Length", HeaderValue::from(bytes.len()));
+    Response::from_parts(parts, Body::from(amplified))
+}
+
+// With `X-Inject-Duplicate-Key: <key>`, append a second `<key>` member to a
+// JSON object response that already has that top-level key, with the value
+// from `X-Inject-Duplicate-Value` (JSON, default null). `Value` cannot hold
+// duplicate keys, so the member is spliced into the serialized body before
+// its closing brace.
+pub async fn duplicate_json_key(request: Request<Body>, next: Next) -> Response {
+    let Some(key) = request.headers().get("X-Inject-Duplicate-Key") else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() => key.to_string(),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Inject-Duplicate-Key",
+                    "details": "X-Inject-Duplicate-Key must be a non-empty key name"
+                }))
+            ).into_response();
+        }
+    };
+    let value = match request.headers().get("X-Inject-Duplicate-Value") {
+        None => Value::Null,
+        Some(h) => match h.to_str().ok().and_then(|s| serde_json::from_str::<Value>(s).ok()) {
+            Some(value) => value,
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid X-Inject-Duplicate-Value",
+                        "details": "X-Inject-Duplicate-Value must be a JSON value"
+                    }))
+                ).into_response();
+            }
+        },
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    let has_key = matches!(
+        serde_json::from_slice::<Value>(&bytes),
+        Ok(Value::Object(object)) if object.contains_key(&key)
+    );
+    if !has_key {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    // A valid object with at least one member, so it ends with `}` once
+    // trailing whitespace is trimmed and a comma can go before the new member
+    let end = bytes.iter().rposition(|&b| b == b'}').unwrap();
+    let mut injected = bytes[..end].to_vec();
+    injected.extend_from_slice(format!(",{}:{}}}", Value::from(key.as_str()), value).as_bytes());
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("duplicate_key", &key);
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(injected))
+}
+
+// With `X-Append-Trailing-Garbage: <bytes>`, append the header value after a
+// JSON response body. The body stays a complete, valid JSON document
+// followed by extra bytes, which lenient parsers ignore and strict ones
+// reject. Content-Length counts the appended bytes. Other bodies are left
+// alone.
+pub async fn append_trailing_garbage(request: Request<Body>, next: Next) -> Response {
+    let Some(garbage) = request.headers().get("X-Append-Trailing-Garbage") else {
+        return next.run(request).await;
+    };
+    if garbage.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Append-Trailing-Garbage",
+                "details": "X-Append-Trailing-Garbage must be the non-empty bytes to append"
+            }))
+        ).into_response();
+    }
+    let garbage = garbage.as_bytes().to_vec();
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    if serde_json::from_slice::<Value>(&bytes).is_err() {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    let mut appended = bytes.to_vec();
+    appended.extend_from_slice(&garbage);
+
+    if let Some(fault_log) = fault_log {
+        fault_log.record("trailing_garbage", garbage.len());
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(appended))
+}
+
+// With `X-Randomize-Ordering: true`, hold each response for a random delay of
+// up to `X-Randomize-Ordering-Max-Ms` so concurrent requests complete in a
+// shuffled order. The delay is applied after the handler, on top of any
+// upstream latency, and reported in `X-Ordering-Delay-Ms`. Disabled along
+// with the other delays by CHAOS_ENABLED=false.
+pub async fn randomize_ordering(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let enabled = request
+        .headers()
+        .get("X-Randomize-Ordering")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false)
+        && state.chaos_active();
+    if !enabled {
+        return next.run(request).await;
+    }
+
+    let max_delay_ms = request
+        .headers()
+        .get("X-Randomize-Ordering-Max-Ms")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_ORDERING_MAX_MS)
+        .min(MAX_ORDERING_MAX_MS);
+    let timing = request.extensions().get::<Timing>().cloned();
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+    let connection = request.extensions().get::<ConnectionState>().cloned();
+
+    let mut response = next.run(request).await;
+
+    let delay = Duration::from_millis(random::up_to(max_delay_ms));
+    let delay_ms = charge_delay_budget(&state, connection.as_ref(), delay).as_millis() as u64;
+    let delay_start = Instant::now();
+    tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    if let Some(timing) = timing {
+        timing.record_delay(delay_start.elapsed());
+    }
+    if let Some(fault_log) = fault_log {
+        fault_log.record("ordering_delay", delay_ms);
+    }
+
+    response
+        .headers_mut()
+        .insert("X-Ordering-Delay-Ms", HeaderValue::from(delay_ms));
+    response
+}
+
+// The part of an injected wait the request's connection still has
+// MAX_DELAY_BUDGET_MS for. Requests without connection state are not limited.
+fn charge_delay_budget(state: &SharedState, connection: Option<&ConnectionState>, wait: Duration) -> Duration {
+    let budget = state.config.read().unwrap().max_delay_budget;
+    connection.map_or(wait, |connection| connection.charge_delay(budget, wait))
+}
+
+// With MAX_DELAY_BUDGET_MS set, mark every response on a connection that has
+// spent its budget with `X-Delay-Budget-Exhausted: true`, including those
+// that asked for no delay. Probes, metrics and admin calls are not marked.
+pub async fn delay_budget(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let budget = state.config.read().unwrap().max_delay_budget;
+    let connection = request.extensions().get::<ConnectionState>().cloned();
+    if budget.is_none() || metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let mut response = next.run(request).await;
+    if connection.is_some_and(|connection| connection.delay_budget_spent(budget)) {
+        response.headers_mut().insert("X-Delay-Budget-Exhausted", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+// With `X-Cpu-Burn-Ms`, busy-loop on a blocking thread for that long before
+// the handler runs. Unlike a sleep this occupies a thread of the blocking
+// pool, so concurrent burns show how the proxy behaves when CPU-bound. The
+// duration is capped at MAX_CPU_BURN_MS and reported in `X-Cpu-Burn-Ms`.
+pub async fn cpu_burn(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (max_burn, chaos_enabled) = {
+        let config = state.config.read().unwrap();
+        (config.max_cpu_burn, config.chaos_enabled && !state.fault_window_ended(&config))
+    };
+    let burn = request
+        .headers()
+        .get("X-Cpu-Burn-Ms")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(|ms| Duration::from_millis(ms).min(max_burn))
+        .filter(|burn| chaos_enabled && !burn.is_zero())
+        .map(|burn| charge_delay_budget(&state, request.extensions().get(), burn))
+        .filter(|burn| !burn.is_zero());
+    let Some(burn) = burn else {
+        return next.run(request).await;
+    };
+
+    let burn_start = Instant::now();
+    let _ = tokio::task::spawn_blocking(move || {
+        let deadline = Instant::now() + burn;
+        let mut counter = 0u64;
+        while Instant::now() < deadline {
+            counter = std::hint::black_box(counter.wrapping_add(1));
+        }
+    })
+    .await;
+    if let Some(timing) = request.extensions().get::<Timing>() {
+        timing.record_delay(burn_start.elapsed());
+    }
+    if let Some(fault_log) = request.extensions().get::<FaultLog>() {
+        fault_log.record("cpu_burn", burn.as_millis());
+    }
+
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert("X-Cpu-Burn-Ms", HeaderValue::from(burn.as_millis() as u64));
+    response
+}
+
+// With `X-Date-Skew-Secs` (or `X-Date-Skew-Sec`), send a `Date` header
+// offset from the current time by that many seconds (negative values are in
+// the past), for testing clients' clock-skew tolerance. The skew is echoed in
+// `X-Applied-Skew`.
+pub async fn date_skew(request: Request<Body>, next: Next) -> Response {
+    let Some((name, h)) = ["X-Date-Skew-Secs", "X-Date-Skew-Sec"]
+        .into_iter()
+        .find_map(|name| request.headers().get(name).map(|h| (name, h)))
+    else {
+        return next.run(request).await;
+    };
+    let skew_secs = match h
+        .to_str()
+        .ok()
+        .and_then(|s| s.trim().parse::<i64>().ok())
+        .filter(|skew_secs| skew_secs.unsigned_abs() <= MAX_DATE_SKEW_SECS)
+    {
+        Some(skew_secs) => skew_secs,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!("Invalid {}", name),
+                    "details": format!(
+                        "{} must be an integer number of seconds between -{} and {}",
+                        name, MAX_DATE_SKEW_SECS, MAX_DATE_SKEW_SECS
+                    )
+                }))
+            ).into_response();
+        }
+    };
+
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let mut response = next.run(request).await;
+
+    let now = SystemTime::now();
+    let offset = Duration::from_secs(skew_secs.unsigned_abs());
+    let date = if skew_secs >= 0 {
+        now + offset
+    } else {
+        now - offset
+    };
+    // Replaces the Date the server would otherwise add
+    let headers = response.headers_mut();
+    headers.insert(header::DATE, HeaderValue::from_str(&httpdate::fmt_http_date(date)).unwrap());
+    headers.insert("X-Applied-Skew", HeaderValue::from(skew_secs));
+    if let Some(fault_log) = fault_log {
+        fault_log.record("date_skew", skew_secs);
+    }
+
+    response
+}
+
+// A Retry-After value is either delta-seconds or an HTTP-date
+fn is_valid_retry_after(value: &str) -> bool {
+    (!value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+        || httpdate::parse_http_date(value).is_ok()
+}
+
+// Attach `X-Retry-After` as the `Retry-After` header of simulated 429 and 503
+// failures, letting tests check that clients honor the server's backoff hint
+pub async fn retry_after(request: Request<Body>, next: Next) -> Response {
+    let retry_after = match request.headers().get("X-Retry-After") {
+        None => None,
+        Some(h) => match h.to_str() {
+            Ok(value) if is_valid_retry_after(value.trim()) => Some(h.clone()),
+            _ => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid X-Retry-After",
+                        "details": "X-Retry-After must be delta-seconds or an HTTP-date"
+                    }))
+                ).into_response();
+            }
+        },
+    };
+    let outcome = request.extensions().get::<RequestOutcome>().cloned();
+
+    let mut response = next.run(request).await;
+
+    let simulated_failure = outcome.and_then(|o| o.marked()) == Some(Outcome::SimulatedFailure);
+    let retryable_status = matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    );
+    if let Some(value) = retry_after.filter(|_| simulated_failure && retryable_status) {
+        response.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+
+    response
+}
+
+// Strong ETag derived from the body with 64-bit FNV-1a, so identical
+// responses get identical tags across requests and restarts
+fn body_etag(body: &[u8]) -> String {
+    let hash = body.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+// `If-None-Match` uses weak comparison: `W/` prefixes are ignored and `*`
+// matches any tag
+fn if_none_match_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+// With `X-Simulate-Etag: true`, tag successful responses with an ETag (an
+// existing one is kept, otherwise one is derived from the body) and answer
+// `304 Not Modified` without a body when the request's `If-None-Match`
+// matches it
+pub async fn simulate_etag(request: Request<Body>, next: Next) -> Response {
+    let enabled = request
+        .headers()
+        .get("X-Simulate-Etag")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    if !enabled {
+        return next.run(request).await;
+    }
+
+    let if_none_match = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Failed to buffer response body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    let etag = match parts.headers.get(header::ETAG) {
+        Some(existing) => existing.clone(),
+        None => HeaderValue::from_str(&body_etag(&bytes)).unwrap(),
+    };
+    parts.headers.insert(header::ETAG, etag.clone());
+
+    let not_modified = if_none_match
+        .zip(etag.to_str().ok())
+        .is_some_and(|(if_none_match, etag)| if_none_match_matches(&if_none_match, etag));
+    if not_modified {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+// Flip each letter of the header name to upper or lower case at random
+fn random_case(name: &str) -> String {
+    name.chars()
+        .map(|c| if random::coin() { c.to_ascii_uppercase() } else { c })
+        .collect()
+}
+
+// hyper writes HTTP/1 header names from the header case map found in the
+// response extensions, but the map type is private to hyper. Obtain one by
+// letting hyper parse a request head spelled with the wanted casing, with
+// `preserve_header_case` enabled, and hand back the parsed request's
+// extensions.
+async fn header_case_extensions(names: &[String]) -> Option<Extensions> {
+    let mut head = String::from("GET / HTTP/1.1\r\n");
+    for name in names {
+        head.push_str(name);
+        head.push_str(": x\r\n");
+    }
+    head.push_str("\r\n");
+
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let sender = Mutex::new(Some(sender));
+    let service = service_fn(move |mut request: Request<Incoming>| {
+        if let Some(sender) = sender.lock().unwrap().take() {
+            let _ = sender.send(std::mem::take(request.extensions_mut()));
+        }
+        async { Ok::<_, Infallible>(hyper::Response::new(Empty::<Bytes>::new())) }
+    });
+
+    // Both directions are buffered in memory, so the head can be written in
+    // full before the connection is driven
+    let (mut client, server) = tokio::io::duplex(head.len() + 1024);
+    client.write_all(head.as_bytes()).await.ok()?;
+    client.shutdown().await.ok()?;
+    let _ = http1::Builder::new()
+        .preserve_header_case(true)
+        .serve_connection(TokioIo::new(server), service)
+        .await;
+
+    receiver.await.ok()
+}
+
+// Parse `X-Backpressure-After-Bytes` and `X-Backpressure-Pause-Ms`. Returns
+// None when no backpressure was requested.
+fn parse_backpressure(headers: &HeaderMap) -> Result<Option<(usize, Duration)>, String> {
+    let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap_or("").trim());
+    let (window, pause_ms) = match (header("X-Backpressure-After-Bytes"), header("X-Backpressure-Pause-Ms")) {
+        (None, None) => return Ok(None),
+        (Some(window), Some(pause_ms)) => (window, pause_ms),
+        _ => return Err("X-Backpressure-After-Bytes and X-Backpressure-Pause-Ms must be set together".to_string()),
+    };
+    let window = window
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or("X-Backpressure-After-Bytes must be a positive integer")?;
+    let pause_ms = pause_ms
+        .parse::<u64>()
+        .ok()
+        .filter(|&ms| ms <= MAX_BACKPRESSURE_PAUSE_MS)
+        .ok_or_else(|| format!("X-Backpressure-Pause-Ms must be an integer between 0 and {}", MAX_BACKPRESSURE_PAUSE_MS))?;
+    Ok(Some((window, Duration::from_millis(pause_ms))))
+}
+
+// Response body released `window` bytes at a time, with a pause before each
+// window after the first while `pauses` remain
+struct Throttled {
+    data: BodyDataStream,
+    pending: BytesMut,
+    window: usize,
+    pause: Duration,
+    pauses: u64,
+    started: bool,
+    finished: bool,
+}
+
+fn throttle(body: Body, window: usize, pause: Duration, pauses: u64) -> Body {
+    let throttled = Throttled {
+        data: body.into_data_stream(),
+        pending: BytesMut::new(),
+        window,
+        pause,
+        pauses,
+        started: false,
+        finished: false,
+    };
+    Body::from_stream(futures_util::stream::unfold(throttled, |mut t| async move {
+        while t.pending.len() < t.window && !t.finished {
+            match t.data.next().await {
+                Some(Ok(chunk)) => t.pending.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    t.finished = true;
+                    t.pending.clear();
+                    return Some((Err(e), t));
+                }
+                None => t.finished = true,
+            }
+        }
+        if t.pending.is_empty() {
+            return None;
+        }
+
+        if t.started && t.pauses > 0 {
+            tokio::time::sleep(t.pause).await;
+            t.pauses -= 1;
+        }
+        t.started = true;
+        let chunk = t.pending.split_to(t.window.min(t.pending.len())).freeze();
+        Some((Ok(chunk), t))
+    }))
+}
+
+// Approximate a client whose receive buffer stalls: with
+// `X-Backpressure-After-Bytes` and `X-Backpressure-Pause-Ms`, the response
+// body is sent in windows of that many bytes and the total pause is spread
+// evenly over the gaps between windows. True TCP backpressure depends on the
+// client's reads, which the proxy cannot observe, so this is timed instead.
+// When the body length is unknown the whole pause follows the first window.
+pub async fn backpressure(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (window, total_pause) = match parse_backpressure(request.headers()) {
+        Ok(Some(backpressure)) if state.chaos_active() => backpressure,
+        Ok(_) => return next.run(request).await,
+        Err(details) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid backpressure headers",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+    let connection = request.extensions().get::<ConnectionState>().cloned();
+
+    let response = next.run(request).await;
+
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    let gaps = match length {
+        Some(length) => length.div_ceil(window as u64).saturating_sub(1),
+        None => 1,
+    };
+    if gaps == 0 {
+        return response;
+    }
+    let total_pause = charge_delay_budget(&state, connection.as_ref(), total_pause);
+    if total_pause.is_zero() {
+        return response;
+    }
+    let pause = match length {
+        Some(_) => total_pause / gaps as u32,
+        None => total_pause,
+    };
+    if let Some(fault_log) = fault_log {
+        fault_log.record("backpressure_pause", total_pause.as_millis());
+    }
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, throttle(body, window, pause, gaps))
+}
+
+// Parse `X-Intermittent-Hang` (the probability of a hang) and
+// `X-Intermittent-Hang-Ms` (how long it lasts), which must be sent together
+fn parse_intermittent_hang(headers: &HeaderMap) -> Result<Option<(f64, Duration)>, String> {
+    let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap_or("").trim());
+    let (probability, hang_ms) = match (header("X-Intermittent-Hang"), header("X-Intermittent-Hang-Ms")) {
+        (None, None) => return Ok(None),
+        (Some(probability), Some(hang_ms)) => (probability, hang_ms),
+        _ => return Err("X-Intermittent-Hang and X-Intermittent-Hang-Ms must be set together".to_string()),
+    };
+    let probability = probability
+        .parse::<f64>()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .ok_or("X-Intermittent-Hang must be a probability between 0.0 and 1.0")?;
+    let hang_ms = hang_ms
+        .parse::<u64>()
+        .ok()
+        .filter(|&ms| ms <= MAX_HANG_MS)
+        .ok_or_else(|| format!("X-Intermittent-Hang-Ms must be an integer between 0 and {}", MAX_HANG_MS))?;
+    Ok(Some((probability, Duration::from_millis(hang_ms))))
+}
+
+// Response body that stalls for `hang` once `offset` bytes have been sent,
+// or after the first chunk when the offset is None
+fn hang_at(body: Body, offset: Option<u64>, hang: Duration) -> Body {
+    let data = body.into_data_stream();
+    let state = (data, None::<Bytes>, offset, Some(hang));
+    Body::from_stream(futures_util::stream::unfold(state, |(mut data, pending, remaining, hang)| async move {
+        let mut hang = hang;
+        if remaining == Some(0) {
+            if let Some(hang) = hang.take() {
+                tokio::time::sleep(hang).await;
+            }
+        }
+        let mut chunk = match pending {
+            Some(chunk) => chunk,
+            None => match data.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => return Some((Err(e), (data, None, None, None))),
+                None => return None,
+            },
+        };
+        let (pending, remaining) = match (hang, remaining) {
+            (None, _) => (None, None),
+            (Some(_), None) => (None, Some(0)),
+            (Some(_), Some(r)) if chunk.len() as u64 > r => (Some(chunk.split_off(r as usize)), Some(0)),
+            (Some(_), Some(r)) => (None, Some(r - chunk.len() as u64)),
+        };
+        Some((Ok(chunk), (data, pending, remaining, hang)))
+    }))
+}
+
+// Model a lossy network: with `X-Intermittent-Hang` and
+// `X-Intermittent-Hang-Ms`, the response stalls for the hang duration with
+// that probability, at a random point before it completes. The point is
+// either before the response head or after a random number of body bytes;
+// for bodies of unknown length, either before the head or after the first
+// chunk. The hang is reported in `X-Hang-Ms` (0 when none occurred) and
+// `X-Hang-At-Byte`.
+pub async fn intermittent_hang(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (probability, hang) = match parse_intermittent_hang(request.headers()) {
+        Ok(Some(intermittent_hang)) if state.chaos_active() => intermittent_hang,
+        Ok(_) => return next.run(request).await,
+        Err(details) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid intermittent hang headers",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+    let connection = request.extensions().get::<ConnectionState>().cloned();
+
+    let mut response = next.run(request).await;
+
+    let hang = if random::chance(probability) {
+        charge_delay_budget(&state, connection.as_ref(), hang)
+    } else {
+        Duration::ZERO
+    };
+    if hang.is_zero() {
+        response.headers_mut().insert("X-Hang-Ms", HeaderValue::from(0));
+        return response;
+    }
+    if let Some(fault_log) = fault_log {
+        fault_log.record("intermittent_hang", hang.as_millis());
+    }
+    response.headers_mut().insert("X-Hang-Ms", HeaderValue::from(hang.as_millis() as u64));
+
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    // Byte after which the response stalls: 0 is before the head, and the
+    // last byte is never reached as the client would already be done
+    let offset = match length {
+        Some(0) => Some(0),
+        Some(length) => Some(random::up_to(length - 1)),
+        None if random::coin() => Some(0),
+        None => None,
+    };
+    if let Some(offset) = offset {
+        response.headers_mut().insert("X-Hang-At-Byte", HeaderValue::from(offset));
+    }
+
+    if offset == Some(0) {
+        tokio::time::sleep(hang).await;
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, hang_at(body, offset, hang))
+}
+
+// Response body that sends `limit` bytes, or its first chunk when the limit
+// is unknown, and then fails
+fn truncate_with_error(body: Body, limit: Option<u64>) -> Body {
+    let data = body.into_data_stream();
+    Body::from_stream(futures_util::stream::unfold(Some((data, limit)), |state| async move {
+        let (mut data, remaining) = state?;
+        if remaining == Some(0) {
+            // Give hyper a chance to flush what was sent so far, so the
+            // client receives the head and partial body before the failure
+            tokio::task::yield_now().await;
+            let error = axum::Error::new("simulated failure while writing the response");
+            return Some((Err(error), None));
+        }
+        match data.next().await {
+            Some(Ok(mut chunk)) => {
+                let sent = remaining.map_or(chunk.len(), |r| chunk.len().min(r as usize));
+                chunk.truncate(sent);
+                let remaining = remaining.map_or(0, |r| r - sent as u64);
+                Some((Ok(chunk), Some((data, Some(remaining)))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => None,
+        }
+    }))
+}
+
+// With `X-Fail-On-Write: <probability>`, a successful response fails partway
+// through writing its body: half the body is sent and then the stream errors,
+// so the client sees the connection drop mid-response instead of an error
+// status. Bodies of unknown length fail after their first chunk.
+pub async fn fail_on_write(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let probability = match request.headers().get("X-Fail-On-Write") {
+        None => return next.run(request).await,
+        Some(h) => match h.to_str().ok().and_then(|s| s.trim().parse::<f64>().ok()) {
+            Some(probability) if (0.0..=1.0).contains(&probability) => probability,
+            _ => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid X-Fail-On-Write",
+                        "details": "X-Fail-On-Write must be a probability between 0.0 and 1.0"
+                    }))
+                ).into_response();
+            }
+        },
+    };
+    let outcome = request.extensions().get::<RequestOutcome>().cloned();
+    let fault_log = request.extensions().get::<FaultLog>().cloned();
+
+    let response = next.run(request).await;
+
+    let chaos_enabled = state.chaos_active();
+    if !chaos_enabled || !response.status().is_success() || !random::chance(probability) {
+        return response;
+    }
+
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    if length == Some(0) {
+        return response;
+    }
+    let limit = length.map(|length| length / 2);
+
+    if let Some(outcome) = outcome {
+        outcome.mark(Outcome::SimulatedFailure);
+    }
+    if let Some(fault_log) = fault_log {
+        match limit {
+            Some(limit) => fault_log.record("write_failure", limit),
+            None => fault_log.record("write_failure", "first_chunk"),
+        }
+    }
+    // Keep announcing the full length, so clients can tell the body is short
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = length {
+        parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    Response::from_parts(parts, truncate_with_error(body, limit))
+}
+
+// With `X-Randomize-Header-Case: true`, send the response header names with
+// random casing (`content-TYPE`) to exercise clients that wrongly treat
+// header names as case-sensitive. Framing headers keep their normal casing,
+// and HTTP/2 responses are always lowercase.
+pub async fn randomize_header_case(request: Request<Body>, next: Next) -> Response {
+    let enabled = request
+        .headers()
+        .get("X-Randomize-Header-Case")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    if !enabled {
+        return next.run(request).await;
+    }
+
+    let mut response = next.run(request).await;
+
+    // One entry per value, so duplicated headers are each cased independently
+    let names: Vec<String> = response
+        .headers()
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !FRAMING_HEADERS.contains(name))
+        .map(random_case)
+        .collect();
+    match header_case_extensions(&names).await {
+        Some(extensions) => response.extensions_mut().extend(extensions),
+        None => tracing::warn!("Failed to build header case map, sending normal casing"),
+    }
+
+    response
+}
+
+// Request body that fails with `proxy::RequestBodyTimeout` once `deadline`
+// passes, for streamed requests that are not buffered up front
+struct DeadlineBody {
+    inner: Body,
+    timeout: Duration,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl HttpBody for DeadlineBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+        ready!(self.deadline.as_mut().poll(cx));
+        Poll::Ready(Some(Err(axum::Error::new(proxy::RequestBodyTimeout(self.timeout)))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Read the request body up front to enforce CLIENT_REQUEST_TIMEOUT_MS and
+// UPSTREAM_MAX_REQUEST_BYTES.
+//
+// With the timeout set, the body is buffered and clients that have not sent
+// all of it in time get `408 Request Timeout` (closing the connection), so
+// slow-loris clients cannot hold a handler open. The clock starts once the
+// request headers have been received. Streamed requests (`X-Stream-Request`)
+// are not buffered; their body fails once the time is up, which aborts the
+// upstream call and answers 408 the same way.
+//
+// With the size limit set, requests bound for the upstream are rejected with
+// 413 as soon as they are known to be too large: from `Content-Length` before
+// any of the body is read, or otherwise as soon as the bytes read cross the
+// limit, so at most the limit is ever held in memory. Streamed requests
+// (`X-Stream-Request`) are not buffered here; the limit travels with the body
+// and the upstream call is aborted when it is crossed.
+pub async fn read_request_body(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (timeout, max_bytes) = {
+        let config = state.config.read().unwrap();
+        (config.client_request_timeout, config.upstream_max_request_bytes)
+    };
+    let max_bytes = max_bytes.filter(|_| !metrics::is_operational(request.uri().path()));
+    if timeout.is_none() && max_bytes.is_none() {
+        return next.run(request).await;
+    }
+
+    if let Some(limit) = max_bytes {
+        let declared = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<u64>().ok());
+        if declared.is_some_and(|declared| declared > limit as u64) {
+            return proxy::request_too_large(limit).into_response();
+        }
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match max_bytes {
+        Some(limit) => Body::new(Limited::new(body, limit)),
+        None => body,
+    };
+    let limited = max_bytes.is_some();
+
+    if proxy::stream_requested(&parts.headers) {
+        let body = match timeout {
+            Some(timeout) => Body::new(DeadlineBody {
+                inner: body,
+                timeout,
+                deadline: Box::pin(tokio::time::sleep(timeout)),
+            }),
+            None => body,
+        };
+        return run_with_body_limit(next, Request::from_parts(parts, body), limited).await;
+    }
+
+    let read = axum::body::to_bytes(body, usize::MAX);
+    let read = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, read).await {
+            Ok(read) => read,
+            Err(_) => {
+                let (status, body) = proxy::request_timeout(timeout);
+                return (status, [(header::CONNECTION, "close")], body).into_response();
+            }
+        },
+        None => read.await,
+    };
+    let bytes = match read {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            if let Some(limit) = max_bytes.filter(|_| proxy::exceeds_body_limit(&e)) {
+                return proxy::request_too_large(limit).into_response();
+            }
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Failed to read request body",
+                    "details": e.to_string()
+                }))
+            ).into_response();
+        }
+    };
+
+    run_with_body_limit(next, Request::from_parts(parts, Body::from(bytes)), limited).await
+}
+
+// Run the rest of the stack. Once UPSTREAM_MAX_REQUEST_BYTES has been
+// enforced, it replaces axum's default 2 MB cap on buffered bodies.
+async fn run_with_body_limit(next: Next, request: Request<Body>, limited: bool) -> Response {
+    if !limited {
+        return next.run(request).await;
+    }
+    DefaultBodyLimit::disable()
+        .layer(next)
+        .oneshot(request)
+        .await
+        .unwrap_or_else(|e| match e {})
+}
+
+// Reject requests with more than MAX_HEADER_COUNT headers, or whose header
+// names and values add up to more than MAX_HEADER_BYTES, with `431 Request
+// Header Fields Too Large`. Repeated headers count once per value.
+pub async fn header_limits(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (max_count, max_bytes) = {
+        let config = state.config.read().unwrap();
+        (config.max_header_count, config.max_header_bytes)
+    };
+    let headers = request.headers();
+    let count = headers.len();
+    let bytes: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+
+    let exceeded = if count > max_count {
+        Some(("MAX_HEADER_COUNT", max_count, count, "headers"))
+    } else if bytes > max_bytes {
+        Some(("MAX_HEADER_BYTES", max_bytes, bytes, "bytes of headers"))
+    } else {
+        None
+    };
+    if let Some((limit, max, actual, unit)) = exceeded {
+        return (
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Json(json!({
+                "error": "Request header fields too large",
+                "details": format!("Request has {} {}, more than the {} limit of {}", actual, unit, limit, max),
+                "limit": limit,
+                "max": max,
+                "actual": actual
+            }))
+        ).into_response();
+    }
+
+    next.run(request).await
+}
+
+// With EMIT_HANDLER_HEADER, report the handler that served the request in
+// `X-Handled-By`, so requests passing through several proxies can be traced
+// to the endpoint that actually answered them
+pub async fn handled_by(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !state.config.read().unwrap().emit_handler_header {
+        return next.run(request).await;
+    }
+
+    let handler = metrics::handler_name(&request);
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&handler) {
+        response.headers_mut().insert("X-Handled-By", value);
+    }
+    response
+}
+
+// Default and maximum time `X-Continue-Behavior: withhold` holds a request
+const DEFAULT_CONTINUE_WITHHOLD_MS: u64 = 5000;
+const MAX_CONTINUE_WITHHOLD_MS: u64 = 60_000;
+
+// What to do with a request carrying `Expect: 100-continue`
+enum ContinueBehavior {
+    Send,
+    Withhold(Duration),
+    Reject,
+}
+
+fn parse_continue_behavior(headers: &HeaderMap) -> Result<ContinueBehavior, String> {
+    let behavior = match headers.get("X-Continue-Behavior") {
+        None => return Ok(ContinueBehavior::Send),
+        Some(h) => h.to_str().unwrap_or_default(),
+    };
+    match behavior {
+        "send" => Ok(ContinueBehavior::Send),
+        "reject-417" => Ok(ContinueBehavior::Reject),
+        "withhold" => {
+            let ms = match headers.get("X-Continue-Withhold-Ms") {
+                None => DEFAULT_CONTINUE_WITHHOLD_MS,
+                Some(h) => h
+                    .to_str()
+                    .ok()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .filter(|&ms| ms <= MAX_CONTINUE_WITHHOLD_MS)
+                    .ok_or_else(|| format!(
+                        "X-Continue-Withhold-Ms must be an integer between 0 and {}",
+                        MAX_CONTINUE_WITHHOLD_MS
+                    ))?,
+            };
+            Ok(ContinueBehavior::Withhold(Duration::from_millis(ms)))
+        }
+        _ => Err("X-Continue-Behavior must be send, withhold or reject-417".to_string()),
+    }
+}
+
+// Control the answer to `Expect: 100-continue` with `X-Continue-Behavior`.
+// hyper writes `100 Continue` the first time the request body is read, so the
+// behaviors come down to when the body is first read:
+// - `send` (the default) reads it as usual, so the 100 goes out at once
+// - `withhold` holds the request for `X-Continue-Withhold-Ms` before anything
+//   reads the body. Clients that wait for the 100 stall until then; clients
+//   with an expect timeout send the body without it.
+// - `reject-417` answers `417 Expectation Failed` without reading the body
+// Requests without the expectation, and every request with
+// CHAOS_ENABLED=false, are handled as usual.
+pub async fn expect_continue(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let expects_continue = request
+        .headers()
+        .get(header::EXPECT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|s| s.eq_ignore_ascii_case("100-continue"));
+    if !expects_continue || !state.chaos_active() {
+        return next.run(request).await;
+    }
+
+    match parse_continue_behavior(request.headers()) {
+        Ok(ContinueBehavior::Send) => next.run(request).await,
+        Ok(ContinueBehavior::Withhold(duration)) => {
+            let duration = charge_delay_budget(&state, request.extensions().get(), duration);
+            tokio::time::sleep(duration).await;
+            if let Some(timing) = request.extensions().get::<Timing>() {
+                timing.record_delay(duration);
+            }
+            next.run(request).await
+        }
+        Ok(ContinueBehavior::Reject) => (
+            StatusCode::EXPECTATION_FAILED,
+            Json(json!({
+                "error": "Expectation failed",
+                "details": "100-continue rejected by X-Continue-Behavior"
+            }))
+        ).into_response(),
+        Err(details) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Continue-Behavior",
+                "details": details
+            }))
+        ).into_response(),
+    }
+}
+
+// With COLD_START_DELAY_MS set, hold the first request after startup (or
+// after /admin/reset) for that long before handling it, like a serverless
+// cold start. Only the request that clears the flag pays; every response
+// reports whether it did in `X-Cold-Start`. Probes, metrics and admin calls
+// neither pay nor clear it.
+pub async fn cold_start(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (delay, chaos_enabled) = {
+        let config = state.config.read().unwrap();
+        (config.cold_start_delay, config.chaos_enabled && !state.fault_window_ended(&config))
+    };
+    let Some(delay) = delay.filter(|_| chaos_enabled) else {
+        return next.run(request).await;
+    };
+    if metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let applied = state.cold_start_pending.swap(false, Ordering::Relaxed);
+    if applied {
+        tokio::time::sleep(delay).await;
+    }
+    let mut response = next.run(request).await;
+    response.headers_mut().insert("X-Cold-Start", HeaderValue::from_static(if applied { "true" } else { "false" }));
+    response
+}
+
+// With FAULT_ACTIVE_WINDOW_SECS set, report in `X-Fault-Window` whether the
+// window is still `active` or has `ended`. Probes, metrics and admin calls
+// are not marked.
+pub async fn fault_window(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let window = state.config.read().unwrap().fault_active_window;
+    let Some(window) = window else {
+        return next.run(request).await;
+    };
+    if metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let active = state.started.elapsed() < window;
+    let mut response = next.run(request).await;
+    response.headers_mut().insert("X-Fault-Window", HeaderValue::from_static(if active { "active" } else { "ended" }));
+    response
+}
+
+// Parse `X-Drop-Response-Headers`, a comma-separated list of header names
+fn parse_drop_headers(headers: &HeaderMap) -> Result<Option<Vec<HeaderName>>, String> {
+    let Some(h) = headers.get("X-Drop-Response-Headers") else {
+        return Ok(None);
+    };
+    let list = h.to_str().map_err(|e| e.to_string())?;
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("{} is not a valid header name", name)))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// With `X-Drop-Response-Headers`, remove the listed headers from the response
+// once everything else has built it, to test clients that expect them.
+// Framing headers are refused unless `X-Force-Drop: true` is also sent.
+// Without Content-Length the body is re-sent without a known length, so it
+// goes out chunked.
+pub async fn drop_response_headers(request: Request<Body>, next: Next) -> Response {
+    let names = match parse_drop_headers(request.headers()) {
+        Ok(Some(names)) => names,
+        Ok(None) => return next.run(request).await,
+        Err(details) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Drop-Response-Headers",
+                    "details": details
+                }))
+            ).into_response();
+        }
+    };
+    if metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let framing: Vec<&str> = names
+        .iter()
+        .map(HeaderName::as_str)
+        .filter(|name| FRAMING_HEADERS.contains(name))
+        .collect();
+    if !framing.is_empty() {
+        let forced = request
+            .headers()
+            .get("X-Force-Drop")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.parse::<bool>().ok())
+            .unwrap_or(false);
+        if !forced {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Refusing to drop framing headers",
+                    "details": format!(
+                        "Dropping {} changes how the response is framed and may break the connection; send X-Force-Drop: true to drop them anyway",
+                        framing.join(", ")
+                    )
+                }))
+            ).into_response();
+        }
+        tracing::warn!("Dropping framing headers {} from the response (X-Force-Drop), the connection may break", framing.join(", "));
+    }
+
+    let mut response = next.run(request).await;
+    for name in &names {
+        response.headers_mut().remove(name);
+    }
+    if names.contains(&header::CONTENT_LENGTH) {
+        let (parts, body) = response.into_parts();
+        response = Response::from_parts(parts, Body::from_stream(body.into_data_stream()));
+    }
+    response
+}
+
+// Remove the STRIP_RESPONSE_HEADERS names from proxied responses, including
+// headers added by the proxy's own layers
+pub async fn strip_response_headers(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let names = state.config.read().unwrap().strip_response_headers.clone();
+    if names.is_empty() || metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let mut response = next.run(request).await;
+    for name in &names {
+        response.headers_mut().remove(name);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::{get, post}, Router};
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder().uri(path).body(Body::empty()).unwrap()
+    }
+
+    // Body sent as `chunks`, waiting `pause` before each one
+    fn paced_body(chunks: &'static [&'static str], pause: Duration) -> Body {
+        Body::from_stream(futures_util::stream::iter(chunks).then(move |chunk| async move {
+            tokio::time::sleep(pause).await;
+            Ok::<_, Infallible>(Bytes::from_static(chunk.as_bytes()))
+        }))
+    }
+
+    // POST /upload echoing the body, behind `read_request_body` with
+    // CLIENT_REQUEST_TIMEOUT_MS set to `timeout`
+    fn upload_app(timeout: Duration) -> Router {
+        let state = crate::test_state(|config| config.client_request_timeout = Some(timeout));
+        Router::new()
+            .route("/upload", post(|body: Bytes| async move { body }))
+            .layer(axum::middleware::from_fn_with_state(state, read_request_body))
+    }
+
+    fn upload(body: Body) -> Request<Body> {
+        Request::builder().method("POST").uri("/upload").body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_request_body_times_out() {
+        let app = upload_app(Duration::from_millis(100));
+
+        let body = paced_body(&["first", "second", "third"], Duration::from_millis(60));
+        let response = app.oneshot(upload(body)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.headers()[header::CONNECTION], "close");
+    }
+
+    #[tokio::test]
+    async fn fast_request_body_passes() {
+        let app = upload_app(Duration::from_millis(500));
+
+        let body = paced_body(&["first", "second", "third"], Duration::from_millis(10));
+        let response = app.oneshot(upload(body)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "firstsecondthird");
+    }
+
+    // POST /upload/stream answering with the first chunk of the body and
+    // 408 once a read fails with `RequestBodyTimeout`, behind
+    // `read_request_body` with CLIENT_REQUEST_TIMEOUT_MS set to `timeout`
+    fn streaming_upload_app(timeout: Duration, read_all: bool) -> Router {
+        let state = crate::test_state(|config| config.client_request_timeout = Some(timeout));
+        Router::new()
+            .route("/upload/stream", post(move |body: Body| async move {
+                let mut stream = body.into_data_stream();
+                let mut received = BytesMut::new();
+                while let Some(chunk) = stream.next().await {
+                    match chunk {
+                        Ok(chunk) => received.extend_from_slice(&chunk),
+                        Err(e) => {
+                            let timed_out = proxy::find_cause::<proxy::RequestBodyTimeout>(&e).is_some();
+                            assert!(timed_out, "unexpected body error: {}", e);
+                            return proxy::request_timeout(timeout).into_response();
+                        }
+                    }
+                    if !read_all {
+                        break;
+                    }
+                }
+                received.freeze().into_response()
+            }))
+            .layer(axum::middleware::from_fn_with_state(state, read_request_body))
+    }
+
+    fn streamed_upload(body: Body) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/upload/stream")
+            .header("X-Stream-Request", "true")
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn streamed_request_body_is_not_buffered() {
+        let app = streaming_upload_app(Duration::from_secs(5), false);
+
+        // A buffered body would reach the handler as one chunk
+        let body = paced_body(&["first", "second"], Duration::from_millis(20));
+        let response = app.oneshot(streamed_upload(body)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "first");
+    }
+
+    #[tokio::test]
+    async fn slow_streamed_request_body_times_out() {
+        let app = streaming_upload_app(Duration::from_millis(100), true);
+
+        let body = paced_body(&["first", "second", "third"], Duration::from_millis(60));
+        let response = app.oneshot(streamed_upload(body)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    // GET /headers behind `header_limits` with the given MAX_HEADER_COUNT
+    // and MAX_HEADER_BYTES
+    async fn header_limits_status(max_count: usize, max_bytes: usize, headers: Vec<(String, String)>) -> StatusCode {
+        let state = crate::test_state(|config| {
+            config.max_header_count = max_count;
+            config.max_header_bytes = max_bytes;
+        });
+        let app = Router::new()
+            .route("/headers", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state, header_limits));
+
+        let mut request = request("/headers");
+        for (name, value) in headers {
+            request.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(&value).unwrap(),
+            );
+        }
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    fn small_headers(count: usize) -> Vec<(String, String)> {
+        (0..count).map(|i| (format!("x-h{}", i), "1".to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn many_small_headers_hit_max_header_count() {
+        assert_eq!(header_limits_status(20, 64 * 1024, small_headers(20)).await, StatusCode::OK);
+        assert_eq!(
+            header_limits_status(20, 64 * 1024, small_headers(21)).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn few_large_headers_hit_max_header_bytes() {
+        // Each header is 4 bytes of name and 996 of value
+        let large = |count: usize, extra: usize| -> Vec<(String, String)> {
+            (0..count)
+                .map(|i| (format!("x-l{}", i), "v".repeat(996 + if i == 0 { extra } else { 0 })))
+                .collect()
+        };
+
+        assert_eq!(header_limits_status(100, 3000, large(3, 0)).await, StatusCode::OK);
+        assert_eq!(
+            header_limits_status(100, 3000, large(3, 1)).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn strip_response_headers_removes_only_listed_names() {
+        let state = crate::test_state(|config| {
+            config.strip_response_headers = crate::proxy::parse_header_names("X-Internal-Trace, server").unwrap();
+        });
+        let app = Router::new()
+            .route("/resource", get(|| async {
+                (
+                    [("x-internal-trace", "abc"), ("Server", "upstream/1.0"), ("X-Request-Cost", "3")],
+                    "ok",
+                )
+            }))
+            .layer(axum::middleware::from_fn_with_state(state, strip_response_headers));
+
+        let response = app.oneshot(request("/resource")).await.unwrap();
+
+        // Names match whatever case they were configured or sent in
+        assert!(!response.headers().contains_key("X-Internal-Trace"));
+        assert!(!response.headers().contains_key("server"));
+        assert_eq!(response.headers()["x-request-cost"], "3");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+    }
+
+    // Status and body length of GET /body behind `amplify_response`, where
+    // the body is `len` bytes
+    async fn amplified(len: usize, factor: usize) -> (StatusCode, usize) {
+        let app = Router::new()
+            .route("/body", get(move || async move { "x".repeat(len) }))
+            .layer(axum::middleware::from_fn(amplify_response));
+        let mut request = request("/body");
+        request.headers_mut().insert("X-Amplify-Response", HeaderValue::from(factor));
+
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, body.len())
+    }
+
+    #[tokio::test]
+    async fn amplified_responses_are_capped() {
+        let largest = MAX_AMPLIFY_BYTES / MAX_AMPLIFY_FACTOR;
+        assert_eq!(amplified(largest, MAX_AMPLIFY_FACTOR).await, (StatusCode::OK, largest * MAX_AMPLIFY_FACTOR));
+        assert_eq!(amplified(largest + 1, MAX_AMPLIFY_FACTOR).await.0, StatusCode::BAD_GATEWAY);
+        // The same body can still be amplified fewer times
+        assert_eq!(amplified(largest + 1, 2).await, (StatusCode::OK, (largest + 1) * 2));
+    }
+}
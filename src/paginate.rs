@@ -0,0 +1,170 @@
+use axum::{
+    extract::{Query, State},
+    http::{Extensions, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+use crate::config;
+use crate::fault::FaultContext;
+use crate::metrics::Outcome;
+use crate::{random, SharedState};
+
+const DEFAULT_PAGE_SIZE: u64 = 10;
+const MAX_PAGE_SIZE: u64 = 1000;
+const DEFAULT_TOTAL: u64 = 100;
+const MAX_TOTAL: u64 = 1_000_000;
+
+// Parameters carried over into the `next` and `prev` links
+const LINK_PARAMS: [&str; 5] = ["page_size", "total", "fail_page", "fail_status", "fail_rate"];
+
+// Paging parameters from the query string
+struct Paging {
+    page_size: u64,
+    total: u64,
+    offset: u64,
+    // `fail_page`, `fail_status` and `fail_rate`: the page that fails, how,
+    // and how often
+    failure: Option<(u64, StatusCode, f64)>,
+}
+
+fn invalid(details: String) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Invalid pagination parameters",
+            "details": details
+        }))
+    ).into_response()
+}
+
+// Cursors are opaque to clients: the offset of the page, base64url-encoded
+fn encode_cursor(offset: u64) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(format!("offset:{}", offset))
+}
+
+fn decode_cursor(cursor: &str) -> Option<u64> {
+    let decoded = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    String::from_utf8(decoded).ok()?.strip_prefix("offset:")?.parse().ok()
+}
+
+fn parse_paging(query: &HashMap<String, String>) -> Result<Paging, String> {
+    let number = |name: &str, default: u64, min: u64, max: u64| match query.get(name) {
+        None => Ok(default),
+        Some(value) => value
+            .parse::<u64>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("{} must be an integer between {} and {}", name, min, max)),
+    };
+    let page_size = number("page_size", DEFAULT_PAGE_SIZE, 1, MAX_PAGE_SIZE)?;
+    let total = number("total", DEFAULT_TOTAL, 0, MAX_TOTAL)?;
+
+    let offset = match query.get("cursor") {
+        None => 0,
+        Some(cursor) => decode_cursor(cursor)
+            .filter(|&offset| offset < total.max(1) && offset % page_size == 0)
+            .ok_or("cursor is not valid for this page_size and total")?,
+    };
+
+    let failure = match query.get("fail_page") {
+        None => None,
+        Some(_) => {
+            let page = number("fail_page", 1, 1, u64::MAX)?;
+            let status = match query.get("fail_status") {
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+                Some(value) => config::parse_status_code(value).map_err(|e| format!("fail_status: {}", e))?,
+            };
+            let rate = match query.get("fail_rate") {
+                None => 1.0,
+                Some(value) => value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|rate| (0.0..=1.0).contains(rate))
+                    .ok_or("fail_rate must be between 0.0 and 1.0")?,
+            };
+            Some((page, status, rate))
+        }
+    };
+
+    Ok(Paging { page_size, total, offset, failure })
+}
+
+// Link to the page at `offset`, keeping the other parameters. They have all
+// been validated as numbers, so they need no escaping.
+fn page_link(query: &HashMap<String, String>, offset: u64) -> String {
+    let mut params: Vec<String> = LINK_PARAMS
+        .iter()
+        .filter_map(|name| query.get(*name).map(|value| format!("{}={}", name, value)))
+        .collect();
+    params.push(format!("cursor={}", encode_cursor(offset)));
+    format!("/paginate?{}", params.join("&"))
+}
+
+// Serve one page of `total` synthetic items, `page_size` at a time, with
+// cursors and links to the neighbouring pages. The last page has no
+// `next_cursor`. The delay faults run before each page, and `fail_page`
+// fails that page to test recovery mid-walk.
+pub async fn paginate_handler(
+    State(state): State<SharedState>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    headers: HeaderMap,
+    extensions: Extensions,
+) -> Response {
+    let paging = match parse_paging(&query) {
+        Ok(paging) => paging,
+        Err(details) => return invalid(details),
+    };
+
+    let mut ctx = FaultContext::without_body(&state, method, headers, &extensions, "/paginate".to_string());
+    if let ControlFlow::Break(response) = state.paginate_faults.apply(&mut ctx).await {
+        return response;
+    }
+
+    let page = paging.offset / paging.page_size + 1;
+    let pages = paging.total.div_ceil(paging.page_size).max(1);
+    if let Some((fail_page, status, rate)) = paging.failure {
+        if fail_page == page && ctx.config.chaos_enabled && random::chance(rate) {
+            ctx.outcome.mark(Outcome::SimulatedFailure);
+            ctx.fault_log.record("failure", status.as_u16());
+            return (
+                status,
+                Json(json!({
+                    "error": "Simulated failure",
+                    "status_code": status.as_u16(),
+                    "page": page
+                }))
+            ).into_response();
+        }
+    }
+
+    let end = (paging.offset + paging.page_size).min(paging.total);
+    let items: Vec<Value> = (paging.offset..end)
+        .map(|index| json!({
+            "id": index + 1,
+            "name": format!("item-{}", index + 1)
+        }))
+        .collect();
+    let next = Some(end).filter(|&end| end < paging.total);
+    let prev = paging.offset.checked_sub(paging.page_size);
+
+    let mut body = json!({
+        "items": items,
+        "page": page,
+        "pages": pages,
+        "page_size": paging.page_size,
+        "total": paging.total,
+        "next_cursor": next.map(encode_cursor),
+        "prev_cursor": prev.map(encode_cursor),
+        "next": next.map(|offset| page_link(&query, offset)),
+        "prev": prev.map(|offset| page_link(&query, offset))
+    });
+    for (key, value) in ctx.report {
+        body[key] = value;
+    }
+    Json(body).into_response()
+}
@@ -0,0 +1,690 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, State},
+    http::{header, request, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri},
+    response::{IntoResponse, Json},
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use http_body_util::{Full, LengthLimitError};
+use hyper::ext::ReasonPhrase;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use crate::accel;
+use crate::body_hash::{BodyHashCheck, Mismatch};
+use crate::capture;
+use crate::config::Config;
+use crate::request_id::RequestId;
+use crate::timing::Timing;
+use crate::upstream_auth;
+use crate::{
+    clone_request, collect_upstream, compression, forward_error_response, retry, send_upstream, smuggle, vhost,
+    SharedState, UpstreamResponse,
+};
+
+// Headers that describe a single connection rather than the request, and the
+// framing headers hyper computes for the upstream request itself. These are
+// never forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+// Parse a comma-separated list of header names. Names are case-insensitive
+// and stored lowercase.
+pub fn parse_header_names(value: &str) -> Result<Vec<HeaderName>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name: {}", name)))
+        .collect()
+}
+
+// Parse `FORWARD_HEADERS`, the request headers copied to the upstream
+pub fn parse_forward_headers(value: &str) -> Result<Vec<HeaderName>, String> {
+    let names = parse_header_names(value)?;
+    if let Some(name) = names.iter().find(|name| HOP_BY_HOP.contains(&name.as_str())) {
+        return Err(format!("{} is hop-by-hop and is never forwarded", name));
+    }
+    Ok(names)
+}
+
+// Copy the client headers allowed by FORWARD_HEADERS onto the upstream
+// request. Headers the proxy already set take precedence, and hop-by-hop
+// headers, including any named by the client's `Connection` header, are
+// skipped.
+pub fn forward_allowed_headers(allowed: &[HeaderName], from: &HeaderMap, mut builder: request::Builder) -> request::Builder {
+    let connection_scoped: Vec<String> = from
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    if let Some(headers) = builder.headers_mut() {
+        for name in allowed {
+            if headers.contains_key(name) || connection_scoped.iter().any(|scoped| scoped == name.as_str()) {
+                continue;
+            }
+            for value in from.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+    builder
+}
+
+// Remove the STRIP_REQUEST_HEADERS names from the upstream request, whoever
+// set them
+pub fn strip_request_headers(names: &[HeaderName], mut builder: request::Builder) -> request::Builder {
+    if let Some(headers) = builder.headers_mut() {
+        for name in names {
+            headers.remove(name);
+        }
+    }
+    builder
+}
+
+// Rewrites matching request paths before they are appended to the target URL.
+// Capture groups are substituted into the template as `$1`, `${1}` or
+// `${name}`.
+#[derive(Clone)]
+pub struct RouteRule {
+    pattern: Regex,
+    rewrite_template: String,
+}
+
+impl RouteRule {
+    // Parse `ROUTE_REGEX`, a JSON array of `{"pattern", "rewrite_template"}`
+    // objects. Patterns are compiled here so invalid ones fail at startup.
+    pub fn parse_list(value: &str) -> Result<Vec<RouteRule>, String> {
+        let entries: Vec<Value> = serde_json::from_str(value)
+            .map_err(|e| format!("expected a JSON array: {}", e))?;
+
+        entries
+            .iter()
+            .map(|entry| {
+                let pattern = entry
+                    .get("pattern")
+                    .and_then(Value::as_str)
+                    .ok_or("each rule needs a string \"pattern\"")?;
+                let rewrite_template = entry
+                    .get("rewrite_template")
+                    .and_then(Value::as_str)
+                    .ok_or("each rule needs a string \"rewrite_template\"")?;
+                let pattern = Regex::new(pattern)
+                    .map_err(|e| format!("invalid pattern {}: {}", pattern, e))?;
+
+                Ok(RouteRule {
+                    pattern,
+                    rewrite_template: rewrite_template.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    // Parse `X-Path-Rewrite: <pattern>=<rewrite_template>`, a rule for one
+    // request. The pattern ends at the first `=`.
+    pub fn from_header(headers: &HeaderMap) -> Result<Option<RouteRule>, (StatusCode, Json<Value>)> {
+        let Some(h) = headers.get("X-Path-Rewrite") else {
+            return Ok(None);
+        };
+        let rule = h
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(|value| value.split_once('=').ok_or_else(|| "expected <pattern>=<rewrite_template>".to_string()))
+            .and_then(|(pattern, rewrite_template)| {
+                let pattern = Regex::new(pattern)
+                    .map_err(|e| format!("invalid pattern {}: {}", pattern, e))?;
+                Ok(RouteRule { pattern, rewrite_template: rewrite_template.to_string() })
+            });
+        match rule {
+            Ok(rule) => Ok(Some(rule)),
+            Err(details) => Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "Invalid X-Path-Rewrite",
+                    "details": details
+                }))
+            )),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "pattern": self.pattern.as_str(),
+            "rewrite_template": self.rewrite_template
+        })
+    }
+}
+
+// Resolve the upstream path and query for an incoming request. The first rule
+// whose pattern matches the path wins; unmatched paths pass through as-is.
+// The incoming query string is always carried over.
+pub fn rewrite_path(rules: &[RouteRule], uri: &Uri) -> String {
+    let path = uri.path();
+    let rewritten = rules.iter().find_map(|rule| {
+        rule.pattern.captures(path).map(|captures| {
+            let mut expanded = String::new();
+            captures.expand(&rule.rewrite_template, &mut expanded);
+            expanded
+        })
+    });
+
+    let mut path_and_query = rewritten.unwrap_or_else(|| path.to_string());
+    if let Some(query) = uri.query() {
+        path_and_query.push(if path_and_query.contains('?') { '&' } else { '?' });
+        path_and_query.push_str(query);
+    }
+    path_and_query
+}
+
+// Generic proxy route: forwards any request that doesn't match a dedicated
+// endpoint to the target URL with the request path appended, and returns the
+// upstream response unchanged. Paths listed in ROTATING_BODIES are answered
+// with their next body instead, without contacting the upstream. With
+// `X-Stream-Request: true` the request body is piped to the upstream as it
+// arrives instead of being buffered first.
+pub async fn proxy_handler(
+    State(state): State<SharedState>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    request: Request<Body>,
+) -> Response<Body> {
+    let config = state.config.read().unwrap().clone();
+    let max_retries = match retry::max_retries(&config, &headers) {
+        Ok(max_retries) => max_retries,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let hash_check = match BodyHashCheck::from_headers(&headers) {
+        Ok(check) => check,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let capture_dir = match capture::capture_dir(&config, &headers) {
+        Ok(dir) => dir,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if let Some(bodies) = config.rotating_bodies.get(uri.path()) {
+        if let Some(index) = state.rotation.next(uri.path(), bodies.len()) {
+            let mut response = Json(bodies[index].clone()).into_response();
+            response
+                .headers_mut()
+                .insert("X-Rotation-Index", HeaderValue::from(index));
+            return response;
+        }
+    }
+
+    let (base_url, _) = vhost::resolve_target(&config, &headers);
+    let base_url = match vhost::upstream_scheme(&headers) {
+        Ok(Some(scheme)) => vhost::with_scheme(&base_url, scheme),
+        Ok(None) => base_url,
+        Err(rejection) => return rejection.into_response(),
+    };
+    // `X-Path-Rewrite` replaces the ROUTE_REGEX rules for this request
+    let path_and_query = match RouteRule::from_header(&headers) {
+        Ok(Some(rule)) => rewrite_path(&[rule], &uri),
+        Ok(None) => rewrite_path(&config.route_rules, &uri),
+        Err(rejection) => return rejection.into_response(),
+    };
+    let target_url = format!("{}{}", base_url.trim_end_matches('/'), path_and_query);
+
+    if let Some(mode) = headers.get("X-Smuggle-Mode").and_then(|h| h.to_str().ok()) {
+        let body = match Bytes::from_request(request, &state).await {
+            Ok(body) => body,
+            Err(rejection) => return rejection.into_response(),
+        };
+        return smuggle::send(&config, mode, &method, &headers, &target_url, body)
+            .await
+            .into_response();
+    }
+
+    let mut builder = Request::builder().method(method.clone()).uri(&target_url);
+    if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+    if let Some(key) = headers.get("Idempotency-Key") {
+        builder = builder.header("Idempotency-Key", key);
+    }
+    match state.upstream_auth.token(&config).await {
+        Ok(token) => builder = upstream_auth::authorize(builder, token.as_deref()),
+        Err(rejection) => return rejection.into_response(),
+    }
+
+    let timing = request.extensions().get::<Timing>().cloned().unwrap_or_default();
+    let request_id = request.extensions().get::<RequestId>().cloned().unwrap_or_default();
+    // Also used for internal redirects, so they keep the caller's overrides
+    let client = match state.client_for(&headers) {
+        Ok(client) => client,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let upstream_start = Instant::now();
+    let (upstream, attempts) = if stream_requested(&headers) {
+        let builder = forward_allowed_headers(&config.forward_headers, &headers, builder);
+        let builder = strip_request_headers(&config.strip_request_headers, builder);
+        match send_streaming(&state, &headers, builder, request.into_body(), &target_url).await {
+            Ok(upstream) => (upstream, 1),
+            Err(rejection) => return rejection.into_response(),
+        }
+    } else {
+        let gzip = match compression::gzip_requested(&config, &headers) {
+            Ok(gzip) => gzip,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let body = match Bytes::from_request(request, &state).await {
+            Ok(body) => body,
+            Err(rejection) => return rejection.into_response(),
+        };
+
+        // An empty body has nothing to compress
+        let body = if gzip && !body.is_empty() {
+            builder = builder.header(header::CONTENT_ENCODING, "gzip");
+            compression::gzip(&body)
+        } else {
+            body
+        };
+        let builder = forward_allowed_headers(&config.forward_headers, &headers, builder);
+        let builder = strip_request_headers(&config.strip_request_headers, builder);
+        let req = match builder.body(Full::new(body)) {
+            Ok(req) => req,
+            Err(e) => return build_error_response(&e, &target_url).into_response(),
+        };
+
+        let retries = if retry::is_retryable(&method, &headers) { max_retries } else { 0 };
+        let ((upstream, ()), attempts) = retry::with_retries(retries, || async {
+            (send_upstream(&client, clone_request(&req), &target_url).await, ())
+        }).await;
+        (upstream, attempts)
+    };
+    // ACCEL_BASE_URL: follow `X-Accel-Redirect` to the response to return
+    let (upstream, accel_followed) = match (upstream, config.accel_base_url.as_deref()) {
+        (Ok(upstream), Some(base_url)) => match accel::follow(&client, base_url, upstream).await {
+            Ok((upstream, followed)) => (Ok(upstream), followed),
+            Err(rejection) => (Err(rejection), Vec::new()),
+        },
+        (upstream, _) => (upstream, Vec::new()),
+    };
+    timing.record_upstream(upstream_start.elapsed());
+    if let Ok(upstream) = &upstream {
+        timing.record_upstream_status(upstream.status);
+    }
+
+    let stale = if config.serve_stale_on_error && method == Method::GET {
+        serve_stale_on_error(&state, uri.path(), &headers, &upstream)
+    } else {
+        None
+    };
+
+    let mut response = match (stale, upstream) {
+        (Some(stale), _) => stale,
+        (None, Ok(UpstreamResponse { status, reason, version, headers: upstream_headers, body })) => {
+            let mismatch = hash_check.and_then(|check| check.verify(&body));
+            match mismatch.as_ref().and_then(Mismatch::strict_response) {
+                Some(response) => response,
+                None => {
+                    let content_type = response_content_type(&config, &upstream_headers, &body);
+                    let capture_file = capture_dir.map(|dir| {
+                        capture::save(dir, &request_id, upstream_headers.get(header::CONTENT_TYPE), body.clone())
+                    });
+                    let mut response = Response::new(Body::from(body));
+                    *response.status_mut() = status;
+                    preserve_reason(&mut response, status, reason);
+                    if let Some(content_type) = content_type {
+                        response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+                    }
+                    // Kept by `X-Simulate-Etag`, so conditional requests use the upstream's tag
+                    if let Some(etag) = upstream_headers.get(header::ETAG) {
+                        response.headers_mut().insert(header::ETAG, etag.clone());
+                    }
+                    if headers.contains_key("X-Upstream-Http-Version") {
+                        let version = HeaderValue::from_str(&format!("{:?}", version)).unwrap();
+                        response.headers_mut().insert("X-Upstream-Http-Version", version);
+                    }
+                    if let Some(mismatch) = mismatch {
+                        mismatch.flag(&mut response);
+                    }
+                    if let Some(name) = capture_file {
+                        capture::add_header(response.headers_mut(), &name);
+                    }
+                    accel::add_header(response.headers_mut(), &accel_followed);
+                    response
+                }
+            }
+        }
+        (None, Err(error_response)) => error_response.into_response(),
+    };
+    if max_retries > 0 {
+        response.headers_mut().insert("X-Upstream-Attempts", HeaderValue::from(attempts));
+    }
+    response
+}
+
+// Send the upstream's own reason phrase (e.g. `418 I am a teapot here`)
+// instead of the standard one, as long as the response carries the
+// upstream's status. hyper writes it on HTTP/1 connections; HTTP/2 has no
+// reason phrases.
+pub fn preserve_reason(response: &mut Response<Body>, upstream_status: StatusCode, reason: Option<ReasonPhrase>) {
+    if let Some(reason) = reason.filter(|_| response.status() == upstream_status) {
+        response.extensions_mut().insert(reason);
+    }
+}
+
+// Content-Type for an upstream body returned as is: the upstream's own, else
+// `application/json` for a body that parses as JSON, else
+// DEFAULT_RESPONSE_CONTENT_TYPE (if set)
+pub fn response_content_type(config: &Config, upstream_headers: &HeaderMap, body: &[u8]) -> Option<HeaderValue> {
+    if let Some(content_type) = upstream_headers.get(header::CONTENT_TYPE) {
+        return Some(content_type.clone());
+    }
+    if serde_json::from_slice::<Value>(body).is_ok() {
+        return Some(HeaderValue::from_static("application/json"));
+    }
+    config.default_response_content_type.clone()
+}
+
+// An upstream body as a JSON value for the success wrapper: the parsed body
+// when it is JSON, a string when it is text, or base64 for binary bodies.
+// Also returns the encoding for base64 bodies, reported as
+// `response_encoding`.
+pub fn body_value(body: &[u8]) -> (Value, Option<&'static str>) {
+    if let Ok(value) = serde_json::from_slice::<Value>(body) {
+        return (value, None);
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) => (Value::from(text), None),
+        Err(_) => (Value::from(BASE64_STANDARD.encode(body)), Some("base64")),
+    }
+}
+
+// SERVE_STALE_ON_ERROR: remember the path's successful responses, and when
+// the upstream fails or answers with a server error, return the last one
+// for the request's `Vary` variant instead (if any)
+fn serve_stale_on_error(
+    state: &SharedState,
+    path: &str,
+    request_headers: &HeaderMap,
+    upstream: &Result<UpstreamResponse, (StatusCode, Json<Value>)>,
+) -> Option<Response<Body>> {
+    match upstream {
+        Ok(upstream) if upstream.status.is_success() => {
+            let content_type = response_content_type(&state.config.read().unwrap(), &upstream.headers, &upstream.body);
+            state.stale_cache.store(
+                path,
+                request_headers,
+                upstream.status,
+                &upstream.headers,
+                content_type.as_ref(),
+                upstream.body.clone(),
+            );
+            None
+        }
+        Ok(upstream) if !upstream.status.is_server_error() => None,
+        _ => state.stale_cache.serve(path, request_headers),
+    }
+}
+
+pub fn stream_requested(headers: &HeaderMap) -> bool {
+    headers
+        .get("X-Stream-Request")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false)
+}
+
+// Send the request with the client's body piped through as it arrives. The
+// body's size hint carries over, so a request with `Content-Length` is
+// forwarded with the same length and a chunked one stays chunked. The body
+// can only be read once, so the request is never retried or compressed.
+async fn send_streaming(
+    state: &SharedState,
+    headers: &HeaderMap,
+    builder: request::Builder,
+    body: Body,
+    target_url: &str,
+) -> Result<Result<UpstreamResponse, (StatusCode, Json<Value>)>, (StatusCode, Json<Value>)> {
+    let client = state.streaming_client_for(headers)?;
+    let req = builder.body(body).map_err(|e| build_error_response(&e, target_url))?;
+    Ok(match client.request(req).await {
+        Ok(resp) => collect_upstream(resp).await,
+        Err(e) if exceeds_body_limit(&e) => {
+            let limit = state.config.read().unwrap().upstream_max_request_bytes.unwrap_or_default();
+            Err(request_too_large(limit))
+        }
+        Err(e) => match find_cause::<RequestBodyTimeout>(&e) {
+            Some(RequestBodyTimeout(timeout)) => Err(request_timeout(*timeout)),
+            None => Err(forward_error_response(&e, target_url)),
+        },
+    })
+}
+
+// Error ending a streamed request body that was not received within
+// CLIENT_REQUEST_TIMEOUT_MS
+#[derive(Debug)]
+pub struct RequestBodyTimeout(pub Duration);
+
+impl std::fmt::Display for RequestBodyTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request body not received within {}ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for RequestBodyTimeout {}
+
+// 408 for a request body not received within CLIENT_REQUEST_TIMEOUT_MS
+pub fn request_timeout(timeout: Duration) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::REQUEST_TIMEOUT,
+        Json(json!({
+            "error": "Request timeout",
+            "details": RequestBodyTimeout(timeout).to_string()
+        }))
+    )
+}
+
+// 413 for a request body over UPSTREAM_MAX_REQUEST_BYTES
+pub fn request_too_large(limit: usize) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({
+            "error": "Request body too large",
+            "details": format!("Request bodies sent upstream are limited to {} bytes", limit),
+            "limit_bytes": limit
+        }))
+    )
+}
+
+// Whether the error, or any error it was caused by, is a body crossing the
+// UPSTREAM_MAX_REQUEST_BYTES limit
+pub fn exceeds_body_limit(e: &(dyn std::error::Error + 'static)) -> bool {
+    find_cause::<LengthLimitError>(e).is_some()
+}
+
+// The error, or the first error it was caused by, of type `T`
+pub fn find_cause<'a, T: std::error::Error + 'static>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a T> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(cause) = e.downcast_ref::<T>() {
+            return Some(cause);
+        }
+        source = e.source();
+    }
+    None
+}
+
+fn build_error_response(e: &axum::http::Error, target_url: &str) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Failed to build upstream request",
+            "details": e.to_string(),
+            "target_url": target_url
+        }))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(value: &str) -> Vec<RouteRule> {
+        RouteRule::parse_list(value).unwrap()
+    }
+
+    #[test]
+    fn rewrite_substitutes_capture_groups() {
+        let rules = rules(r#"[{"pattern": "^/users/(\\d+)/orders$", "rewrite_template": "/api/order-service?user=$1"}]"#);
+
+        assert_eq!(rewrite_path(&rules, &"/users/42/orders".parse().unwrap()), "/api/order-service?user=42");
+        // The incoming query is appended to the template's
+        assert_eq!(
+            rewrite_path(&rules, &"/users/42/orders?page=2".parse().unwrap()),
+            "/api/order-service?user=42&page=2"
+        );
+    }
+
+    #[test]
+    fn rewrite_uses_first_matching_rule() {
+        let rules = rules(
+            r#"[
+                {"pattern": "^/users/(?P<id>\\d+)$", "rewrite_template": "/v2/users/${id}"},
+                {"pattern": "^/users/(\\w+)$", "rewrite_template": "/v1/users/$1"},
+                {"pattern": "^/users/", "rewrite_template": "/never"}
+            ]"#,
+        );
+
+        assert_eq!(rewrite_path(&rules, &"/users/7".parse().unwrap()), "/v2/users/7");
+        assert_eq!(rewrite_path(&rules, &"/users/alice".parse().unwrap()), "/v1/users/alice");
+    }
+
+    #[test]
+    fn rewrite_leaves_unmatched_path_unchanged() {
+        let rules = rules(r#"[{"pattern": "^/users/(\\d+)/orders$", "rewrite_template": "/api/order-service?user=$1"}]"#);
+
+        assert_eq!(rewrite_path(&rules, &"/health/deep".parse().unwrap()), "/health/deep");
+        assert_eq!(rewrite_path(&rules, &"/users/abc/orders?x=1".parse().unwrap()), "/users/abc/orders?x=1");
+        assert_eq!(rewrite_path(&[], &"/users/42/orders".parse().unwrap()), "/users/42/orders");
+    }
+
+    #[test]
+    fn strip_request_headers_removes_only_listed_names() {
+        let names = parse_header_names("X-Internal-Token, authorization").unwrap();
+        let builder = Request::builder()
+            .header("x-internal-token", "secret")
+            .header("Authorization", "Bearer abc")
+            .header("X-Tenant", "acme");
+
+        let req = strip_request_headers(&names, builder).body(()).unwrap();
+
+        // Names match whatever case they were configured or sent in
+        assert!(!req.headers().contains_key("X-Internal-Token"));
+        assert!(!req.headers().contains_key(header::AUTHORIZATION));
+        assert_eq!(req.headers()["x-tenant"], "acme");
+    }
+
+    #[test]
+    fn route_rules_reject_invalid_patterns() {
+        assert!(RouteRule::parse_list(r#"[{"pattern": "(", "rewrite_template": "/x"}]"#).is_err());
+    }
+
+    // Upstream that reports the framing headers of each request and how many
+    // body bytes it received, signalling `first_chunk` once the first bytes
+    // have arrived
+    async fn framing_upstream(first_chunk: tokio::sync::mpsc::Sender<()>) -> String {
+        use http_body_util::BodyExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = hyper::service::service_fn(move |req: Request<hyper::body::Incoming>| {
+                let first_chunk = first_chunk.clone();
+                async move {
+                    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok()).map(str::to_string);
+                    let content_length = header(header::CONTENT_LENGTH);
+                    let transfer_encoding = header(header::TRANSFER_ENCODING);
+                    let mut body = req.into_body();
+                    let mut received = 0;
+                    while let Some(frame) = body.frame().await {
+                        if let Ok(data) = frame.unwrap().into_data() {
+                            if received == 0 && !data.is_empty() {
+                                let _ = first_chunk.send(()).await;
+                            }
+                            received += data.len();
+                        }
+                    }
+                    let report = json!({
+                        "received": received,
+                        "content_length": content_length,
+                        "transfer_encoding": transfer_encoding
+                    });
+                    Ok::<_, std::convert::Infallible>(Response::new(Full::new(bytes::Bytes::from(report.to_string()))))
+                }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .await;
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn large_streamed_upload_is_forwarded_as_it_arrives() {
+        use tower::ServiceExt;
+
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 128;
+
+        let (first_chunk_tx, mut first_chunk_rx) = tokio::sync::mpsc::channel(1);
+        let target_url = framing_upstream(first_chunk_tx).await;
+        let state = crate::test_state(|config| config.target_url = target_url);
+        let app = axum::Router::new().fallback(proxy_handler).with_state(state);
+
+        // The rest of the body is only sent once the upstream has seen the
+        // first chunk, so a proxy that buffered the body would never finish
+        let (chunks_tx, chunks_rx) = tokio::sync::mpsc::channel::<Result<bytes::Bytes, std::io::Error>>(1);
+        let body = Body::from_stream(futures_util::stream::unfold(chunks_rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        }));
+        tokio::spawn(async move {
+            chunks_tx.send(Ok(bytes::Bytes::from(vec![b'x'; CHUNK]))).await.unwrap();
+            first_chunk_rx.recv().await.unwrap();
+            for _ in 1..CHUNKS {
+                chunks_tx.send(Ok(bytes::Bytes::from(vec![b'x'; CHUNK]))).await.unwrap();
+            }
+        });
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/uploads")
+            .header("X-Stream-Request", "true")
+            .body(body)
+            .unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(10), app.oneshot(request))
+            .await
+            .expect("the upload was buffered instead of streamed")
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: Value = serde_json::from_slice(&body).unwrap();
+        // Larger than axum's 2 MB limit on buffered bodies
+        assert_eq!(report["received"], CHUNK * CHUNKS);
+        // A body of unknown length stays chunked rather than being given a
+        // Content-Length, which would require reading it all first
+        assert_eq!(report["content_length"], Value::Null);
+        assert_eq!(report["transfer_encoding"], "chunked");
+    }
+}
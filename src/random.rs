@@ -0,0 +1,58 @@
+use rand::rngs::{SmallRng, StdRng};
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+
+// All random sampling goes through these helpers, so probabilities are
+// clamped in one place instead of each caller guarding `gen_bool`, which
+// panics outside 0.0..=1.0. Each worker thread keeps its own
+// non-cryptographic generator, seeded once from the OS: none of this
+// randomness needs to be unpredictable, only well distributed.
+thread_local! {
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+// True with the given probability. Out-of-range probabilities (e.g. from a
+// bad `X-Failure-Rate`) are clamped to 0.0..=1.0 rather than panicking.
+pub fn chance(probability: f64) -> bool {
+    if probability.is_nan() || probability <= 0.0 {
+        return false;
+    }
+    if probability >= 1.0 {
+        return true;
+    }
+    RNG.with(|rng| rng.borrow_mut().gen_bool(probability))
+}
+
+pub fn coin() -> bool {
+    RNG.with(|rng| rng.borrow_mut().gen_bool(0.5))
+}
+
+// Uniform in 0..=max
+pub fn up_to(max: u64) -> u64 {
+    RNG.with(|rng| rng.borrow_mut().gen_range(0..=max))
+}
+
+// Generator for faults that can be replayed: the same `X-Random-Seed` always
+// gives the same sequence. Unseeded, it is seeded from the OS.
+pub fn seeded(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// 16 hex digits, for request ids
+pub fn hex_id() -> String {
+    RNG.with(|rng| format!("{:016x}", rng.borrow_mut().gen::<u64>()))
+}
+
+// `len` random letters and digits, safe to embed in a header or JSON string
+pub fn alphanumeric(len: usize) -> String {
+    RNG.with(|rng| {
+        (&mut *rng.borrow_mut())
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect()
+    })
+}
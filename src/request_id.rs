@@ -0,0 +1,52 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::random;
+
+// Longest client-supplied `X-Request-Id` that is kept as is
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Identifier of a request, shared by its access log line, its
+// `X-Request-Id` response header and the `_proxy` metadata. Inserted as a
+// request extension by `assign_request_id`.
+#[derive(Clone, Default)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Reuse the client's `X-Request-Id` when it is a reasonable token, so the
+// proxy's logs line up with the client's, and otherwise generate one. The id
+// is echoed back in `X-Request-Id`.
+pub async fn assign_request_id(mut request: Request<Body>, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(random::hex_id);
+    let id = RequestId(id.into());
+    request.extensions_mut().insert(id.clone());
+
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+    response
+}
@@ -0,0 +1,166 @@
+use axum::{
+    http::{HeaderMap, Method, StatusCode},
+    response::Json,
+};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::{random, UpstreamResponse};
+
+// Upper bound for UPSTREAM_RETRIES and `X-Upstream-Retries`
+pub const MAX_RETRIES: u32 = 10;
+
+// Wait before the first retry, doubled for each further one up to
+// MAX_BACKOFF
+const BASE_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// Methods that are safe to send again: repeating them has the same effect on
+// the upstream as sending them once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+// Whether a failed upstream call with this method may be retried. POST,
+// PATCH and other non-idempotent methods are only retried when the client
+// sends an `Idempotency-Key` (forwarded so the upstream can deduplicate) or
+// opts in with `X-Force-Retry: true`.
+pub fn is_retryable(method: &Method, headers: &HeaderMap) -> bool {
+    let force_retry = headers
+        .get("X-Force-Retry")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    is_idempotent(method) || headers.contains_key("Idempotency-Key") || force_retry
+}
+
+// Number of retries allowed after the first attempt: `X-Upstream-Retries`,
+// or UPSTREAM_RETRIES
+pub fn max_retries(config: &Config, headers: &HeaderMap) -> Result<u32, (StatusCode, Json<Value>)> {
+    let Some(h) = headers.get("X-Upstream-Retries") else {
+        return Ok(config.upstream_retries);
+    };
+    h.to_str()
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .filter(|&n| n <= MAX_RETRIES)
+        .ok_or_else(|| (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Upstream-Retries",
+                "details": format!("X-Upstream-Retries must be an integer between 0 and {}", MAX_RETRIES)
+            }))
+        ))
+}
+
+// Forwarding errors and gateway statuses are worth another attempt; any
+// other response is final
+fn should_retry(result: &Result<UpstreamResponse, (StatusCode, Json<Value>)>) -> bool {
+    match result {
+        Ok(upstream) => matches!(
+            upstream.status,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(_) => true,
+    }
+}
+
+// Wait before retry number `retry` (from 1): exponential backoff, with
+// jitter over the upper half so that clients retrying together spread out
+fn backoff(retry: u32) -> Duration {
+    let ceiling = BASE_BACKOFF.saturating_mul(1 << (retry - 1).min(16)).min(MAX_BACKOFF);
+    let half = ceiling.as_millis() as u64 / 2;
+    Duration::from_millis(half + random::up_to(half))
+}
+
+// Run `attempt` until it returns a final result or `retries` retries have
+// been made, backing off before each retry. `attempt` yields the upstream
+// result along with any extra data the caller reports for it. Returns the
+// last attempt's output and the number of attempts made.
+pub async fn with_retries<T, F, Fut>(retries: u32, mut attempt: F) -> ((Result<UpstreamResponse, (StatusCode, Json<Value>)>, T), u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = (Result<UpstreamResponse, (StatusCode, Json<Value>)>, T)>,
+{
+    let mut attempts = 1;
+    loop {
+        let output = attempt().await;
+        if attempts > retries || !should_retry(&output.0) {
+            return (output, attempts);
+        }
+        tokio::time::sleep(backoff(attempts)).await;
+        attempts += 1;
+    }
+}
+
+// Retry report for the `/delay` and `/failure` success body
+pub fn report(max_retries: u32, retryable: bool, attempts: u32) -> Value {
+    json!({
+        "max_retries": max_retries,
+        "retryable": retryable,
+        "attempts": attempts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    // Attempts made for a request whose upstream always fails, with up to 3
+    // retries when the request is retryable, as the handlers call it
+    async fn attempts_against_failing_upstream(method: Method, headers: &HeaderMap) -> u32 {
+        let retries = if is_retryable(&method, headers) { 3 } else { 0 };
+        let mut calls = 0;
+        let ((result, ()), attempts) = with_retries(retries, || {
+            calls += 1;
+            async { (Err((StatusCode::BAD_GATEWAY, Json(json!({ "error": "unreachable" })))), ()) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, calls);
+        attempts
+    }
+
+    fn header(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[tokio::test]
+    async fn post_without_idempotency_key_is_not_retried() {
+        assert_eq!(attempts_against_failing_upstream(Method::POST, &HeaderMap::new()).await, 1);
+        assert_eq!(attempts_against_failing_upstream(Method::PATCH, &HeaderMap::new()).await, 1);
+        assert_eq!(attempts_against_failing_upstream(Method::POST, &header("X-Force-Retry", "false")).await, 1);
+    }
+
+    #[tokio::test]
+    async fn post_with_idempotency_key_is_retried() {
+        assert_eq!(attempts_against_failing_upstream(Method::POST, &header("Idempotency-Key", "order-17")).await, 4);
+        assert_eq!(attempts_against_failing_upstream(Method::POST, &header("X-Force-Retry", "true")).await, 4);
+    }
+
+    #[tokio::test]
+    async fn idempotent_methods_are_retried() {
+        for method in [Method::GET, Method::HEAD, Method::PUT, Method::DELETE, Method::OPTIONS] {
+            assert_eq!(attempts_against_failing_upstream(method, &HeaderMap::new()).await, 4);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        for _ in 0..100 {
+            let first = backoff(1);
+            assert!(first >= BASE_BACKOFF / 2 && first <= BASE_BACKOFF, "{:?}", first);
+            let third = backoff(3);
+            assert!(third >= BASE_BACKOFF * 2 && third <= BASE_BACKOFF * 4, "{:?}", third);
+            let last = backoff(MAX_RETRIES);
+            assert!(last >= MAX_BACKOFF / 2 && last <= MAX_BACKOFF, "{:?}", last);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Parse `ROTATING_BODIES`, a JSON object mapping request paths to the
+// non-empty list of bodies returned for them in turn
+pub fn parse_rotating_bodies(value: &str) -> Result<HashMap<String, Vec<Value>>, String> {
+    let bodies: HashMap<String, Vec<Value>> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of path to array of bodies: {}", e))?;
+
+    for (path, list) in &bodies {
+        if !path.starts_with('/') {
+            return Err(format!("{} must be a path starting with /", path));
+        }
+        if list.is_empty() {
+            return Err(format!("{} needs at least one body", path));
+        }
+    }
+
+    Ok(bodies)
+}
+
+// Position of each path in its body rotation. The set of paths is fixed at
+// startup, so every counter is a plain atomic.
+pub struct BodyRotation {
+    positions: HashMap<String, AtomicUsize>,
+}
+
+impl BodyRotation {
+    pub fn new(bodies: &HashMap<String, Vec<Value>>) -> Self {
+        BodyRotation {
+            positions: bodies.keys().map(|path| (path.clone(), AtomicUsize::new(0))).collect(),
+        }
+    }
+
+    // Index of the next body for the path, cycling through `len` bodies
+    pub fn next(&self, path: &str, len: usize) -> Option<usize> {
+        self.positions
+            .get(path)
+            .map(|position| position.fetch_add(1, Ordering::Relaxed) % len)
+    }
+
+    // Number of bodies served so far for each path
+    pub fn to_json(&self) -> Value {
+        self.positions
+            .iter()
+            .map(|(path, position)| (path.clone(), Value::from(position.load(Ordering::Relaxed))))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    // Restart every rotation from its first body
+    pub fn reset(&self) {
+        for position in self.positions.values() {
+            position.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A recurring wall-clock window with extra delay. Windows repeat every
+// `period_secs`, aligned to the Unix epoch (so to UTC midnight for periods
+// that divide a day), and are active for `duration_secs` starting
+// `offset_secs` into each period. "The first 10 seconds of every minute" is
+// `{"period_secs": 60, "offset_secs": 0, "duration_secs": 10}`.
+#[derive(Clone)]
+pub struct DelayRule {
+    period_secs: u64,
+    offset_secs: u64,
+    duration_secs: u64,
+    delay_ms: u64,
+}
+
+impl DelayRule {
+    // Parse `DELAY_SCHEDULE`, a JSON array of `{"period_secs", "offset_secs",
+    // "duration_secs", "delay_ms"}` objects. `offset_secs` defaults to 0.
+    pub fn parse_list(value: &str) -> Result<Vec<DelayRule>, String> {
+        let entries: Vec<Value> = serde_json::from_str(value)
+            .map_err(|e| format!("expected a JSON array: {}", e))?;
+
+        entries
+            .iter()
+            .map(|entry| {
+                let field = |name: &str| entry.get(name).and_then(Value::as_u64);
+                let period_secs = field("period_secs")
+                    .filter(|&n| n > 0)
+                    .ok_or("each rule needs a positive integer \"period_secs\"")?;
+                let offset_secs = match entry.get("offset_secs") {
+                    None => 0,
+                    Some(_) => field("offset_secs")
+                        .ok_or("\"offset_secs\" must be a non-negative integer")?,
+                };
+                let duration_secs = field("duration_secs")
+                    .filter(|&n| n > 0)
+                    .ok_or("each rule needs a positive integer \"duration_secs\"")?;
+                let delay_ms = field("delay_ms")
+                    .ok_or("each rule needs a non-negative integer \"delay_ms\"")?;
+                if offset_secs + duration_secs > period_secs {
+                    return Err(format!(
+                        "window {}s+{}s does not fit in a {}s period",
+                        offset_secs, duration_secs, period_secs
+                    ));
+                }
+
+                Ok(DelayRule { period_secs, offset_secs, duration_secs, delay_ms })
+            })
+            .collect()
+    }
+
+    fn is_active(&self, now_secs: u64) -> bool {
+        let position = now_secs % self.period_secs;
+        position >= self.offset_secs && position < self.offset_secs + self.duration_secs
+    }
+
+    pub fn delay_ms(&self) -> u64 {
+        self.delay_ms
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "period_secs": self.period_secs,
+            "offset_secs": self.offset_secs,
+            "duration_secs": self.duration_secs,
+            "delay_ms": self.delay_ms
+        })
+    }
+}
+
+// The first rule whose window contains the current time, with its index
+pub fn active_rule(rules: &[DelayRule]) -> Option<(usize, &DelayRule)> {
+    let now_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    rules.iter().enumerate().find(|(_, rule)| rule.is_active(now_secs))
+}
@@ -0,0 +1,142 @@
+use axum::{
+    extract::State,
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::SharedState;
+
+// One canned response of SEQUENCE_RESPONSES
+#[derive(Clone)]
+pub struct SequenceResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Value,
+}
+
+impl SequenceResponse {
+    fn parse(fields: &Map<String, Value>) -> Result<Self, String> {
+        let status = match fields.get("status") {
+            None => StatusCode::OK,
+            Some(status) => status
+                .as_u64()
+                .filter(|code| (100..=599).contains(code))
+                .and_then(|code| StatusCode::from_u16(code as u16).ok())
+                .ok_or("status must be a status code between 100 and 599")?,
+        };
+
+        let headers = match fields.get("headers") {
+            None => Vec::new(),
+            Some(Value::Object(headers)) => headers
+                .iter()
+                .map(|(name, value)| {
+                    let name = HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|_| format!("{} is not a valid header name", name))?;
+                    let value = value
+                        .as_str()
+                        .and_then(|value| HeaderValue::from_str(value).ok())
+                        .ok_or_else(|| format!("header {} must be a valid header value string", name))?;
+                    Ok((name, value))
+                })
+                .collect::<Result<_, String>>()?,
+            Some(_) => return Err("headers must be a JSON object of header names to strings".to_string()),
+        };
+
+        let body = fields.get("body").cloned().unwrap_or(Value::Null);
+        Ok(SequenceResponse { status, headers, body })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let headers: Map<String, Value> = self.headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), Value::from(value.to_str().unwrap_or_default())))
+            .collect();
+        json!({
+            "status": self.status.as_u16(),
+            "headers": headers,
+            "body": self.body
+        })
+    }
+}
+
+// Parse `SEQUENCE_RESPONSES`, a JSON array of `{"status", "headers", "body"}`
+// objects returned by /sequence in turn. `status` defaults to 200, `headers`
+// to none and `body` to null.
+pub fn parse_sequence_responses(value: &str) -> Result<Vec<SequenceResponse>, String> {
+    let responses: Vec<Map<String, Value>> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON array of response objects: {}", e))?;
+    responses
+        .iter()
+        .enumerate()
+        .map(|(index, fields)| SequenceResponse::parse(fields).map_err(|e| format!("response {}: {}", index, e)))
+        .collect()
+}
+
+// Position in SEQUENCE_RESPONSES, shared by every /sequence request to the
+// listener
+#[derive(Default)]
+pub struct Sequence {
+    position: AtomicUsize,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Index of the next response, cycling through `len` responses
+    fn next(&self, len: usize) -> usize {
+        self.position.fetch_add(1, Ordering::Relaxed) % len
+    }
+
+    // Number of responses served so far
+    pub fn to_json(&self) -> Value {
+        Value::from(self.position.load(Ordering::Relaxed))
+    }
+
+    // Restart from the first response
+    pub fn reset(&self) {
+        self.position.store(0, Ordering::Relaxed);
+    }
+}
+
+// Return the next response of SEQUENCE_RESPONSES, in order and cycling back
+// to the first after the last, whatever the method, path or body of the
+// request. The upstream is not contacted.
+pub async fn sequence_handler(State(state): State<SharedState>) -> Response {
+    let config = state.config.read().unwrap();
+    let responses = &config.sequence_responses;
+    if responses.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "error": "No sequence configured",
+                "details": "SEQUENCE_RESPONSES is not set"
+            }))
+        ).into_response();
+    }
+
+    let index = state.sequence.next(responses.len());
+    let canned = &responses[index];
+    let mut response = (canned.status, Json(canned.body.clone())).into_response();
+    let headers = response.headers_mut();
+    for (name, value) in &canned.headers {
+        headers.insert(name.clone(), value.clone());
+    }
+    headers.insert("X-Sequence-Index", HeaderValue::from(index));
+    response
+}
+
+// Restart /sequence and every ROTATING_BODIES rotation from their first
+// response, without the admin token or resetting anything else
+pub async fn reset_counters_handler(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    state.sequence.reset();
+    state.rotation.reset();
+    (StatusCode::OK, Json(json!({
+        "status": "reset",
+        "reset": ["sequence", "body_rotation"],
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
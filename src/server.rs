@@ -0,0 +1,168 @@
+use axum::extract::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::config::Config;
+
+// State shared by the requests of one client connection, available to
+// handlers as a request extension
+#[derive(Clone, Default)]
+pub struct ConnectionState {
+    // Requests on this connection that asked for a warmup delay
+    pub warmup_requests: Arc<AtomicU64>,
+    // Injected delay spent on this connection, against MAX_DELAY_BUDGET_MS
+    pub injected_delay_ms: Arc<AtomicU64>,
+}
+
+impl ConnectionState {
+    // Take an injected wait out of what is left of the connection's
+    // MAX_DELAY_BUDGET_MS, and return how much of it may be spent: all of it,
+    // the rest of the budget, or nothing once the budget has run out
+    pub fn charge_delay(&self, budget: Option<Duration>, wait: Duration) -> Duration {
+        let Some(budget) = budget else {
+            return wait;
+        };
+        let budget_ms = budget.as_millis() as u64;
+        let requested_ms = wait.as_millis() as u64;
+        let used_ms = self.injected_delay_ms
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_add(requested_ms).min(budget_ms).max(used))
+            })
+            .unwrap();
+        Duration::from_millis(requested_ms.min(budget_ms.saturating_sub(used_ms)))
+    }
+
+    // Whether the connection has spent all of MAX_DELAY_BUDGET_MS
+    pub fn delay_budget_spent(&self, budget: Option<Duration>) -> bool {
+        budget.is_some_and(|budget| self.injected_delay_ms.load(Ordering::Relaxed) >= budget.as_millis() as u64)
+    }
+}
+
+// TLS termination settings for the listener
+#[derive(Clone)]
+pub struct TlsSettings {
+    acceptor: TlsAcceptor,
+    handshake_delay: Option<Duration>,
+}
+
+impl TlsSettings {
+    // Load the PEM certificate chain and PKCS#8 private key configured via
+    // TLS_CERT_FILE and TLS_KEY_FILE. Returns None when TLS is not configured.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let (cert_file, key_file) = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+            (None, None) => {
+                if config.tls_handshake_delay.is_some() {
+                    tracing::warn!("TLS_HANDSHAKE_DELAY_MS ignored: TLS termination is not enabled");
+                }
+                return None;
+            }
+            _ => panic!("TLS_CERT_FILE and TLS_KEY_FILE must be set together"),
+        };
+
+        let cert = std::fs::read(cert_file)
+            .unwrap_or_else(|e| panic!("Failed to read TLS_CERT_FILE {}: {}", cert_file, e));
+        let key = std::fs::read(key_file)
+            .unwrap_or_else(|e| panic!("Failed to read TLS_KEY_FILE {}: {}", key_file, e));
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .expect("TLS_CERT_FILE must be a PEM certificate and TLS_KEY_FILE a PEM PKCS#8 key");
+        let acceptor = native_tls::TlsAcceptor::new(identity)
+            .expect("Failed to initialize TLS acceptor");
+
+        Some(TlsSettings {
+            acceptor: acceptor.into(),
+            handshake_delay: config.tls_handshake_delay,
+        })
+    }
+}
+
+// Serve the app on the listener, terminating TLS when configured. Once
+// `shutdown` completes the listener stops accepting and in-flight connections
+// are drained before returning.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<TlsSettings>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let Some(tls) = tls else {
+        // Each accepted connection gets its own copy of the app that tags its
+        // requests with the connection's state
+        let make_service = tower::service_fn(move |_: axum::serve::IncomingStream<'_>| {
+            let connection = ConnectionState::default();
+            let service = app.clone().map_request(move |mut request: Request| {
+                request.extensions_mut().insert(connection.clone());
+                request
+            });
+            std::future::ready(Ok::<_, std::convert::Infallible>(service))
+        });
+        axum::serve(listener, make_service)
+            .with_graceful_shutdown(shutdown)
+            .await
+            .unwrap();
+        return;
+    };
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (tcp, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = tls.acceptor.clone();
+        let handshake_delay = tls.handshake_delay;
+        let connection = ConnectionState::default();
+        let service = TowerToHyperService::new(app.clone().map_request(
+            move |mut request: hyper::Request<Incoming>| {
+                request.extensions_mut().insert(connection.clone());
+                request
+            },
+        ));
+        let watcher = graceful.watcher();
+
+        tokio::spawn(async move {
+            // Hold the client's ClientHello unanswered to stall the handshake
+            if let Some(delay) = handshake_delay {
+                tokio::time::sleep(delay).await;
+            }
+
+            let stream = match acceptor.accept(tcp).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder
+                .serve_connection(TokioIo::new(stream), service)
+                .into_owned();
+            if let Err(e) = watcher.watch(connection).await {
+                tracing::warn!("Connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    graceful.shutdown().await;
+}
@@ -0,0 +1,183 @@
+use axum::{
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::Json,
+};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+// How long to wait for the upstream to answer an ambiguous request. Servers
+// that read the body with the longer framing wait for bytes that never come,
+// so the connection is abandoned rather than held.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Most of the upstream's raw answer kept in the report
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+// Conflicting framing written to the upstream by `X-Smuggle-Mode`. hyper
+// always frames requests itself (dropping a `Content-Length` that conflicts
+// with `Transfer-Encoding`), so these requests are written to the socket as
+// raw bytes instead.
+#[derive(Clone, Copy)]
+enum Mode {
+    // `Content-Length` then `Transfer-Encoding: chunked`
+    ClTe,
+    // `Transfer-Encoding: chunked` then `Content-Length`
+    TeCl,
+    // Two `Content-Length` headers with different values
+    DoubleCl,
+    // `Transfer-Encoding : chunked`, with whitespace before the colon, plus
+    // `Content-Length`
+    TeSpace,
+}
+
+impl Mode {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "cl-te" => Some(Mode::ClTe),
+            "te-cl" => Some(Mode::TeCl),
+            "double-cl" => Some(Mode::DoubleCl),
+            "te-space" => Some(Mode::TeSpace),
+            _ => None,
+        }
+    }
+
+    // Framing headers for a body of `len` bytes
+    fn framing(self, len: usize, alt_len: usize) -> String {
+        match self {
+            Mode::ClTe => format!("Content-Length: {}\r\nTransfer-Encoding: chunked\r\n", len),
+            Mode::TeCl => format!("Transfer-Encoding: chunked\r\nContent-Length: {}\r\n", len),
+            Mode::DoubleCl => format!("Content-Length: {}\r\nContent-Length: {}\r\n", len, alt_len),
+            Mode::TeSpace => format!("Content-Length: {}\r\nTransfer-Encoding : chunked\r\n", len),
+        }
+    }
+}
+
+fn reject(status: StatusCode, error: &str, details: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "error": error, "details": details })))
+}
+
+// Send the request to `target_url` with the framing named by
+// `X-Smuggle-Mode`, and report the raw request and whatever the upstream
+// answered. The client's body is written verbatim after the headers, so the
+// client decides what each framing interpretation sees.
+pub async fn send(
+    config: &Config,
+    mode: &str,
+    method: &Method,
+    headers: &HeaderMap,
+    target_url: &str,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    if !config.allow_smuggling {
+        return reject(
+            StatusCode::FORBIDDEN,
+            "Smuggling disabled",
+            "X-Smuggle-Mode requires ALLOW_SMUGGLING=true",
+        );
+    }
+    let Some(parsed) = Mode::parse(mode) else {
+        return reject(
+            StatusCode::BAD_REQUEST,
+            "Invalid X-Smuggle-Mode",
+            "X-Smuggle-Mode must be cl-te, te-cl, double-cl or te-space",
+        );
+    };
+    let alt_len = match headers.get("X-Smuggle-Alt-Length") {
+        None => 0,
+        Some(h) => match h.to_str().ok().and_then(|s| s.parse::<usize>().ok()) {
+            Some(alt_len) => alt_len,
+            None => {
+                return reject(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid X-Smuggle-Alt-Length",
+                    "X-Smuggle-Alt-Length must be a non-negative integer",
+                );
+            }
+        },
+    };
+    let Some((uri, host, port)) = target_url
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| {
+            let host = uri.host()?.to_string();
+            let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+            Some((uri, host, port))
+        })
+    else {
+        return reject(StatusCode::BAD_REQUEST, "Invalid target URL", target_url);
+    };
+
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let authority = uri.authority().map_or(host.as_str(), |a| a.as_str());
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, authority);
+    if let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|h| h.to_str().ok()) {
+        request.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    request.push_str(&parsed.framing(body.len(), alt_len));
+    request.push_str("Connection: close\r\n\r\n");
+    let mut raw = request.into_bytes();
+    raw.extend_from_slice(&body);
+
+    let exchange = async {
+        // DNS_OVERRIDES apply here too, since this bypasses the connector
+        let tcp = match config.dns_overrides.get(&host.to_ascii_lowercase()) {
+            Some(ip) => TcpStream::connect((*ip, port)).await?,
+            None => TcpStream::connect((host.as_str(), port)).await?,
+        };
+        if uri.scheme_str() == Some("https") {
+            let tls = tokio_native_tls::TlsConnector::from(
+                native_tls::TlsConnector::new().map_err(std::io::Error::other)?,
+            );
+            let stream = tls.connect(&host, tcp).await.map_err(std::io::Error::other)?;
+            exchange(stream, &raw).await
+        } else {
+            exchange(tcp, &raw).await
+        }
+    };
+
+    match exchange.await {
+        Ok((response, complete)) => (StatusCode::OK, Json(json!({
+            "smuggle_mode": mode,
+            "target_url": target_url,
+            "request": String::from_utf8_lossy(&raw),
+            "response": String::from_utf8_lossy(&response),
+            "complete": complete
+        }))),
+        Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({
+            "error": "Failed to forward request",
+            "error_type": "upstream_error",
+            "details": e.to_string(),
+            "target_url": target_url
+        }))),
+    }
+}
+
+// Write the raw request and read the answer until the upstream closes the
+// connection, the read times out or the cap is reached. Returns the bytes
+// read and whether the upstream closed the connection.
+async fn exchange(mut stream: impl AsyncRead + AsyncWrite + Unpin, request: &[u8]) -> std::io::Result<(Vec<u8>, bool)> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(0)) => return Ok((response, true)),
+            Ok(Ok(n)) => {
+                response.extend_from_slice(&buf[..n]);
+                if response.len() >= MAX_RESPONSE_BYTES {
+                    response.truncate(MAX_RESPONSE_BYTES);
+                    return Ok((response, false));
+                }
+            }
+            Ok(Err(e)) if response.is_empty() => return Err(e),
+            Ok(Err(_)) | Err(_) => return Ok((response, false)),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Response, StatusCode},
+};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// A successful upstream response kept for SERVE_STALE_ON_ERROR
+struct CachedResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    vary: Option<HeaderValue>,
+    body: Bytes,
+    stored_at: Instant,
+    // Order in which responses were stored, for evicting the oldest
+    serial: u64,
+}
+
+// Cached responses for one path. A response only answers requests with the
+// same values for the headers its `Vary` names, so there is one response per
+// combination of those values.
+struct CachedPath {
+    // Header names in the `Vary` of the latest response
+    vary: Vec<HeaderName>,
+    // Responses by the request's values of the `vary` headers
+    variants: HashMap<Vec<Option<HeaderValue>>, CachedResponse>,
+}
+
+// Most responses kept across all paths and variants
+const MAX_CACHED_RESPONSES: usize = 1000;
+
+// Last successful response of the generic proxy route for each path and
+// `Vary` variant. Paths and variants come from requests, so at most
+// MAX_CACHED_RESPONSES are kept, dropping the oldest to make room.
+#[derive(Default)]
+pub struct StaleCache {
+    entries: Mutex<HashMap<String, CachedPath>>,
+    stored: AtomicU64,
+}
+
+// Header names listed by a response's `Vary`, or None for `Vary: *`, which
+// makes the response depend on more than headers and so uncacheable
+fn vary_names(response_headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in response_headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+// The request's values of the varied headers. Repeated headers are joined as
+// they would be on one line.
+fn variant_key(vary: &[HeaderName], request_headers: &HeaderMap) -> Vec<Option<HeaderValue>> {
+    vary.iter()
+        .map(|name| {
+            let values: Vec<&[u8]> = request_headers.get_all(name).iter().map(HeaderValue::as_bytes).collect();
+            if values.is_empty() {
+                None
+            } else {
+                HeaderValue::from_bytes(&values.join(&b", "[..])).ok()
+            }
+        })
+        .collect()
+}
+
+// Drop the response stored longest ago, and its path once it has no
+// responses left
+fn evict_oldest(entries: &mut HashMap<String, CachedPath>) {
+    let oldest = entries
+        .iter()
+        .flat_map(|(path, cached_path)| {
+            cached_path.variants.iter().map(move |(key, cached)| (cached.serial, path, key))
+        })
+        .min_by_key(|(serial, _, _)| *serial)
+        .map(|(_, path, key)| (path.clone(), key.clone()));
+    let Some((path, key)) = oldest else {
+        return;
+    };
+    if let Some(cached_path) = entries.get_mut(&path) {
+        cached_path.variants.remove(&key);
+        if cached_path.variants.is_empty() {
+            entries.remove(&path);
+        }
+    }
+}
+
+impl StaleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Cache a response to a request for `path`. When the response varies on
+    // different headers than the path's earlier responses, those are dropped,
+    // since they can no longer be told apart. A `Vary: *` response is not
+    // cached and drops every earlier response for the path, which may no
+    // longer be valid.
+    pub fn store(
+        &self,
+        path: &str,
+        request_headers: &HeaderMap,
+        status: StatusCode,
+        response_headers: &HeaderMap,
+        content_type: Option<&HeaderValue>,
+        body: Bytes,
+    ) {
+        let Some(vary) = vary_names(response_headers) else {
+            self.entries.lock().unwrap().remove(path);
+            return;
+        };
+        let key = variant_key(&vary, request_headers);
+
+        let mut entries = self.entries.lock().unwrap();
+        let replaces = entries
+            .get(path)
+            .is_some_and(|cached_path| cached_path.vary == vary && cached_path.variants.contains_key(&key));
+        if !replaces {
+            while entries.values().map(|cached_path| cached_path.variants.len()).sum::<usize>() >= MAX_CACHED_RESPONSES {
+                evict_oldest(&mut entries);
+            }
+        }
+        let cached_path = entries.entry(path.to_string()).or_insert_with(|| CachedPath {
+            vary: Vec::new(),
+            variants: HashMap::new(),
+        });
+        if cached_path.vary != vary {
+            cached_path.vary = vary;
+            cached_path.variants.clear();
+        }
+        cached_path.variants.insert(key, CachedResponse {
+            status,
+            content_type: content_type.cloned(),
+            vary: response_headers.get(header::VARY).cloned(),
+            body,
+            stored_at: Instant::now(),
+            serial: self.stored.fetch_add(1, Ordering::Relaxed),
+        });
+    }
+
+    // The cached response for the path matching the request's varied
+    // headers, marked with `X-Served-Stale: true` and its `Age` in seconds
+    pub fn serve(&self, path: &str, request_headers: &HeaderMap) -> Option<Response<Body>> {
+        let entries = self.entries.lock().unwrap();
+        let cached_path = entries.get(path)?;
+        let cached = cached_path.variants.get(&variant_key(&cached_path.vary, request_headers))?;
+
+        let mut response = Response::new(Body::from(cached.body.clone()));
+        *response.status_mut() = cached.status;
+        let headers = response.headers_mut();
+        if let Some(content_type) = &cached.content_type {
+            headers.insert(header::CONTENT_TYPE, content_type.clone());
+        }
+        if let Some(vary) = &cached.vary {
+            headers.insert(header::VARY, vary.clone());
+        }
+        headers.insert(header::AGE, HeaderValue::from(cached.stored_at.elapsed().as_secs()));
+        headers.insert("X-Served-Stale", HeaderValue::from_static("true"));
+        Some(response)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut paths: Vec<String> = self.entries.lock().unwrap().keys().cloned().collect();
+        paths.sort();
+        json!({ "cached_paths": paths })
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn store(cache: &StaleCache, request: &[(&'static str, &'static str)], vary: &'static str, body: &'static str) {
+        cache.store(
+            "/report",
+            &headers(request),
+            StatusCode::OK,
+            &headers(&[("vary", vary)]),
+            None,
+            Bytes::from_static(body.as_bytes()),
+        );
+    }
+
+    async fn served_body(cache: &StaleCache, request: &[(&'static str, &'static str)]) -> Option<Bytes> {
+        let response = cache.serve("/report", &headers(request))?;
+        assert_eq!(response.headers()["X-Served-Stale"], "true");
+        Some(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_variant_with_matching_accept_encoding() {
+        let cache = StaleCache::new();
+        store(&cache, &[("accept-encoding", "gzip")], "Accept-Encoding", "gzipped");
+        store(&cache, &[("accept-encoding", "br")], "Accept-Encoding", "brotli");
+
+        assert_eq!(served_body(&cache, &[("accept-encoding", "gzip")]).await.unwrap(), "gzipped");
+        assert_eq!(served_body(&cache, &[("accept-encoding", "br")]).await.unwrap(), "brotli");
+    }
+
+    #[tokio::test]
+    async fn misses_when_accept_encoding_differs() {
+        let cache = StaleCache::new();
+        store(&cache, &[("accept-encoding", "gzip")], "Accept-Encoding", "gzipped");
+
+        assert!(served_body(&cache, &[("accept-encoding", "identity")]).await.is_none());
+        assert!(served_body(&cache, &[]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_vary_star() {
+        let cache = StaleCache::new();
+        store(&cache, &[], "*", "anything");
+
+        assert!(served_body(&cache, &[]).await.is_none());
+        assert_eq!(cache.to_json(), json!({ "cached_paths": [] }));
+    }
+
+    #[tokio::test]
+    async fn vary_star_drops_earlier_variants() {
+        let cache = StaleCache::new();
+        store(&cache, &[("accept-encoding", "gzip")], "Accept-Encoding", "gzipped");
+        store(&cache, &[("accept-encoding", "gzip")], "*", "anything");
+
+        assert!(served_body(&cache, &[("accept-encoding", "gzip")]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_response_when_full() {
+        let cache = StaleCache::new();
+        let store_path = |path: &str| {
+            cache.store(path, &HeaderMap::new(), StatusCode::OK, &HeaderMap::new(), None, Bytes::from(path.to_string()));
+        };
+        for i in 0..=MAX_CACHED_RESPONSES {
+            store_path(&format!("/items/{}", i));
+        }
+        // Replacing a cached response does not evict anything
+        store_path(&format!("/items/{}", MAX_CACHED_RESPONSES));
+
+        assert!(cache.serve("/items/0", &HeaderMap::new()).is_none());
+        assert!(cache.serve("/items/1", &HeaderMap::new()).is_some());
+        assert!(cache.serve(&format!("/items/{}", MAX_CACHED_RESPONSES), &HeaderMap::new()).is_some());
+        assert_eq!(cache.entries.lock().unwrap().len(), MAX_CACHED_RESPONSES);
+    }
+}
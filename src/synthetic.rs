@@ -0,0 +1,224 @@
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    response::Json,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::upstream_auth;
+use crate::{send_upstream, SharedState};
+
+// Most recent probe results kept for `/synthetic`
+const MAX_RESULTS: usize = 100;
+
+// The request sent by each probe, from `SYNTHETIC_REQUEST`
+#[derive(Clone)]
+pub struct SyntheticRequest {
+    method: Method,
+    path: String,
+    body: Option<Value>,
+}
+
+impl Default for SyntheticRequest {
+    fn default() -> Self {
+        SyntheticRequest { method: Method::GET, path: "/".to_string(), body: None }
+    }
+}
+
+impl SyntheticRequest {
+    // Parse `SYNTHETIC_REQUEST`, a JSON object with an optional `method`
+    // (default GET), `path` appended to the target URL (default /) and JSON
+    // `body`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let spec: serde_json::Map<String, Value> = serde_json::from_str(value)
+            .map_err(|e| format!("expected a JSON object with method, path and body: {}", e))?;
+        let mut request = SyntheticRequest::default();
+
+        for (key, value) in spec {
+            match key.as_str() {
+                "method" => {
+                    request.method = value
+                        .as_str()
+                        .and_then(|s| s.parse::<Method>().ok())
+                        .ok_or("method must be an HTTP method name")?;
+                }
+                "path" => {
+                    request.path = value
+                        .as_str()
+                        .filter(|s| s.starts_with('/'))
+                        .ok_or("path must be a string starting with /")?
+                        .to_string();
+                }
+                "body" => request.body = Some(value),
+                other => return Err(format!("unknown field: {}", other)),
+            }
+        }
+
+        Ok(request)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "method": self.method.as_str(),
+            "path": self.path,
+            "body": self.body
+        })
+    }
+}
+
+struct ProbeResult {
+    timestamp: DateTime<Utc>,
+    status: Option<StatusCode>,
+    latency_ms: f64,
+    error: Option<String>,
+}
+
+impl ProbeResult {
+    fn succeeded(&self) -> bool {
+        self.status.is_some_and(|status| status.is_success())
+    }
+}
+
+#[derive(Default)]
+struct History {
+    results: VecDeque<ProbeResult>,
+    probes: u64,
+    failures: u64,
+    // Consecutive failed probes up to the latest one
+    failure_streak: u64,
+    longest_failure_streak: u64,
+    last_success: Option<DateTime<Utc>>,
+}
+
+// Results of the synthetic probes sent with SYNTHETIC_INTERVAL_MS
+#[derive(Default)]
+pub struct SyntheticMonitor {
+    history: Mutex<History>,
+}
+
+impl SyntheticMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, result: ProbeResult) {
+        let mut history = self.history.lock().unwrap();
+        history.probes += 1;
+        if result.succeeded() {
+            history.failure_streak = 0;
+            history.last_success = Some(result.timestamp);
+        } else {
+            history.failures += 1;
+            history.failure_streak += 1;
+            history.longest_failure_streak = history.longest_failure_streak.max(history.failure_streak);
+        }
+        if history.results.len() == MAX_RESULTS {
+            history.results.pop_back();
+        }
+        history.results.push_front(result);
+    }
+
+    fn to_json(&self) -> Value {
+        let history = self.history.lock().unwrap();
+        json!({
+            "probes": history.probes,
+            "failures": history.failures,
+            "failure_streak": history.failure_streak,
+            "longest_failure_streak": history.longest_failure_streak,
+            "last_success": history.last_success.map(|t| t.to_rfc3339()),
+            "results": history.results.iter().map(|result| json!({
+                "timestamp": result.timestamp.to_rfc3339(),
+                "status": result.status.map(|status| status.as_u16()),
+                "latency_ms": result.latency_ms,
+                "success": result.succeeded(),
+                "error": result.error
+            })).collect::<Vec<_>>()
+        })
+    }
+}
+
+// Send one probe to the listener's current target URL. A probe gets at most
+// one interval to complete, so a hung upstream cannot stall the schedule.
+async fn probe(state: &SharedState) -> ProbeResult {
+    let config = state.config.read().unwrap().clone();
+    let target_url = format!("{}{}", config.target_url.trim_end_matches('/'), config.synthetic_request.path);
+    let request = config.synthetic_request.clone();
+    let interval = config.synthetic_interval.unwrap_or_default();
+
+    let timestamp = Utc::now();
+    let token = match state.upstream_auth.token(&config).await {
+        Ok(token) => token,
+        Err((_, Json(error))) => {
+            let details = error.get("details").and_then(Value::as_str).unwrap_or("token error");
+            return ProbeResult { timestamp, status: None, latency_ms: 0.0, error: Some(details.to_string()) };
+        }
+    };
+    let builder = Request::builder().method(request.method).uri(&target_url);
+    let mut builder = upstream_auth::authorize(builder, token.as_deref());
+    let body = match &request.body {
+        Some(body) => {
+            builder = builder.header(header::CONTENT_TYPE, "application/json");
+            Bytes::from(body.to_string())
+        }
+        None => Bytes::new(),
+    };
+    let req = match builder.body(Full::new(body)) {
+        Ok(req) => req,
+        Err(e) => return ProbeResult { timestamp, status: None, latency_ms: 0.0, error: Some(e.to_string()) },
+    };
+
+    let start = Instant::now();
+    let result = tokio::time::timeout(interval, send_upstream(&state.client, req, &target_url)).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let (status, error) = match result {
+        Ok(Ok(upstream)) => (Some(upstream.status), None),
+        Ok(Err((_, Json(error)))) => {
+            let details = error.get("details").and_then(Value::as_str).unwrap_or("upstream error");
+            (None, Some(details.to_string()))
+        }
+        Err(_) => (None, Some(format!("timed out after {}ms", interval.as_millis()))),
+    };
+    ProbeResult { timestamp, status, latency_ms, error }
+}
+
+// Probe the upstream every SYNTHETIC_INTERVAL_MS, independent of traffic.
+// Probes run one at a time; a late one delays the next instead of piling up.
+pub async fn run(state: SharedState) {
+    let Some(interval) = state.config.read().unwrap().synthetic_interval else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let result = probe(&state).await;
+        match (&result.error, result.status) {
+            (Some(error), _) => tracing::warn!("Synthetic probe failed: {}", error),
+            (None, Some(status)) if !status.is_success() => {
+                tracing::warn!("Synthetic probe failed with status {}", status.as_u16())
+            }
+            _ => {}
+        }
+        state.synthetic.record(result);
+    }
+}
+
+// Recent synthetic probe results and failure streaks
+pub async fn synthetic(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    let (interval, request) = {
+        let config = state.config.read().unwrap();
+        (config.synthetic_interval, config.synthetic_request.to_json())
+    };
+    let mut body = state.synthetic.to_json();
+    body["enabled"] = json!(interval.is_some());
+    body["interval_ms"] = json!(interval.map(|d| d.as_millis() as u64));
+    body["request"] = request;
+    body["timestamp"] = json!(Utc::now().to_rfc3339());
+    (StatusCode::OK, Json(body))
+}
@@ -0,0 +1,85 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::SharedState;
+
+// Per-request record of where time went. Inserted as a request extension by
+// `access_log` (or `record_timing`, if it runs first); handlers add the time
+// spent in injected delays and waiting on the upstream.
+#[derive(Clone, Default)]
+pub struct Timing(Arc<Mutex<Breakdown>>);
+
+#[derive(Clone, Copy, Default)]
+struct Breakdown {
+    delay: Duration,
+    upstream: Duration,
+    // Status of the last upstream response
+    upstream_status: Option<StatusCode>,
+}
+
+impl Timing {
+    pub fn record_delay(&self, elapsed: Duration) {
+        self.0.lock().unwrap().delay += elapsed;
+    }
+
+    pub fn record_upstream(&self, elapsed: Duration) {
+        self.0.lock().unwrap().upstream += elapsed;
+    }
+
+    pub fn record_upstream_status(&self, status: StatusCode) {
+        self.0.lock().unwrap().upstream_status = Some(status);
+    }
+
+    // Breakdown for log lines, e.g. `delay=200ms upstream=45ms
+    // upstream_status=200`
+    pub fn describe(&self) -> String {
+        let breakdown = *self.0.lock().unwrap();
+        format!(
+            "delay={}ms upstream={}ms upstream_status={}",
+            breakdown.delay.as_millis(),
+            breakdown.upstream.as_millis(),
+            breakdown.upstream_status.map_or("none".to_string(), |status| status.as_u16().to_string())
+        )
+    }
+
+    // Time spent in injected delays so far
+    pub fn delay(&self) -> Duration {
+        self.0.lock().unwrap().delay
+    }
+}
+
+// Measure total handling time and, when EMIT_TIMING is enabled, report the
+// breakdown as `X-Timing: delay=200ms;upstream=45ms;total=248ms`
+pub async fn record_timing(
+    State(state): State<SharedState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let timing = request.extensions().get::<Timing>().cloned().unwrap_or_default();
+    request.extensions_mut().insert(timing.clone());
+
+    let mut response = next.run(request).await;
+
+    if state.config.read().unwrap().emit_timing {
+        let breakdown = *timing.0.lock().unwrap();
+        let value = format!(
+            "delay={}ms;upstream={}ms;total={}ms",
+            breakdown.delay.as_millis(),
+            breakdown.upstream.as_millis(),
+            start.elapsed().as_millis()
+        );
+        response
+            .headers_mut()
+            .insert("X-Timing", HeaderValue::from_str(&value).unwrap());
+    }
+
+    response
+}
@@ -0,0 +1,193 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::State,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use hyper::body::Frame;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::SharedState;
+
+// Headers that frame the message or route it, which are not allowed as
+// trailers
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "content-range",
+    "transfer-encoding",
+    "trailer",
+    "te",
+    "connection",
+    "host",
+    "authorization",
+    "set-cookie",
+];
+
+// Parse `X-Response-Trailers`, a JSON object of trailer names to string values
+fn parse_trailers(value: &str) -> Result<HeaderMap, String> {
+    let fields: serde_json::Map<String, Value> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of trailer names to values: {}", e))?;
+
+    let mut trailers = HeaderMap::new();
+    for (name, value) in fields {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid trailer name: {}", name))?;
+        if FORBIDDEN_TRAILERS.contains(&header_name.as_str()) {
+            return Err(format!("{} is not allowed as a trailer", header_name));
+        }
+        let value = value
+            .as_str()
+            .and_then(|s| HeaderValue::from_str(s).ok())
+            .ok_or_else(|| format!("value of {} must be a valid header value string", name))?;
+        trailers.append(header_name, value);
+    }
+    Ok(trailers)
+}
+
+// Body that sends the inner body and then the trailers. It never reports an
+// exact length, so HTTP/1.1 responses use chunked encoding, the only framing
+// that can carry trailers.
+struct TrailersBody {
+    inner: Body,
+    trailers: Option<HeaderMap>,
+}
+
+impl HttpBody for TrailersBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(None) => Poll::Ready(self.trailers.take().map(|t| Ok(Frame::trailers(t)))),
+            other => other,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream() && self.trailers.is_none()
+    }
+}
+
+// With ENABLE_TRAILERS, send the trailers from `X-Response-Trailers` after
+// the response body. Trailers are declared in the `Trailer` header. hyper
+// only writes them to HTTP/1.1 clients that sent `TE: trailers`; other
+// clients get the same chunked body without them.
+pub async fn response_trailers(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !state.config.read().unwrap().enable_trailers {
+        return next.run(request).await;
+    }
+    let trailers = match request.headers().get("X-Response-Trailers") {
+        None => return next.run(request).await,
+        Some(h) => match h.to_str().map_err(|e| e.to_string()).and_then(parse_trailers) {
+            Ok(trailers) => trailers,
+            Err(details) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "Invalid X-Response-Trailers",
+                        "details": details
+                    }))
+                ).into_response();
+            }
+        },
+    };
+
+    let response = next.run(request).await;
+    if trailers.is_empty() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let declared = trailers.keys().map(HeaderName::as_str).collect::<Vec<_>>().join(", ");
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::TRAILER, HeaderValue::from_str(&declared).unwrap());
+    let body = TrailersBody { inner: body, trailers: Some(trailers) };
+    Response::from_parts(parts, Body::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use http_body_util::BodyExt;
+    use hyper_util::rt::TokioIo;
+
+    const BODY: &str = "a body long enough to be sent before the trailers";
+
+    // Serve GET /resource behind `response_trailers`, with ENABLE_TRAILERS on
+    async fn serve() -> std::net::SocketAddr {
+        let state = crate::test_state(|config| config.enable_trailers = true);
+        let app = Router::new()
+            .route("/resource", get(|| async { BODY }))
+            .layer(axum::middleware::from_fn_with_state(state, response_trailers));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    // GET /resource over a fresh HTTP/1.1 connection with a hyper client
+    async fn get_resource(addr: std::net::SocketAddr, te_trailers: bool) -> Response<hyper::body::Incoming> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+
+        let mut request = Request::builder()
+            .uri("/resource")
+            .header(header::HOST, addr.to_string())
+            .header("X-Response-Trailers", r#"{"X-Checksum": "abc123", "Server-Timing": "db;dur=53"}"#);
+        if te_trailers {
+            request = request.header(header::TE, "trailers");
+        }
+        sender.send_request(request.body(http_body_util::Empty::<Bytes>::new()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn trailers_are_read_frame_by_frame() {
+        let addr = serve().await;
+        let response = get_resource(addr, true).await;
+        assert_eq!(response.headers()[header::TRAILER], "server-timing, x-checksum");
+        assert_eq!(response.headers()[header::TRANSFER_ENCODING], "chunked");
+
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        let mut trailers = None;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.unwrap();
+            if let Some(chunk) = frame.data_ref() {
+                assert!(trailers.is_none(), "data after the trailers");
+                data.extend_from_slice(chunk);
+            } else {
+                trailers = frame.into_trailers().ok();
+            }
+        }
+
+        assert_eq!(data, BODY.as_bytes());
+        let trailers = trailers.expect("no trailers frame");
+        assert_eq!(trailers["x-checksum"], "abc123");
+        assert_eq!(trailers["server-timing"], "db;dur=53");
+    }
+
+    #[tokio::test]
+    async fn clients_ignoring_trailers_get_the_full_body() {
+        let addr = serve().await;
+        for te_trailers in [true, false] {
+            let response = get_resource(addr, te_trailers).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX).await.unwrap();
+            assert_eq!(body, BODY);
+        }
+    }
+}
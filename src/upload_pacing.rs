@@ -0,0 +1,121 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::State,
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use hyper::body::{Frame, SizeHint};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+use crate::{metrics, SharedState};
+
+// Parse `X-Upload-Read-Rate-Bps`, the rate in bytes per second at which the
+// request body is read
+fn parse_rate(headers: &HeaderMap) -> Result<Option<u64>, (StatusCode, Json<Value>)> {
+    let Some(h) = headers.get("X-Upload-Read-Rate-Bps") else {
+        return Ok(None);
+    };
+    h.to_str()
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .filter(|&rate| rate > 0)
+        .map(Some)
+        .ok_or_else(|| (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Upload-Read-Rate-Bps",
+                "details": "X-Upload-Read-Rate-Bps must be a positive integer"
+            }))
+        ))
+}
+
+// Request body read at no more than `rate` bytes per second on average. Each
+// chunk is held until the time the bytes read so far should have taken, and
+// the next chunk is not read from the client until then, so the client's
+// upload slows down once its send buffers fill. The time taken to deliver
+// the body so far is kept in `elapsed`.
+struct PacedBody {
+    inner: Body,
+    rate: u64,
+    started: Instant,
+    read: u64,
+    held: Option<(Frame<Bytes>, Pin<Box<Sleep>>)>,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl HttpBody for PacedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Some((_, sleep)) = self.held.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            let (frame, _) = self.held.take().unwrap();
+            *self.elapsed.lock().unwrap() = self.started.elapsed();
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        let frame = match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            other => return Poll::Ready(other),
+        };
+        let Some(data) = frame.data_ref() else {
+            return Poll::Ready(Some(Ok(frame)));
+        };
+        self.read += data.len() as u64;
+        let due = self.started + Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+        self.held = Some((frame, Box::pin(tokio::time::sleep_until(due))));
+        self.poll_frame(cx)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// With `X-Upload-Read-Rate-Bps`, read the request body at that rate before it
+// is forwarded, like an upstream that consumes uploads slowly, so clients
+// show gradual upload progress. The time the body took to read is reported
+// in `X-Upload-Duration-Ms`. Probes, metrics and admin calls are not paced.
+pub async fn upload_pacing(
+    State(state): State<SharedState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let rate = match parse_rate(request.headers()) {
+        Ok(Some(rate)) if state.chaos_active() => rate,
+        Ok(_) => return next.run(request).await,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if metrics::is_operational(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let elapsed = Arc::new(Mutex::new(Duration::ZERO));
+    let (parts, body) = request.into_parts();
+    let body = Body::new(PacedBody {
+        inner: body,
+        rate,
+        started: Instant::now(),
+        read: 0,
+        held: None,
+        elapsed: elapsed.clone(),
+    });
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    let elapsed = *elapsed.lock().unwrap();
+    response
+        .headers_mut()
+        .insert("X-Upload-Duration-Ms", HeaderValue::from(elapsed.as_millis() as u64));
+    response
+}
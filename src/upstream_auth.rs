@@ -0,0 +1,222 @@
+use axum::{
+    body::Bytes,
+    http::{header, request, HeaderValue, Method, Request, StatusCode, Uri},
+    response::Json,
+};
+use http_body_util::Full;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::connector::HttpClient;
+use crate::send_upstream;
+
+// Tokens are refreshed this long before they expire, so one is never sent
+// just as it runs out
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+// How long a token is cached when the token endpoint gives no `expires_in`
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+
+// Longest wait for the token endpoint
+const TOKEN_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where the bearer token sent upstream comes from
+#[derive(Clone)]
+pub enum TokenSource {
+    // UPSTREAM_BEARER_TOKEN
+    Static(String),
+    // OAuth 2.0 client credentials grant against UPSTREAM_TOKEN_URL
+    Endpoint {
+        url: String,
+        client_id: Option<String>,
+        client_secret: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+impl TokenSource {
+    // Read UPSTREAM_BEARER_TOKEN or UPSTREAM_TOKEN_URL (with
+    // UPSTREAM_TOKEN_CLIENT_ID, UPSTREAM_TOKEN_CLIENT_SECRET and
+    // UPSTREAM_TOKEN_SCOPE). Only one of the two may be set.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, String> {
+        match (var("UPSTREAM_BEARER_TOKEN"), var("UPSTREAM_TOKEN_URL")) {
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => Err("set UPSTREAM_BEARER_TOKEN or UPSTREAM_TOKEN_URL, not both".to_string()),
+            (Some(token), None) => {
+                HeaderValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|_| "UPSTREAM_BEARER_TOKEN is not a valid header value".to_string())?;
+                Ok(Some(TokenSource::Static(token)))
+            }
+            (None, Some(url)) => {
+                let uri = url
+                    .parse::<Uri>()
+                    .map_err(|e| format!("UPSTREAM_TOKEN_URL {} is not a valid URL: {}", url, e))?;
+                if uri.scheme().is_none() || uri.host().is_none() {
+                    return Err(format!("UPSTREAM_TOKEN_URL {} must be an absolute URL", url));
+                }
+                Ok(Some(TokenSource::Endpoint {
+                    url,
+                    client_id: var("UPSTREAM_TOKEN_CLIENT_ID"),
+                    client_secret: var("UPSTREAM_TOKEN_CLIENT_SECRET"),
+                    scope: var("UPSTREAM_TOKEN_SCOPE"),
+                }))
+            }
+        }
+    }
+
+    // Secrets are left out
+    pub fn to_json(&self) -> Value {
+        match self {
+            TokenSource::Static(_) => json!({ "source": "static" }),
+            TokenSource::Endpoint { url, client_id, scope, .. } => json!({
+                "source": "token_url",
+                "token_url": url,
+                "client_id": client_id,
+                "scope": scope
+            }),
+        }
+    }
+}
+
+// Token fetched from UPSTREAM_TOKEN_URL and when to fetch a new one
+struct CachedToken {
+    token: String,
+    refresh_at: Instant,
+}
+
+// Cache of the token from UPSTREAM_TOKEN_URL. The lock is held while a token
+// is fetched, so concurrent requests wait for one fetch instead of each
+// starting their own.
+pub struct UpstreamAuth {
+    // Client for the token endpoint
+    client: HttpClient,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl UpstreamAuth {
+    pub fn new(client: HttpClient) -> Self {
+        UpstreamAuth { client, cached: Mutex::new(None) }
+    }
+
+    // Token to send upstream, if any: the static token, or the cached token
+    // from the token endpoint, fetched again once it is close to expiring
+    pub async fn token(&self, config: &Config) -> Result<Option<String>, (StatusCode, Json<Value>)> {
+        let endpoint = match &config.upstream_auth {
+            None => return Ok(None),
+            Some(TokenSource::Static(token)) => return Ok(Some(token.clone())),
+            Some(endpoint) => endpoint,
+        };
+
+        let mut cached = self.cached.lock().await;
+        if let Some(cached) = cached.as_ref().filter(|cached| Instant::now() < cached.refresh_at) {
+            return Ok(Some(cached.token.clone()));
+        }
+        let (token, lifetime) = tokio::time::timeout(TOKEN_FETCH_TIMEOUT, fetch(endpoint, &self.client))
+            .await
+            .unwrap_or_else(|_| Err(format!("timed out after {}s", TOKEN_FETCH_TIMEOUT.as_secs())))
+            .map_err(token_error)?;
+        *cached = Some(CachedToken {
+            token: token.clone(),
+            refresh_at: Instant::now() + cache_lifetime(lifetime),
+        });
+        Ok(Some(token))
+    }
+
+    // Drop the cached token, so the next request fetches a new one
+    pub async fn reset(&self) {
+        *self.cached.lock().await = None;
+    }
+}
+
+// How long a token with this lifetime is reused. Tokens are refreshed
+// REFRESH_MARGIN early, except short-lived ones, which would then never be
+// reused and are kept for half their lifetime instead.
+fn cache_lifetime(lifetime: Duration) -> Duration {
+    if lifetime > REFRESH_MARGIN {
+        lifetime - REFRESH_MARGIN
+    } else {
+        lifetime / 2
+    }
+}
+
+fn token_error(details: String) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(json!({
+            "error": "Failed to fetch upstream token",
+            "details": details
+        }))
+    )
+}
+
+// Request a token with the client credentials grant. Returns the token and
+// how long it is valid for.
+async fn fetch(endpoint: &TokenSource, client: &HttpClient) -> Result<(String, Duration), String> {
+    let TokenSource::Endpoint { url, client_id, client_secret, scope } = endpoint else {
+        unreachable!("static tokens are never fetched");
+    };
+
+    // The serializer is not Send, so it is dropped before the request is sent
+    let form = {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "client_credentials");
+        for (name, value) in [("client_id", client_id), ("client_secret", client_secret), ("scope", scope)] {
+            if let Some(value) = value {
+                form.append_pair(name, value);
+            }
+        }
+        form.finish()
+    };
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(header::ACCEPT, "application/json")
+        .body(Full::new(Bytes::from(form)))
+        .map_err(|e| e.to_string())?;
+
+    let response = send_upstream(client, req, url).await.map_err(|(_, Json(error))| {
+        error.get("details").and_then(Value::as_str).unwrap_or("request failed").to_string()
+    })?;
+    if !response.status.is_success() {
+        return Err(format!("token endpoint returned {}", response.status));
+    }
+    let body: Value = serde_json::from_slice(&response.body)
+        .map_err(|e| format!("token endpoint returned invalid JSON: {}", e))?;
+    let token = body
+        .get("access_token")
+        .and_then(Value::as_str)
+        .filter(|token| HeaderValue::from_str(&format!("Bearer {}", token)).is_ok())
+        .ok_or("token endpoint response has no valid access_token")?;
+    let lifetime = body
+        .get("expires_in")
+        .and_then(Value::as_u64)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    Ok((token.to_string(), lifetime))
+}
+
+// Send the token as `Authorization: Bearer <token>`, replacing any
+// Authorization header already on the request
+pub fn authorize(mut builder: request::Builder, token: Option<&str>) -> request::Builder {
+    if let (Some(token), Some(headers)) = (token, builder.headers_mut()) {
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_lived_tokens_are_still_reused() {
+        assert_eq!(cache_lifetime(Duration::from_secs(3600)), Duration::from_secs(3570));
+        assert_eq!(cache_lifetime(Duration::from_secs(31)), Duration::from_secs(1));
+        assert_eq!(cache_lifetime(Duration::from_secs(30)), Duration::from_secs(15));
+        assert_eq!(cache_lifetime(Duration::from_secs(10)), Duration::from_secs(5));
+        assert_eq!(cache_lifetime(Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -0,0 +1,118 @@
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use serde_json::{json, Map, Value};
+
+use crate::config::Config;
+
+// A VHOST_MAP entry: requests whose `Host` matches `host` are sent to
+// `target_url`. `host` is an exact name, or `*.` followed by a domain to
+// match any name under it.
+#[derive(Clone)]
+pub struct VirtualHost {
+    host: String,
+    target_url: String,
+}
+
+impl VirtualHost {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix('*') {
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => host == self.host,
+        }
+    }
+}
+
+// Parse `VHOST_MAP`, a JSON object mapping host names to upstream URLs, e.g.
+// `{"api.localhost": "http://127.0.0.1:8080", "*.example.com": "http://127.0.0.1:9000"}`.
+// Entries are ordered for matching: exact names first, then wildcards from
+// the most specific (longest) domain down.
+pub fn parse_vhost_map(value: &str) -> Result<Vec<VirtualHost>, String> {
+    let entries: Map<String, Value> = serde_json::from_str(value)
+        .map_err(|e| format!("expected a JSON object of host name to upstream URL: {}", e))?;
+
+    let mut vhosts = entries
+        .into_iter()
+        .map(|(host, target_url)| {
+            let target_url = target_url
+                .as_str()
+                .ok_or_else(|| format!("the upstream URL for {} must be a string", host))?;
+            let uri = target_url
+                .parse::<Uri>()
+                .map_err(|e| format!("{} is not a valid URL: {}", target_url, e))?;
+            if uri.scheme().is_none() || uri.host().is_none() {
+                return Err(format!("{} must be an absolute URL", target_url));
+            }
+
+            let host = host.to_ascii_lowercase();
+            let name = host.strip_prefix("*.").unwrap_or(&host);
+            if name.is_empty() || name.contains('*') {
+                return Err(format!("{} must be a host name or *.<domain>", host));
+            }
+
+            Ok(VirtualHost { host, target_url: target_url.to_string() })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    vhosts.sort_by_key(|vhost| (vhost.host.starts_with('*'), std::cmp::Reverse(vhost.host.len())));
+    Ok(vhosts)
+}
+
+pub fn to_json(vhosts: &[VirtualHost]) -> Value {
+    vhosts
+        .iter()
+        .map(|vhost| (vhost.host.clone(), Value::from(vhost.target_url.as_str())))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+// The request's `Host` without its port, lowercased
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let name = match host.strip_prefix('[') {
+        // IPv6 literal, e.g. `[::1]:3000`
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.split(':').next().unwrap_or(host),
+    };
+    Some(name.to_ascii_lowercase())
+}
+
+// Upstream for a request: `X-Proxy-Url`, else the VHOST_MAP entry matching its
+// `Host`, else TARGET_URL. Also returns the VHOST_MAP host that matched.
+pub fn resolve_target(config: &Config, headers: &HeaderMap) -> (String, Option<String>) {
+    if let Some(url) = headers.get("X-Proxy-Url").and_then(|h| h.to_str().ok()) {
+        return (url.to_string(), None);
+    }
+    let vhost = request_host(headers)
+        .and_then(|host| config.vhost_map.iter().find(|vhost| vhost.matches(&host)));
+    match vhost {
+        Some(vhost) => (vhost.target_url.clone(), Some(vhost.host.clone())),
+        None => (config.target_url.clone(), None),
+    }
+}
+
+// Parse `X-Upstream-Scheme`: `http` or `https`, to switch the scheme of the
+// resolved upstream URL for one request
+pub fn upstream_scheme(headers: &HeaderMap) -> Result<Option<&'static str>, (StatusCode, Json<Value>)> {
+    let Some(h) = headers.get("X-Upstream-Scheme") else {
+        return Ok(None);
+    };
+    match h.to_str().map(|s| s.trim().to_ascii_lowercase()).as_deref() {
+        Ok("http") => Ok(Some("http")),
+        Ok("https") => Ok(Some("https")),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Upstream-Scheme",
+                "details": "X-Upstream-Scheme must be http or https"
+            }))
+        )),
+    }
+}
+
+// Replace the scheme of an upstream URL, keeping its host, port and path
+pub fn with_scheme(target_url: &str, scheme: &str) -> String {
+    let rest = target_url.split_once("://").map_or(target_url, |(_, rest)| rest);
+    format!("{}://{}", scheme, rest)
+}
@@ -0,0 +1,48 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Expired keys are only swept once the table holds this many, keeping the
+// per-request cost constant for typical test runs
+const SWEEP_THRESHOLD: usize = 1024;
+
+// Number of calls seen for each `X-Warm-Key`. A key is forgotten once it has
+// not been seen for WARM_KEY_TTL_SECS, so the next call starts slow again.
+#[derive(Default)]
+pub struct WarmKeys {
+    calls: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl WarmKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Count a call for the key and return its index, starting at 1
+    pub fn next_call(&self, key: &str, ttl: Duration) -> u64 {
+        let now = Instant::now();
+        let mut calls = self.calls.lock().unwrap();
+        if calls.len() >= SWEEP_THRESHOLD {
+            calls.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < ttl);
+        }
+
+        let entry = calls.entry(key.to_string()).or_insert((0, now));
+        if now.duration_since(entry.1) >= ttl {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = now;
+        entry.0
+    }
+
+    // Number of keys being tracked, including ones that have expired but not
+    // yet been swept
+    pub fn to_json(&self) -> Value {
+        json!({ "tracked_keys": self.calls.lock().unwrap().len() })
+    }
+
+    pub fn reset(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
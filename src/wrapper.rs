@@ -0,0 +1,74 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::{json, Map, Value};
+
+// Shape of the success wrapper, from SUCCESS_WRAPPER_TEMPLATE or
+// `X-Success-Wrapper-Template`
+#[derive(Clone)]
+pub enum WrapperTemplate {
+    // Return the upstream body without a wrapper
+    Passthrough,
+    // JSON in which every string of the form "{{field}}" is replaced by that
+    // field of the default wrapper, e.g. "{{response}}"
+    Template(Value),
+}
+
+impl WrapperTemplate {
+    // Parse a template: empty or `passthrough` for the raw body, otherwise JSON
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.is_empty() || value == "passthrough" {
+            return Ok(WrapperTemplate::Passthrough);
+        }
+        serde_json::from_str(value)
+            .map(WrapperTemplate::Template)
+            .map_err(|e| format!("expected passthrough or JSON: {}", e))
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            WrapperTemplate::Passthrough => json!("passthrough"),
+            WrapperTemplate::Template(template) => template.clone(),
+        }
+    }
+}
+
+// `X-Success-Wrapper-Template`, which overrides SUCCESS_WRAPPER_TEMPLATE for
+// the request
+pub fn from_headers(headers: &HeaderMap) -> Result<Option<WrapperTemplate>, (StatusCode, Json<Value>)> {
+    let Some(h) = headers.get("X-Success-Wrapper-Template") else {
+        return Ok(None);
+    };
+    h.to_str()
+        .map_err(|e| e.to_string())
+        .and_then(WrapperTemplate::parse)
+        .map(Some)
+        .map_err(|details| (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Invalid X-Success-Wrapper-Template",
+                "details": details
+            }))
+        ))
+}
+
+// Fill the template's placeholders from the default wrapper. Placeholders
+// for fields the wrapper does not have become null.
+pub fn render(template: &Value, wrapper: &Value) -> Value {
+    match template {
+        Value::String(s) => match s.strip_prefix("{{").and_then(|s| s.strip_suffix("}}")) {
+            Some(field) => wrapper.get(field.trim()).cloned().unwrap_or(Value::Null),
+            None => template.clone(),
+        },
+        Value::Array(items) => Value::Array(items.iter().map(|item| render(item, wrapper)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value, wrapper)))
+                .collect::<Map<_, _>>(),
+        ),
+        _ => template.clone(),
+    }
+}